@@ -1414,6 +1414,12 @@ rec {
             name = "thiserror";
             packageId = "thiserror";
           }
+          {
+            name = "time";
+            packageId = "time";
+            usesDefaultFeatures = false;
+            features = [ "formatting" ];
+          }
           {
             name = "vec1";
             packageId = "vec1";
@@ -3,16 +3,21 @@
 
 use crate::builder::{self, BuildError};
 use crate::daemon::LoopHandlerEvent;
+use crate::nix::env::ClientEnv;
 use crate::nix::options::NixOptions;
-use crate::pathreduction::reduce_paths;
+use crate::pathreduction::PathReducers;
+use crate::project::config::ProjectConfig;
+use crate::project::pins::{self, ChangedPin, Pins};
 use crate::project::{self, Project};
 use crate::run_async::Async;
-use crate::watch::{Watch, WatchPathBuf};
+use crate::watch::{Watch, WatchConfig, WatchPathBuf};
 use crate::NixFile;
 use anyhow::{anyhow, Context};
 use crossbeam_channel as chan;
-use slog::debug;
+use slog::{debug, error, warn};
+use std::collections::HashSet;
 use std::path::PathBuf;
+use std::time::Duration;
 
 /// Build events that can happen.
 /// Abstracting over its internal to make different serialize instances possible.
@@ -41,12 +46,43 @@ pub enum EventI<NixFile, Reason, OutputPath, BuildError> {
         /// The error that exited the build
         failure: BuildError,
     },
+    /// A build failed with a transient error and will be retried after a delay
+    Retrying {
+        /// The shell.nix file for the building project
+        nix_file: NixFile,
+        /// Number of the retry, starting at 1
+        attempt: u32,
+        /// The error that exited the failed attempt
+        failure: BuildError,
+    },
+    /// The build loop of the project stopped unexpectedly and will be restarted after a delay
+    Crashed {
+        /// The shell.nix file for the building project
+        nix_file: NixFile,
+        /// Why the build loop stopped
+        reason: String,
+        /// Seconds until the build loop is restarted
+        restart_in_secs: u64,
+    },
 }
 
 /// Builder events sent back over `BuildLoop.tx`.
 pub type Event = EventI<NixFile, Reason, builder::OutputPath<project::RootPath>, BuildError>;
 
 impl<NixFile, Reason, OutputPath, BuildError> EventI<NixFile, Reason, OutputPath, BuildError> {
+    /// The project the event is about, `None` for `SectionEnd`.
+    pub fn nix_file(&self) -> Option<&NixFile> {
+        use EventI::*;
+        match self {
+            SectionEnd => None,
+            Started { nix_file, .. }
+            | Completed { nix_file, .. }
+            | Failure { nix_file, .. }
+            | Retrying { nix_file, .. }
+            | Crashed { nix_file, .. } => Some(nix_file),
+        }
+    }
+
     /// Map over the inner types.
     pub fn map<F, G, H, I, NixFile2, Reason2, OutputPaths2, BuildError2>(
         self,
@@ -79,6 +115,24 @@ impl<NixFile, Reason, OutputPath, BuildError> EventI<NixFile, Reason, OutputPath
                 nix_file: nix_file_f(nix_file),
                 failure: build_error_f(failure),
             },
+            Retrying {
+                nix_file,
+                attempt,
+                failure,
+            } => Retrying {
+                nix_file: nix_file_f(nix_file),
+                attempt,
+                failure: build_error_f(failure),
+            },
+            Crashed {
+                nix_file,
+                reason,
+                restart_in_secs,
+            } => Crashed {
+                nix_file: nix_file_f(nix_file),
+                reason,
+                restart_in_secs,
+            },
         }
     }
 }
@@ -93,6 +147,14 @@ pub enum ReasonI<NixFile> {
     /// When there is a filesystem change, the first changed file is recorded,
     /// along with a count of other filesystem events.
     FilesChanged(Vec<PathBuf>),
+    /// When a niv or npins pin file changed, the changed files are
+    /// recorded along with the pins whose revision changed.
+    PinsChanged {
+        /// All changed files, including the pin files.
+        files: Vec<PathBuf>,
+        /// The pins that changed.
+        pins: Vec<ChangedPin>,
+    },
 }
 
 impl<NixFile> ReasonI<NixFile> {
@@ -106,12 +168,56 @@ impl<NixFile> ReasonI<NixFile> {
             ProjectAdded(nix_file) => ProjectAdded(nix_file_f(nix_file)),
             PingReceived => PingReceived,
             FilesChanged(vec) => FilesChanged(vec),
+            PinsChanged { files, pins } => PinsChanged { files, pins },
         }
     }
 }
 
 type Reason = ReasonI<NixFile>;
 
+/// How often and how fast to retry builds that failed with a transient error
+/// (see `BuildError::is_transient`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Give up after this many retries; 0 disables retrying.
+    pub max_retries: u32,
+    /// Delay before the first retry; it doubles with every further retry.
+    pub initial_backoff: Duration,
+    /// Upper bound for the delay.
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            initial_backoff: Duration::from_secs(2),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before retry number `attempt` (starting at 1).
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .checked_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff))
+    }
+}
+
+/// A request to (re)build the project, e.g. because a client pinged the daemon.
+#[derive(Clone, Debug, Default)]
+pub struct BuildRequest {
+    /// The environment of the client; subsequent builds run with it.
+    /// If `None`, the previous one is kept.
+    pub env: Option<ClientEnv>,
+    /// Where to send the result (`Completed` or `Failure`) of the first build that
+    /// starts after the request, if somebody waits for it.
+    /// A build that was already running when the request arrived doesn’t count.
+    pub done: Option<chan::Sender<Event>>,
+}
+
 /// The BuildLoop repeatedly builds the Nix expression in
 /// `project` each time a source file influencing
 /// a previous build changes.
@@ -123,9 +229,25 @@ pub struct BuildLoop<'a> {
     project: &'a Project,
     /// Extra options to pass to each nix invocation
     extra_nix_options: NixOptions,
+    /// Limits for the evaluation and build of each run
+    timeouts: builder::Timeouts,
+    /// When to retry failed builds
+    retry: RetryPolicy,
     /// Watches all input files for changes.
     /// As new input files are discovered, they are added to the watchlist.
     watch: Watch,
+    /// Environment to run nix with, as last sent by a client.
+    /// `None` means the environment of the daemon.
+    env: Option<ClientEnv>,
+    /// Settings from the project’s config file.
+    project_config: ProjectConfig,
+    /// Rules for reducing the input files of a build to the paths we watch.
+    /// They depend on the `NIX_PATH` of `env`.
+    reducers: PathReducers,
+    /// The niv/npins pins of the project, as of the last change to a pin file.
+    pins: Pins,
+    /// The (reduced) input files of the last successful build.
+    last_referenced_paths: HashSet<WatchPathBuf>,
     logger: slog::Logger,
 }
 
@@ -148,6 +270,10 @@ impl BuildState {
         }
     }
 
+    fn is_running(&self) -> bool {
+        !matches!(self, Self::NotRunning)
+    }
+
     fn display_status(&self) -> &str {
         match self {
             Self::NotRunning => "not running",
@@ -157,23 +283,100 @@ impl BuildState {
     }
 }
 
+/// The clients waiting for build results (see `BuildRequest::done`).
+#[derive(Default)]
+struct Waiting {
+    /// Wait for the running build, or the next one if none is running.
+    running: Vec<chan::Sender<Event>>,
+    /// Wait for the build scheduled after the running one.
+    scheduled: Vec<chan::Sender<Event>>,
+}
+
+impl Waiting {
+    /// A request arrived while a build was `running` (or not).
+    fn add(&mut self, done: chan::Sender<Event>, running: bool) {
+        if running {
+            // the running build started before the request, it might miss its changes
+            self.scheduled.push(done)
+        } else {
+            self.running.push(done)
+        }
+    }
+
+    /// The running build finished, and the scheduled one `started` (or there was none).
+    /// Returns who waited for the finished build.
+    fn finished(&mut self, started: bool) -> Vec<chan::Sender<Event>> {
+        let finished = std::mem::take(&mut self.running);
+        if started {
+            self.running = std::mem::take(&mut self.scheduled);
+        }
+        finished
+    }
+
+    /// The `finished` build is retried; the retry is the next (or already running) build.
+    fn retry(&mut self, finished: Vec<chan::Sender<Event>>) {
+        self.running.extend(finished)
+    }
+}
+
+/// Paths outside of the project that influence every evaluation:
+/// the root channels, `~/.nix-defexpr` and the `nix.conf` files.
+/// Only the ones that exist are returned.
+fn nix_environment_paths() -> Vec<PathBuf> {
+    let mut paths = vec![
+        // contains the `channels` profile symlink, which is swapped on update
+        PathBuf::from("/nix/var/nix/profiles/per-user/root"),
+        std::env::var_os("NIX_CONF_DIR")
+            .map_or_else(|| PathBuf::from("/etc/nix"), PathBuf::from)
+            .join("nix.conf"),
+    ];
+    if let Some(dirs) = directories::BaseDirs::new() {
+        paths.push(dirs.home_dir().join(".nix-defexpr"));
+        paths.push(dirs.config_dir().join("nix").join("nix.conf"));
+    }
+    paths.retain(|path| path.exists());
+    paths
+}
+
 impl<'a> BuildLoop<'a> {
     /// Instatiate a new BuildLoop. Uses an internal filesystem
     /// watching implementation.
     ///
-    /// Will start by only watching the project’s nix file,
+    /// Will start by only watching the project’s nix file (and its niv/npins pin files),
     /// and then add new files after each nix run.
+    /// Changes to nix’s configuration and channels are always watched, see `nix_environment_paths`.
+    ///
+    /// `watch_config` can be overridden by the project’s config file (see `ProjectConfig`).
     pub fn new(
         project: &'a Project,
         extra_nix_options: NixOptions,
+        watch_config: WatchConfig,
+        timeouts: builder::Timeouts,
+        retry: RetryPolicy,
         logger: slog::Logger,
     ) -> anyhow::Result<BuildLoop<'a>> {
-        let watch = Watch::new(&logger).map_err(|err| anyhow!(err))?;
+        let project_config = ProjectConfig::load(&project.nix_file, &logger);
+        // until a client sends its environment (see `set_env`), nix runs with the daemon’s
+        let reducers = project_config
+            .path_reducers(&project.nix_file, std::env::var("NIX_PATH").ok().as_deref());
+        debug!(logger, "path reducers"; "project" => &project.nix_file, "rules" => ?reducers.names());
+        let watch_config = project_config
+            .watch_config(watch_config)
+            .resolve_backend(project.nix_file.as_absolute_path());
+        debug!(logger, "watch config"; "project" => &project.nix_file, "config" => ?watch_config);
+        let watch = Watch::new(&logger, watch_config).map_err(|err| anyhow!(err))?;
+        // the pinned sources live in the nix store, so we watch the pin files themselves
+        let pins = Pins::load(&project.nix_file, &logger);
+        let nix_environment = nix_environment_paths();
         watch
             .add_to_watch_tx
-            .send(vec![WatchPathBuf::Normal(
-                project.nix_file.as_absolute_path().to_owned(),
-            )])
+            .send(
+                std::iter::once(project.nix_file.as_absolute_path())
+                    .chain(pins.paths())
+                    .chain(nix_environment.iter().map(|path| path.as_path()))
+                    .map(|path| WatchPathBuf::Normal(path.to_owned()))
+                    .collect(),
+            )
             .with_context(|| {
                 format!(
                     "Failed to add nix path to watcher for nix file {}",
@@ -184,7 +387,14 @@ impl<'a> BuildLoop<'a> {
         Ok(BuildLoop {
             project,
             extra_nix_options,
+            timeouts,
+            retry,
             watch,
+            env: None,
+            project_config,
+            reducers,
+            pins,
+            last_referenced_paths: HashSet::new(),
             logger,
         })
     }
@@ -193,13 +403,25 @@ impl<'a> BuildLoop<'a> {
     /// Sends `Event`s over `Self.tx` once they happen.
     /// When new filesystem changes are detected while a build is
     /// still running, it is finished first before starting a new build.
+    ///
+    /// Only returns if a build failed with an error the user cannot fix
+    /// (see `BuildError::is_actionable`), after reporting it as `Event::Failure`.
+    /// The caller should start a fresh `BuildLoop` then.
+    ///
+    /// `uid` is the user the project is built for, it is passed along with the events.
     pub fn forever(
         &mut self,
+        uid: Option<u32>,
         tx_events: chan::Sender<LoopHandlerEvent>,
-        rx_ping: chan::Receiver<()>,
-    ) -> crate::Never {
+        mut rx_ping: chan::Receiver<BuildRequest>,
+    ) -> Result<crate::Never, BuildError> {
         let mut current_build = BuildState::NotRunning;
+        // retries of the last build that failed with a transient error
+        let mut retries: u32 = 0;
+        let mut rx_retry = chan::never();
+        let mut waiting = Waiting::default();
         let rx_watcher = self.watch.watch_events_rx.clone();
+        let rx_watch_status = self.watch.watch_status_rx.clone();
 
         loop {
             debug!(self.logger, "looping build_loop";
@@ -209,7 +431,7 @@ impl<'a> BuildLoop<'a> {
 
             let send_event = |msg| {
                 tx_events
-                    .send(LoopHandlerEvent::BuildEvent(msg))
+                    .send(LoopHandlerEvent::BuildEvent { uid, event: msg })
                     .expect("Failed to send an event")
             };
 
@@ -219,22 +441,49 @@ impl<'a> BuildLoop<'a> {
                 recv(rx_current_build) -> msg => match msg {
                     Ok(run_result) => {
                         self.start_another_build_or_stop(&mut current_build);
+                        let finished = waiting.finished(current_build.is_running());
+                        let report = |event: Event| {
+                            for tx in &finished {
+                                // the client may be gone
+                                let _ = tx.send(event.clone());
+                            }
+                            send_event(event)
+                        };
 
                         match self.handle_run_result(run_result) {
                             Ok(rooted_output_paths) => {
-                                send_event(Event::Completed {
+                                retries = 0;
+                                report(Event::Completed {
                                     nix_file: self.project.nix_file.clone(),
                                     rooted_output_paths,
                                 });
                             }
-                            Err(e) => {
-                                if e.is_actionable() {
-                                    send_event(Event::Failure {
-                                        nix_file: self.project.nix_file.clone(),
-                                        failure: e,
-                                    })
+                            Err(e) if e.is_transient() && retries < self.retry.max_retries => {
+                                retries += 1;
+                                if let BuildState::NotRunning = current_build {
+                                    let backoff = self.retry.backoff(retries);
+                                    debug!(self.logger, "retrying build"; "project" => &self.project.nix_file, "attempt" => retries, "backoff" => ?backoff, "error" => %e);
+                                    rx_retry = chan::after(backoff);
                                 } else {
-                                    panic!("Unrecoverable error:\n{:#?}", e);
+                                    // the scheduled build has already started, it is the retry
+                                    debug!(self.logger, "retrying build with the scheduled build"; "project" => &self.project.nix_file, "attempt" => retries, "error" => %e);
+                                }
+                                waiting.retry(finished);
+                                send_event(Event::Retrying {
+                                    nix_file: self.project.nix_file.clone(),
+                                    attempt: retries,
+                                    failure: e,
+                                })
+                            }
+                            Err(e) => {
+                                retries = 0;
+                                report(Event::Failure {
+                                    nix_file: self.project.nix_file.clone(),
+                                    failure: e.clone(),
+                                });
+                                if !e.is_actionable() {
+                                    error!(self.logger, "build loop stopped by unrecoverable error"; "project" => &self.project.nix_file, "error" => %e);
+                                    return Err(e);
                                 }
                             }
                         }
@@ -243,13 +492,22 @@ impl<'a> BuildLoop<'a> {
                         debug!(self.logger, "current build async chan was disconnected"; "project" => &self.project.nix_file)
                 },
 
+                // the backoff of a failed build is over
+                recv(rx_retry) -> _ => {
+                    rx_retry = chan::never();
+                    self.start_or_schedule_build(&mut current_build)
+                },
+
                 // watcher found file change
                 recv(rx_watcher) -> msg => match msg {
                     Ok(changed) => {
+                            retries = 0;
+                            rx_retry = chan::never();
                             // TODO: this is not a started, this is just a scheduled!
+                            let reason = self.files_changed_reason(changed);
                             send_event(Event::Started {
                                 nix_file: self.project.nix_file.clone(),
-                                reason: Reason::FilesChanged(changed)
+                                reason,
                             });
                             self.start_or_schedule_build(&mut current_build)
                     },
@@ -257,9 +515,32 @@ impl<'a> BuildLoop<'a> {
                         debug!(self.logger, "notify chan was disconnected"; "project" => &self.project.nix_file)
                 },
 
+                // the watch list changed
+                recv(rx_watch_status) -> msg => match msg {
+                    Ok(status) => {
+                        tx_events
+                            .send(LoopHandlerEvent::WatchStatus {
+                                nix_file: self.project.nix_file.clone(),
+                                uid,
+                                status,
+                            })
+                            .expect("Failed to send an event")
+                    },
+                    Err(chan::RecvError) =>
+                        debug!(self.logger, "watch status chan was disconnected"; "project" => &self.project.nix_file)
+                },
+
                 // we were pinged
                 recv(rx_ping) -> msg => match msg {
-                    Ok(()) => {
+                    Ok(BuildRequest { env, done }) => {
+                        retries = 0;
+                        rx_retry = chan::never();
+                        if let Some(env) = env {
+                            self.set_env(env);
+                        }
+                        if let Some(done) = done {
+                            waiting.add(done, current_build.is_running());
+                        }
                         // TODO: this is not a started, this is just a scheduled!
                         send_event(Event::Started{
                             nix_file: self.project.nix_file.clone(),
@@ -267,13 +548,52 @@ impl<'a> BuildLoop<'a> {
                         });
                         self.start_or_schedule_build(&mut current_build)
                     },
-                    Err(chan::RecvError) =>
-                        debug!(self.logger, "ping chan was disconnected"; "project" => &self.project.nix_file)
+                    Err(chan::RecvError) => {
+                        debug!(self.logger, "ping chan was disconnected"; "project" => &self.project.nix_file);
+                        // nobody can ping us anymore, but we keep watching
+                        rx_ping = chan::never();
+                    }
                 }
             };
         }
     }
 
+    /// Build with the environment of a client from now on.
+    ///
+    /// The path reduction rules depend on the client’s `NIX_PATH`, so they are recreated.
+    fn set_env(&mut self, env: ClientEnv) {
+        if self.env.as_ref() == Some(&env) {
+            return;
+        }
+        self.reducers = self
+            .project_config
+            .path_reducers(&self.project.nix_file, env.get("NIX_PATH"));
+        debug!(self.logger, "path reducers"; "project" => &self.project.nix_file, "rules" => ?self.reducers.names());
+        self.env = Some(env);
+    }
+
+    /// If a pin file is among the changed files, reload the pins
+    /// and report which of them changed.
+    fn files_changed_reason(&mut self, changed: Vec<PathBuf>) -> Reason {
+        if !changed
+            .iter()
+            .any(|path| pins::is_pin_file(&self.project.nix_file, path))
+        {
+            return Reason::FilesChanged(changed);
+        }
+        let pins = Pins::load(&self.project.nix_file, &self.logger);
+        let changed_pins = pins.changed_since(&self.pins);
+        self.pins = pins;
+        if changed_pins.is_empty() {
+            Reason::FilesChanged(changed)
+        } else {
+            Reason::PinsChanged {
+                files: changed,
+                pins: changed_pins,
+            }
+        }
+    }
+
     /// Schedule a build to be run as soon as possible; immediately start the build if we are `NotRunning`.
     fn start_or_schedule_build(&self, current_build: &mut BuildState) {
         *current_build = match std::mem::replace(current_build, BuildState::NotRunning) {
@@ -297,9 +617,18 @@ impl<'a> BuildLoop<'a> {
         let nix_file = self.project.nix_file.clone();
         let cas = self.project.cas.clone();
         let extra_nix_options = self.extra_nix_options.clone();
+        let env = self.env.clone();
+        let timeouts = self.timeouts;
         let logger2 = self.logger.clone();
         crate::run_async::Async::run(&self.logger, move || {
-            builder::run(&nix_file, &cas, &extra_nix_options, &logger2)
+            builder::run(
+                &nix_file,
+                &cas,
+                &extra_nix_options,
+                env.as_ref(),
+                timeouts,
+                &logger2,
+            )
         })
     }
 
@@ -311,10 +640,19 @@ impl<'a> BuildLoop<'a> {
         let nix_file = self.project.nix_file.clone();
         let cas = self.project.cas.clone();
         let extra_nix_options = self.extra_nix_options.clone();
+        let env = self.env.clone();
+        let timeouts = self.timeouts;
         let logger2 = self.logger.clone();
         self.handle_run_result(
             crate::run_async::Async::run(&self.logger, move || {
-                builder::run(&nix_file, &cas, &extra_nix_options, &logger2)
+                builder::run(
+                    &nix_file,
+                    &cas,
+                    &extra_nix_options,
+                    env.as_ref(),
+                    timeouts,
+                    &logger2,
+                )
             })
             .block(),
         )
@@ -324,18 +662,44 @@ impl<'a> BuildLoop<'a> {
         &mut self,
         run_result: Result<builder::RunResult, BuildError>,
     ) -> Result<builder::OutputPath<project::RootPath>, BuildError> {
-        let run_result = run_result?;
+        let run_result = match run_result {
+            Ok(run_result) => run_result,
+            Err(err) => {
+                // A failed evaluation only read part of its inputs, so we keep
+                // watching the inputs of the last good build in addition.
+                // Otherwise we would not notice when the error is fixed.
+                let paths = self.reducers.reduce_paths(err.referenced_paths());
+                if !paths.is_subset(&self.last_referenced_paths) {
+                    let sent = self.watch.replace_watch_tx.send(
+                        paths
+                            .union(&self.last_referenced_paths)
+                            .cloned()
+                            .collect::<Vec<_>>(),
+                    );
+                    // the evaluation error is what the user needs to see
+                    if let Err(send_err) = sent {
+                        warn!(self.logger, "could not update the watch list"; "project" => &self.project.nix_file, "error" => %send_err);
+                    }
+                }
+                return Err(err);
+            }
+        };
         let paths = run_result.referenced_paths;
 
         let original_paths_len = paths.len();
-        let paths = reduce_paths(&paths);
+        let paths = self.reducers.reduce_paths(&paths);
         debug!(self.logger, "paths reduced"; "from" => original_paths_len, "to" => paths.len());
 
-        // add all new (reduced) nix sources to the input source watchlist
+        // the (reduced) nix sources of this build replace the input source watchlist
         self.watch
-            .add_to_watch_tx
-            .send(paths.into_iter().collect::<Vec<_>>())
+            .replace_watch_tx
+            .send(paths.iter().cloned().collect::<Vec<_>>())
             .map_err(BuildError::io)?;
+        self.last_referenced_paths = paths;
+
+        self.project
+            .record_nix_path(run_result.nix_path.as_deref())
+            .map_err(BuildError::from)?;
 
         // root the result
         self.project
@@ -343,3 +707,103 @@ impl<'a> BuildLoop<'a> {
             .map_err(BuildError::io)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{BuildLoop, Event, ReasonI, RetryPolicy, Waiting};
+    use crate::nix::env::ClientEnv;
+    use crate::nix::options::NixOptions;
+    use crate::project::Project;
+    use crate::watch::{WatchConfig, WatchPathBuf};
+    use crate::{AbsPathBuf, NixFile};
+    use crossbeam_channel as chan;
+    use std::time::Duration;
+
+    #[test]
+    fn path_reducers_follow_the_client_nix_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let shell_nix = dir.path().join("shell.nix");
+        let checkout = dir.path().join("nixpkgs");
+        std::fs::write(&shell_nix, "{}").unwrap();
+        std::fs::create_dir_all(checkout.join(".git")).unwrap();
+        std::fs::write(checkout.join(".git/HEAD"), "ref: refs/heads/master\n").unwrap();
+        let project = Project::new(
+            NixFile::from(AbsPathBuf::new(shell_nix).unwrap()),
+            &AbsPathBuf::new(dir.path().join("gc_roots")).unwrap(),
+            crate::cas::ContentAddressable::new(AbsPathBuf::new(dir.path().join("cas")).unwrap())
+                .unwrap(),
+        );
+        let mut build_loop = BuildLoop::new(
+            &project,
+            NixOptions::empty(),
+            WatchConfig::default(),
+            Default::default(),
+            RetryPolicy::default(),
+            crate::logging::test_logger("path_reducers_follow_the_client_nix_path"),
+        )
+        .unwrap();
+        let input = [WatchPathBuf::Normal(checkout.join("lib/default.nix"))];
+        let git_head = WatchPathBuf::Normal(checkout.join(".git/HEAD"));
+
+        build_loop.set_env(ClientEnv::from_vars(vec![(
+            "NIX_PATH".to_string(),
+            format!("nixpkgs={}", checkout.display()),
+        )]));
+        assert!(build_loop.reducers.reduce_paths(&input).contains(&git_head));
+
+        // a client without the checkout in its NIX_PATH
+        build_loop.set_env(ClientEnv::default());
+        assert!(!build_loop.reducers.reduce_paths(&input).contains(&git_head));
+    }
+
+    #[test]
+    fn pings_during_a_build_wait_for_the_next_one() {
+        let mut waiting = Waiting::default();
+        let (tx_first, rx_first) = chan::unbounded();
+        let (tx_during, rx_during) = chan::unbounded();
+        // the events of build `n` are those of the project `/n`
+        let notify = |waiting: Vec<chan::Sender<Event>>, n: usize| {
+            for tx in waiting {
+                tx.send(Event::Started {
+                    nix_file: NixFile::from(AbsPathBuf::new(format!("/{}", n).into()).unwrap()),
+                    reason: ReasonI::PingReceived,
+                })
+                .unwrap();
+            }
+        };
+        let build = |rx: &chan::Receiver<Event>| {
+            rx.try_recv()
+                .ok()
+                .and_then(|event| event.nix_file().map(|f| f.display().to_string()))
+        };
+
+        // build 1 starts for the first ping
+        waiting.add(tx_first, false);
+        // a ping arrives while it is running and schedules build 2
+        waiting.add(tx_during, true);
+        // build 1 finishes, build 2 starts
+        notify(waiting.finished(true), 1);
+        assert_eq!(build(&rx_first), Some("/1".to_string()));
+        assert_eq!(build(&rx_during), None);
+        // build 2 fails transiently and is retried as build 3
+        let failed = waiting.finished(false);
+        waiting.retry(failed);
+        notify(waiting.finished(false), 3);
+        assert_eq!(build(&rx_during), Some("/3".to_string()));
+        assert!(waiting.finished(false).is_empty());
+    }
+
+    #[test]
+    fn retry_backoff_doubles_up_to_the_limit() {
+        let policy = RetryPolicy {
+            max_retries: 10,
+            initial_backoff: Duration::from_secs(2),
+            max_backoff: Duration::from_secs(60),
+        };
+        let backoffs = (1..=7)
+            .map(|attempt| policy.backoff(attempt).as_secs())
+            .collect::<Vec<_>>();
+        assert_eq!(backoffs, vec![2, 4, 8, 16, 32, 60, 60]);
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_secs(60));
+    }
+}
@@ -8,7 +8,7 @@
 //! `stderr`, like which source files are used by the evaluator.
 
 use crate::cas::ContentAddressable;
-use crate::nix::{options::NixOptions, StorePath};
+use crate::nix::{env::ClientEnv, options::NixOptions, StorePath};
 use crate::osstrlines;
 use crate::watch::WatchPathBuf;
 use crate::{DrvFile, NixFile};
@@ -17,8 +17,10 @@ use slog::debug;
 use std::ffi::{OsStr, OsString};
 use std::io::BufReader;
 use std::os::unix::prelude::OsStrExt;
+use std::os::unix::process::CommandExt;
 use std::path::PathBuf;
 use std::process::{Command, ExitStatus, Stdio};
+use std::time::Duration;
 use std::{fmt, thread};
 
 /// An error that can occur during a build.
@@ -29,6 +31,11 @@ pub enum BuildError {
         /// Error message of the underlying error. Stored as a string because we need `BuildError`
         /// to implement `Copy`, but `io::Error` does not implement `Copy`.
         msg: String,
+
+        /// Whether the error came from the network, so the build might succeed
+        /// if it is just tried again. Only used inside the daemon, so it is not serialized.
+        #[serde(skip)]
+        transient: bool,
     },
 
     /// An error occurred while spawning a Nix process.
@@ -56,6 +63,29 @@ pub enum BuildError {
 
         /// Error logs of the failed process.
         logs: Vec<LogLine>,
+
+        /// Paths the evaluation read before it failed.
+        /// They have to be watched, so that we notice when the error is fixed.
+        /// Only used inside the daemon, so they are not serialized.
+        #[serde(skip)]
+        referenced_paths: Vec<WatchPathBuf>,
+    },
+
+    /// The Nix process ran longer than its timeout and was killed.
+    Timeout {
+        /// The command that timed out.
+        cmd: String,
+
+        /// The timeout that was exceeded.
+        timeout: Duration,
+
+        /// Error logs the process wrote before it was killed.
+        logs: Vec<LogLine>,
+
+        /// Paths the evaluation read before it was killed.
+        /// Only used inside the daemon, so they are not serialized.
+        #[serde(skip)]
+        referenced_paths: Vec<WatchPathBuf>,
     },
 
     /// There was something wrong with the output of the Nix command.
@@ -69,7 +99,15 @@ pub enum BuildError {
 
 impl From<std::io::Error> for BuildError {
     fn from(e: std::io::Error) -> BuildError {
-        BuildError::io(e)
+        use std::io::ErrorKind::*;
+        let transient = matches!(
+            e.kind(),
+            ConnectionRefused | ConnectionReset | ConnectionAborted | NotConnected | TimedOut
+        );
+        BuildError::Io {
+            msg: format!("{:?}", e),
+            transient,
+        }
     }
 }
 
@@ -88,7 +126,7 @@ impl From<serde_json::Error> for BuildError {
 impl fmt::Display for BuildError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            BuildError::Io { msg } => write!(f, "I/O error: {}", msg),
+            BuildError::Io { msg, .. } => write!(f, "I/O error: {}", msg),
             BuildError::Spawn { cmd, msg } => write!(
                 f,
                 "failed to spawn Nix process. Is Nix installed and on the $PATH?\n\
@@ -96,7 +134,9 @@ impl fmt::Display for BuildError {
                  {}",
                 cmd, msg,
             ),
-            BuildError::Exit { cmd, status, logs } => write!(
+            BuildError::Exit {
+                cmd, status, logs, ..
+            } => write!(
                 f,
                 "Nix process returned exit code {}.\n\
                  $ {}\n\
@@ -105,6 +145,17 @@ impl fmt::Display for BuildError {
                 cmd,
                 LogLinesDisplay(logs)
             ),
+            BuildError::Timeout {
+                cmd, timeout, logs, ..
+            } => write!(
+                f,
+                "Nix process did not finish within {}s and was killed.\n\
+                 $ {}\n\
+                 {}",
+                timeout.as_secs(),
+                cmd,
+                LogLinesDisplay(logs)
+            ),
             BuildError::Output { msg } => write!(f, "{}", msg),
         }
     }
@@ -112,13 +163,15 @@ impl fmt::Display for BuildError {
 
 // TODO: rethink these constructors
 impl BuildError {
-    /// Smart constructor for `BuildError::Io`
+    /// Smart constructor for `BuildError::Io`, for errors that are not transient.
+    /// I/O errors from the network are converted with `BuildError::from`.
     pub fn io<D>(e: D) -> BuildError
     where
         D: fmt::Debug,
     {
         BuildError::Io {
             msg: format!("{:?}", e),
+            transient: false,
         }
     }
 
@@ -143,6 +196,54 @@ impl BuildError {
             cmd: format!("{:?}", cmd),
             status: status.code(),
             logs: logs.iter().map(|l| LogLine::from(l.clone())).collect(),
+            referenced_paths: vec![],
+        }
+    }
+
+    /// Smart constructor for `BuildError::Timeout`
+    pub fn timeout(cmd: &Command, timeout: Duration, logs: Vec<OsString>) -> BuildError {
+        BuildError::Timeout {
+            cmd: format!("{:?}", cmd),
+            timeout,
+            logs: logs.into_iter().map(LogLine::from).collect(),
+            referenced_paths: vec![],
+        }
+    }
+
+    /// Attach the paths a failed evaluation read to a `BuildError::Exit`
+    /// or `BuildError::Timeout`. Other errors are returned unchanged.
+    pub fn with_referenced_paths(self, paths: Vec<WatchPathBuf>) -> BuildError {
+        match self {
+            BuildError::Exit {
+                cmd, status, logs, ..
+            } => BuildError::Exit {
+                cmd,
+                status,
+                logs,
+                referenced_paths: paths,
+            },
+            BuildError::Timeout {
+                cmd, timeout, logs, ..
+            } => BuildError::Timeout {
+                cmd,
+                timeout,
+                logs,
+                referenced_paths: paths,
+            },
+            other => other,
+        }
+    }
+
+    /// The paths that were read before the error happened, if any.
+    pub fn referenced_paths(&self) -> &[WatchPathBuf] {
+        match self {
+            BuildError::Exit {
+                referenced_paths, ..
+            }
+            | BuildError::Timeout {
+                referenced_paths, ..
+            } => referenced_paths,
+            _ => &[],
         }
     }
 
@@ -157,9 +258,44 @@ impl BuildError {
             BuildError::Io { .. } => false,
             BuildError::Spawn { .. } => true, // install Nix or fix $PATH
             BuildError::Exit { .. } => true,  // fix Nix expression
+            BuildError::Timeout { .. } => true, // fix Nix expression or raise the timeout
             BuildError::Output { .. } => true, // fix Nix expression
         }
     }
+
+    /// Might the same build succeed if we just try again?
+    ///
+    /// True for network I/O errors and for nix failures whose log shows a network
+    /// problem with a substituter, a remote builder or the nix daemon.
+    ///
+    /// Only nix’s own `error:` and `warning:` lines are considered. The log also
+    /// contains the output of builders, and e.g. a test suite that fails with
+    /// “Connection refused” fails the same way on every try.
+    pub fn is_transient(&self) -> bool {
+        lazy_static::lazy_static! {
+            static ref TRANSIENT: Regex = Regex::new(concat!(
+                // nix colors the prefix if stderr is a terminal
+                r"^(?:\x1b\[[0-9;]*m)*(?:error|warning):(?:\x1b\[[0-9;]*m)* (?:",
+                "unable to download '|",
+                "cannot connect to '|",
+                "cannot connect to daemon at '|",
+                "failed to start SSH connection to '|",
+                ".* while connecting to the Nix daemon|",
+                "unexpected end-of-file$",
+                ")"
+            ))
+            .expect("invalid regex!");
+        }
+        match self {
+            BuildError::Io { transient, .. } => *transient,
+            BuildError::Exit { logs, .. } => logs
+                .iter()
+                .any(|LogLine(line)| TRANSIENT.is_match(&line.to_string_lossy())),
+            BuildError::Spawn { .. } | BuildError::Timeout { .. } | BuildError::Output { .. } => {
+                false
+            }
+        }
+    }
 }
 
 /// A line from stderr log output.
@@ -218,6 +354,8 @@ fn instrumented_instantiation(
     nix_file: &NixFile,
     cas: &ContentAddressable,
     extra_nix_options: &NixOptions,
+    env: Option<&ClientEnv>,
+    timeout: Option<Duration>,
     logger: &slog::Logger,
 ) -> Result<InstantiateOutput, BuildError> {
     // We're looking for log lines matching:
@@ -263,12 +401,17 @@ fn instrumented_instantiation(
     .stdin(Stdio::null())
     .stdout(Stdio::piped())
     .stderr(Stdio::piped());
+    if let Some(env) = env {
+        env.apply(&mut cmd);
+    }
+    // so that `wait_timeout` can kill everything nix starts
+    cmd.process_group(0);
 
     debug!(logger, "nix-instantiate"; "command" => ?cmd);
 
     let mut child = cmd.spawn().map_err(|e| match e.kind() {
         std::io::ErrorKind::NotFound => BuildError::spawn(&cmd, e),
-        _ => BuildError::io(e),
+        _ => BuildError::from(e),
     })?;
 
     let stdout = child
@@ -293,7 +436,7 @@ fn instrumented_instantiation(
     });
 
     let (exec_result, mut build_products, results) = (
-        child.wait()?,
+        crate::nix::wait_timeout(&mut child, timeout)?,
         build_products
             .join()
             .expect("Failed to join stdout processing thread")?,
@@ -335,8 +478,19 @@ fn instrumented_instantiation(
         };
     }
 
-    if !exec_result.success() {
-        return Err(BuildError::exit(&cmd, exec_result, log_lines));
+    match (exec_result, timeout) {
+        (Some(status), _) if status.success() => {}
+        (Some(status), _) => {
+            return Err(BuildError::exit(&cmd, status, log_lines).with_referenced_paths(paths))
+        }
+        (None, timeout) => {
+            return Err(BuildError::timeout(
+                &cmd,
+                timeout.expect("only a timeout kills the process"),
+                log_lines,
+            )
+            .with_referenced_paths(paths))
+        }
     }
 
     let shell_gc_root = match build_products.len() {
@@ -364,8 +518,20 @@ struct BuildOutput {
 /// Builds the Nix expression in `root_nix_file`.
 ///
 /// Instruments the nix file to gain extra information, which is valuable even if the build fails.
-fn build(drv_path: DrvFile, logger: &slog::Logger) -> Result<BuildOutput, BuildError> {
-    let (path, gc_handle) = crate::nix::CallOpts::file(drv_path.as_path()).path(logger)?;
+fn build(
+    drv_path: DrvFile,
+    env: Option<&ClientEnv>,
+    timeout: Option<Duration>,
+    logger: &slog::Logger,
+) -> Result<BuildOutput, BuildError> {
+    let mut opts = crate::nix::CallOpts::file(drv_path.as_path());
+    if let Some(env) = env {
+        opts.env(env.clone());
+    }
+    if let Some(timeout) = timeout {
+        opts.timeout(timeout);
+    }
+    let (path, gc_handle) = opts.path(logger)?;
     Ok(BuildOutput {
         output: RootedPath { gc_handle, path },
     })
@@ -385,23 +551,51 @@ pub struct RunResult {
     pub referenced_paths: Vec<WatchPathBuf>,
     /// The status of the build attempt
     pub result: RootedPath,
+    /// The `NIX_PATH` the evaluation ran with, if it was set
+    pub nix_path: Option<String>,
+}
+
+/// How long the phases of a build may take before nix is killed.
+/// `None` means no limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Limit for the evaluation (`nix-instantiate`), including import-from-derivation.
+    pub instantiate: Option<Duration>,
+    /// Limit for realising the evaluated derivation.
+    pub build: Option<Duration>,
 }
 
 /// Builds the Nix expression in `root_nix_file`.
 ///
 /// Instruments the nix file to gain extra information,
 /// which is valuable even if the build fails.
+///
+/// Nix runs with the allowlisted variables of `env` if given,
+/// and otherwise inherits the environment of this process.
 pub fn run(
     root_nix_file: &NixFile,
     cas: &ContentAddressable,
     extra_nix_options: &NixOptions,
+    env: Option<&ClientEnv>,
+    timeouts: Timeouts,
     logger: &slog::Logger,
 ) -> Result<RunResult, BuildError> {
-    let inst_info = instrumented_instantiation(root_nix_file, cas, extra_nix_options, logger)?;
-    let buildoutput = build(inst_info.output.path, logger)?;
+    let inst_info = instrumented_instantiation(
+        root_nix_file,
+        cas,
+        extra_nix_options,
+        env,
+        timeouts.instantiate,
+        logger,
+    )?;
+    let buildoutput = build(inst_info.output.path, env, timeouts.build, logger)?;
     Ok(RunResult {
         referenced_paths: inst_info.referenced_paths,
         result: buildoutput.output,
+        nix_path: match env {
+            Some(env) => env.get("NIX_PATH").map(String::from),
+            None => std::env::var("NIX_PATH").ok(),
+        },
     })
 }
 
@@ -534,6 +728,57 @@ mod tests {
         );
     }
 
+    #[test]
+    fn transient_errors() {
+        let exit = |log: &str| BuildError::Exit {
+            cmd: "nix-build".to_string(),
+            status: Some(1),
+            logs: vec![LogLine::from(log.to_string())],
+            referenced_paths: vec![],
+        };
+        let io = |kind| BuildError::from(std::io::Error::from(kind));
+        assert!(io(std::io::ErrorKind::ConnectionReset).is_transient());
+        assert!(!io(std::io::ErrorKind::PermissionDenied).is_transient());
+        assert!(!BuildError::io("disk on fire").is_transient());
+        assert!(exit("error: unable to download 'https://cache.nixos.org/abc.narinfo': Couldn't resolve host name (6)").is_transient());
+        assert!(exit("warning: unable to download 'https://cache.nixos.org/abc.narinfo': HTTP error 503; retrying in 262 ms").is_transient());
+        assert!(exit("error: cannot connect to 'builder.example.com'").is_transient());
+        assert!(
+            exit("error: failed to start SSH connection to 'builder.example.com'").is_transient()
+        );
+        assert!(exit("error: cannot connect to daemon at '/nix/var/nix/daemon-socket/socket': Connection refused").is_transient());
+        assert!(exit("error: unexpected end-of-file").is_transient());
+        assert!(exit(
+            "\x1b[31;1merror:\x1b[0m unable to download 'https://cache.nixos.org/abc.narinfo'"
+        )
+        .is_transient());
+        assert!(!exit("error: undefined variable 'pkgs'").is_transient());
+        assert!(!BuildError::output("no output".to_string()).is_transient());
+    }
+
+    #[test]
+    fn builder_output_is_not_transient() {
+        // a test suite that needs the network, in a sandboxed build
+        let failed_tests = BuildError::Exit {
+            cmd: "nix-build".to_string(),
+            status: Some(1),
+            logs: [
+                "running tests",
+                "test_fetch ... curl: (7) Failed to connect to localhost port 80: Connection refused",
+                "test_download ... Could not resolve host: example.com",
+                "test_pipe ... Broken pipe",
+                "test_parse ... unexpected end-of-file",
+                "error: unable to download, says the test",
+                "error: builder for '/nix/store/abc-foo.drv' failed with exit code 1",
+            ]
+            .iter()
+            .map(|line| LogLine::from(line.to_string()))
+            .collect(),
+            referenced_paths: vec![],
+        };
+        assert!(!failed_tests.is_transient());
+    }
+
     /// Create a locally built base derivation expression.
     /// `args` is just interpolated into the derivation fields.
     fn drv(name: &str, args: &str) -> String {
@@ -589,6 +834,8 @@ in {}
             &crate::NixFile::from(cas.file_from_string(&nix_drv)?),
             &cas,
             &NixOptions::empty(),
+            None,
+            Timeouts::default(),
             &crate::logging::test_logger("non_utf8_nix_output"),
         )
         .expect("should not crash!");
@@ -610,6 +857,8 @@ in {}
             &d,
             &cas,
             &NixOptions::empty(),
+            None,
+            Timeouts::default(),
             &crate::logging::test_logger("gracefully_handle_failing_build"),
         ) {
         } else {
@@ -621,6 +870,75 @@ in {}
         Ok(())
     }
 
+    /// A build that hangs is killed once it exceeds its timeout.
+    #[test]
+    fn build_timeout_kills_nix() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let cas = ContentAddressable::new(crate::AbsPathBuf::new(tmp.path().to_owned()).unwrap())?;
+
+        let d = crate::NixFile::from(cas.file_from_string(&drv(
+            "shell",
+            &format!("dep = {};", drv("dep", r#"args = [ "-c" "sleep 600" ];"#)),
+        ))?);
+
+        match run(
+            &d,
+            &cas,
+            &NixOptions::empty(),
+            None,
+            Timeouts {
+                instantiate: None,
+                build: Some(std::time::Duration::from_secs(2)),
+            },
+            &crate::logging::test_logger("build_timeout_kills_nix"),
+        ) {
+            Err(BuildError::Timeout { timeout, .. }) => {
+                assert_eq!(timeout, std::time::Duration::from_secs(2))
+            }
+            other => panic!(
+                "builder::run should have failed with BuildError::Timeout, but got {:#?}",
+                other
+            ),
+        }
+        Ok(())
+    }
+
+    /// If the evaluation fails, we still need to know which files it read,
+    /// so we notice once the user fixes the error.
+    #[test]
+    fn failed_evaluation_returns_referenced_paths() -> std::io::Result<()> {
+        let root_tmp = tempfile::tempdir()?;
+        let cas_tmp = tempfile::tempdir()?;
+        let root = root_tmp.path();
+        let shell = root.join("shell.nix");
+        std::fs::write(&shell, drv("shell", "broken = import ./broken.nix;"))?;
+        let broken = root.join("broken.nix");
+        std::fs::write(&broken, "{ this is not valid nix")?;
+
+        let cas = ContentAddressable::new(AbsPathBuf::new(cas_tmp.path().to_owned()).unwrap())?;
+        match run(
+            &crate::NixFile::from(AbsPathBuf::new(shell).unwrap()),
+            &cas,
+            &NixOptions::empty(),
+            None,
+            Timeouts::default(),
+            &crate::logging::test_logger("failed_evaluation_returns_referenced_paths"),
+        ) {
+            Err(err @ BuildError::Exit { .. }) => assert!(
+                err.referenced_paths()
+                    .contains(&WatchPathBuf::Normal(broken.clone())),
+                "{} should be referenced, but got {:#?}",
+                broken.display(),
+                err.referenced_paths()
+            ),
+            other => panic!(
+                "builder::run should have failed with BuildError::Exit, but got {:#?}",
+                other
+            ),
+        }
+        Ok(())
+    }
+
     // TODO: builtins.fetchTarball and the like? What happens with those?
     // Are they directories and if yes, should we watch them?
     /// The paths that are returned by the nix-instantiate call
@@ -673,6 +991,8 @@ dir-as-source = ./dir;
             &NixFile::from(AbsPathBuf::new(shell).unwrap()),
             &cas,
             &NixOptions::empty(),
+            None,
+            None,
             &crate::logging::test_logger("no_unnecessary_files_or_directories_watched"),
         )
         .unwrap();
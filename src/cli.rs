@@ -20,6 +20,15 @@ pub struct Arguments {
     #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
     pub verbosity: u8,
 
+    /// Path of the daemon socket, for the daemon and its clients.
+    /// Clients also accept the TCP port of a daemon started with `--listen-tcp`,
+    /// like `tcp://127.0.0.1:4242`; they authenticate with the token in
+    /// `$XDG_RUNTIME_DIR/lorri/daemon.token` (or LORRI_DAEMON_TOKEN_FILE).
+    /// Can also be set with the LORRI_DAEMON_SOCKET environment variable.
+    /// Defaults to `$XDG_RUNTIME_DIR/lorri/daemon.socket`.
+    #[structopt(long = "daemon-socket", parse(from_os_str))]
+    pub daemon_socket: Option<PathBuf>,
+
     /// Sub-command to execute
     #[structopt(subcommand)]
     pub command: Command,
@@ -58,6 +67,20 @@ pub enum Command {
     #[structopt(name = "watch")]
     Watch(WatchOptions),
 
+    /// Show what the daemon is building.
+    ///
+    /// Prints the last event of every project, then every new event as it happens.
+    #[structopt(name = "events")]
+    Events(EventsOptions),
+
+    /// Wait until the daemon built the project.
+    ///
+    /// Pings the daemon if it does not watch the project yet.
+    /// Exits successfully once the project is built, or right away if it is up to date;
+    /// exits with the error if the build fails.
+    #[structopt(name = "wait")]
+    Wait(WaitOptions),
+
     /// Start the multi-project daemon. Replaces `lorri watch`
     #[structopt(name = "daemon")]
     Daemon(DaemonOptions),
@@ -85,6 +108,12 @@ pub struct DirenvOptions {
     /// The .nix file in the current directory to use
     #[structopt(long = "shell-file", parse(from_os_str), default_value = "shell.nix")]
     pub nix_file: PathBuf,
+
+    /// If the project was never built, wait up to this long for its first build, e.g. 10s.
+    ///
+    /// Without this option, direnv loads the environment once the daemon built it.
+    #[structopt(long = "wait", parse(try_from_str = "human_friendly_timeout"))]
+    pub wait: Option<Duration>,
 }
 
 /// Options for the `info` subcommand.
@@ -97,32 +126,89 @@ pub struct InfoOptions {
     pub nix_file: Option<PathBuf>,
 }
 
-/// Parses a duration from a timestamp like 30d, 2m.
-fn human_friendly_duration(s: &str) -> Result<Duration, String> {
-    let multiplier = if s.ends_with('d') {
-        24 * 60 * 60
-    } else if s.ends_with('m') {
-        30 * 24 * 60 * 60
-    } else if s.ends_with('y') {
-        365 * 24 * 60 * 60
-    } else {
-        return Err(format!(
-            "Invalid duration: «{}» should end with d, m or y.",
-            s
-        ));
-    };
-    let integer_part = match s.get(0..(s.len() - 1)) {
-        Some(x) => x,
-        None => return Err(format!("Invalid duration: «{}» has no integer part.", s)),
-    };
-    let n: Result<u64, std::num::ParseIntError> = integer_part.parse();
-    match n {
-        Ok(n) => Ok(Duration::from_secs(n * multiplier)),
-        Err(e) => Err(format!(
+/// Options for the `wait` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct WaitOptions {
+    /// The .nix file in the current directory to use
+    #[structopt(long = "shell-file", parse(from_os_str), default_value = "shell.nix")]
+    pub nix_file: PathBuf,
+
+    /// Give up after this long, e.g. 90s, 5min or 1h.
+    #[structopt(
+        long = "timeout",
+        default_value = "60s",
+        parse(try_from_str = "human_friendly_timeout")
+    )]
+    pub timeout: Duration,
+}
+
+/// Parses a (short) duration like 500ms, 60s, 5min, 1h.
+/// A number without unit are seconds.
+fn human_friendly_timeout(s: &str) -> Result<Duration, String> {
+    duration_with_units(
+        s,
+        &[
+            ("ms", 1),
+            ("", 1000),
+            ("s", 1000),
+            ("min", 60 * 1000),
+            ("h", 60 * 60 * 1000),
+        ],
+        "ms, s, min or h",
+    )
+}
+
+/// Parses an integer followed by one of `units`, given as (suffix, milliseconds).
+/// `expected` lists the suffixes for the error message.
+fn duration_with_units(s: &str, units: &[(&str, u64)], expected: &str) -> Result<Duration, String> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (integer_part, unit) = s.split_at(split);
+    let n: u64 = integer_part.parse().map_err(|e| {
+        format!(
             "Invalid duration: «{}» is not an integer: {}",
             integer_part, e
-        )),
-    }
+        )
+    })?;
+    let millis = match units.iter().find(|(suffix, _)| *suffix == unit) {
+        Some((_, millis)) => *millis,
+        None => {
+            return Err(format!(
+                "Invalid duration: «{}» should end with {}.",
+                s, expected
+            ))
+        }
+    };
+    n.checked_mul(millis)
+        .map(Duration::from_millis)
+        .ok_or_else(|| format!("Invalid duration: «{}» is too long.", s))
+}
+
+#[test]
+fn test_human_friendly_timeout() {
+    assert_eq!(
+        human_friendly_timeout("500ms"),
+        Ok(Duration::from_millis(500))
+    );
+    assert_eq!(human_friendly_timeout("60s"), Ok(Duration::from_secs(60)));
+    assert_eq!(human_friendly_timeout("60"), Ok(Duration::from_secs(60)));
+    assert_eq!(human_friendly_timeout("5min"), Ok(Duration::from_secs(300)));
+    assert_eq!(human_friendly_timeout("1h"), Ok(Duration::from_secs(3600)));
+    assert!(human_friendly_timeout("1d").is_err());
+    assert!(human_friendly_timeout("s").is_err());
+    assert!(human_friendly_timeout("-1s").is_err());
+    assert!(human_friendly_timeout("18446744073709551615ms").is_ok());
+    assert!(human_friendly_timeout("18446744073709551615s").is_err());
+    assert!(human_friendly_timeout("18446744073709551616ms").is_err());
+}
+
+/// Parses a duration from a timestamp like 30d, 2m.
+fn human_friendly_duration(s: &str) -> Result<Duration, String> {
+    const DAY: u64 = 24 * 60 * 60 * 1000;
+    duration_with_units(
+        s,
+        &[("d", DAY), ("m", 30 * DAY), ("y", 365 * DAY)],
+        "d, m or y",
+    )
 }
 
 #[test]
@@ -149,6 +235,7 @@ fn test_human_friendly_duration() {
     assert!(human_friendly_duration("d").is_err());
     assert!(human_friendly_duration("1j").is_err());
     assert!(human_friendly_duration("é").is_err());
+    assert!(human_friendly_duration("18446744073709551615y").is_err());
 }
 
 /// Options for the `gc` subcommand.
@@ -231,6 +318,53 @@ pub struct DaemonOptions {
     ///   "substituters": <optional list of string>
     /// }
     pub extra_nix_options: Option<NixOptions>,
+
+    /// Debounce window of the file watcher, in milliseconds (default: 200).
+    /// Events for the same file within this window are merged.
+    /// Can be overridden per project in `.lorri.json` (`debounce_ms`).
+    #[structopt(long = "debounce-ms")]
+    pub debounce_ms: Option<u64>,
+
+    /// Only start a build after no file changed for this many milliseconds.
+    /// All files changed in the meantime trigger a single build.
+    /// Useful to not rebuild repeatedly during a large `git rebase`.
+    /// Can be overridden per project in `.lorri.json` (`quiet_period_ms`).
+    #[structopt(long = "quiet-period-ms")]
+    pub quiet_period_ms: Option<u64>,
+
+    /// Kill the evaluation of a project (including import-from-derivation)
+    /// if it takes longer than this many seconds (default: no limit).
+    #[structopt(long = "instantiate-timeout-secs")]
+    pub instantiate_timeout_secs: Option<u64>,
+
+    /// Kill the build of a project’s environment
+    /// if it takes longer than this many seconds (default: no limit).
+    #[structopt(long = "build-timeout-secs")]
+    pub build_timeout_secs: Option<u64>,
+
+    /// How often to retry a build that failed with a transient error,
+    /// like a network problem with a substituter or remote builder (default: 3).
+    /// Retries back off exponentially.
+    #[structopt(long = "max-retries")]
+    pub max_retries: Option<u32>,
+
+    /// Also accept clients on this loopback TCP port (or address, like `[::1]:4242`),
+    /// e.g. for clients in containers that can’t reach the daemon socket.
+    /// Every user of this machine can connect to the port; without the token the daemon
+    /// writes to `$XDG_RUNTIME_DIR/lorri/daemon.token`, a client could make the daemon
+    /// evaluate nix code as you and read your build logs, so keep that file secret.
+    #[structopt(
+        long = "listen-tcp",
+        parse(try_from_str = "crate::daemon::tcp::parse_loopback_address")
+    )]
+    pub listen_tcp: Option<std::net::SocketAddr>,
+
+    /// Serve all users of this machine (Linux only), see contrib/daemon.md.
+    /// Every user can connect to the socket, but the daemon only builds nix files
+    /// the user can read, and keeps each user’s GC roots in a directory of their own.
+    /// Run it as a dedicated user, never as root: it evaluates nix code for all users.
+    #[structopt(long = "multi-user")]
+    pub multi_user: bool,
 }
 
 /// The nix options we can parse as json string
@@ -255,12 +389,12 @@ pub enum Internal_ {
     #[structopt(name = "ping")]
     Ping_(Ping_),
 
-    /// (experimental) Ask the lorri daemon to report build events as they occur.
+    /// (plumbing) Ask the lorri daemon to report build events as they occur.
     ///
-    /// This is intended for scripts. However, we don’t guarantee any stability for now,
-    /// so if you want to use it in your scripts make sure you follow our changes.
-    /// Once it stabilizes a bit more we will start mentioning changes in the changelog,
-    /// and eventually ensure backwards compat.
+    /// Like `lorri events --json`, but kept for existing scripts: it prints format version 0
+    /// unless `--format-version` says otherwise.
+    /// Every event is printed as a line of JSON; the format is versioned and documented
+    /// in contrib/stream-events.md.
     #[structopt(name = "stream-events")]
     StreamEvents_(StreamEvents_),
 }
@@ -270,19 +404,75 @@ pub enum Internal_ {
 /// Pinging with a project tells the daemon that the project was recently interacted with.
 /// If the daemon has not been pinged for a project, it begins listening. If it does not
 /// get pinged for a long time, it may stop watching the project for changes.
+///
+/// The ping carries the current `NIX_PATH` and other variables that influence nix
+/// (see `nix::env::ALLOWLIST`), which the daemon uses for subsequent builds.
+/// `lorri direnv` only sends them along; the daemon keeps building with the
+/// environment of the first client, until `lorri internal ping` sends another one.
 #[derive(StructOpt, Debug)]
 pub struct Ping_ {
     /// The .nix file to watch and build on changes.
     #[structopt(parse(from_os_str))]
     pub nix_file: PathBuf,
+
+    /// Wait for the build triggered by the ping to finish, and exit
+    /// with an error if it failed.
+    #[structopt(long = "wait")]
+    pub wait: bool,
 }
 
 /// Stream events from the daemon.
 #[derive(StructOpt, Debug)]
 pub struct StreamEvents_ {
+    // Which events to report (flattened options can’t have doc comments)
+    #[allow(missing_docs)]
+    #[structopt(flatten)]
+    pub select: EventSelection,
+
+    /// The version of the output format (see contrib/stream-events.md).
+    /// Version 0 is the unstable format of lorri 1.6 and earlier, and stays the default
+    /// here so that existing scripts keep working; new scripts should pass 1.
+    #[structopt(long = "format-version", default_value = "0")]
+    pub format_version: crate::ops::stream_events::FormatVersion,
+}
+
+/// Options for the `events` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct EventsOptions {
+    // Which events to report (flattened options can’t have doc comments)
+    #[allow(missing_docs)]
+    #[structopt(flatten)]
+    pub select: EventSelection,
+
+    /// Print the events as JSON, one object per line (see contrib/stream-events.md)
+    #[structopt(long = "json")]
+    pub json: bool,
+
+    /// The version of the JSON format, only with `--json`
+    #[structopt(long = "format-version", default_value = "1")]
+    pub format_version: crate::ops::stream_events::FormatVersion,
+}
+
+/// The events `events` and `internal stream-events` report.
+#[derive(StructOpt, Debug)]
+pub struct EventSelection {
     #[structopt(long, default_value = "all")]
-    /// The kind of events to report
+    /// The kind of events to report: the last event of every project (snapshot),
+    /// new events (live) or both (all)
     pub kind: crate::ops::EventKind,
+
+    /// Only report events of this project. Can be given multiple times.
+    #[structopt(long = "shell-file", parse(from_os_str))]
+    pub nix_files: Vec<PathBuf>,
+
+    /// Only report events of projects in this directory or below. It must exist.
+    #[structopt(long = "under", parse(from_os_str))]
+    pub under: Option<PathBuf>,
+
+    /// Only report events of this type (started, completed, failure, retrying, crashed).
+    /// Can be given multiple times.
+    #[structopt(long = "type")]
+    pub types: Vec<crate::socket::communicate::EventType>,
 }
 
 /// A stub struct to represent how what we want to upgrade to.
@@ -336,3 +526,31 @@ pub struct BranchDest {
     /// the path to git branch of the upstream repository.
     pub branch: String,
 }
+
+#[test]
+fn stream_events_keeps_the_old_format_by_default() {
+    use crate::ops::stream_events::FormatVersion;
+    use structopt::StructOpt;
+    let version = |args: &[&str]| match Arguments::from_iter_safe(args).unwrap().command {
+        Command::Events(opts) => opts.format_version,
+        Command::Internal {
+            command: Internal_::StreamEvents_(opts),
+        } => opts.format_version,
+        other => panic!("unexpected command {:?}", other),
+    };
+    assert_eq!(version(&["lorri", "events", "--json"]), FormatVersion::V1);
+    assert_eq!(
+        version(&["lorri", "internal", "stream-events"]),
+        FormatVersion::V0
+    );
+    assert_eq!(
+        version(&[
+            "lorri",
+            "internal",
+            "stream-events",
+            "--format-version",
+            "1"
+        ]),
+        FormatVersion::V1
+    );
+}
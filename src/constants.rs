@@ -1,16 +1,32 @@
 //! Global project constants.
 
 use crate::cas::ContentAddressable;
+use crate::socket::path::{DaemonAddress, SocketPath};
 use crate::AbsPathBuf;
 use directories::ProjectDirs;
+use std::path::PathBuf;
 use thiserror::Error;
 
+/// Environment variable that overrides the path of the daemon socket.
+/// Clients also accept a TCP address like `tcp://127.0.0.1:4242`.
+pub const DAEMON_SOCKET_ENV: &str = "LORRI_DAEMON_SOCKET";
+
+/// Environment variable that overrides the path of the token file of the daemon’s TCP port
+/// (`lorri daemon --listen-tcp`), e.g. for clients in a container.
+pub const DAEMON_TOKEN_FILE_ENV: &str = "LORRI_DAEMON_TOKEN_FILE";
+
+/// Environment variable that overrides the GC root directory,
+/// e.g. to use the GC roots a shared daemon keeps for the user.
+pub const GC_ROOT_DIR_ENV: &str = "LORRI_GC_ROOT_DIR";
+
 /// Path constants like the GC root directory.
 #[derive(Clone)]
 pub struct Paths {
     gc_root_dir: AbsPathBuf,
     // TODO: make SocketPath
     daemon_socket_file: AbsPathBuf,
+    daemon_address: DaemonAddress,
+    daemon_token_file: AbsPathBuf,
     cas_store: ContentAddressable,
 }
 
@@ -18,14 +34,6 @@ pub struct Paths {
 /// Mostly filesystem access problems.
 #[derive(Debug, Error)]
 pub enum PathsInitError {
-    /// The `gc_root_dir` creation failed.
-    #[error("Could not create GC roots in {gc_root_dir}")]
-    #[allow(missing_docs)]
-    GcRootsDirectoryCantBeCreated {
-        gc_root_dir: String,
-        #[source]
-        err: std::io::Error,
-    },
     /// The `socket_dir` creation failed.
     #[error("Could not create the socket directory in {socket_dir}")]
     #[allow(missing_docs)]
@@ -34,6 +42,21 @@ pub enum PathsInitError {
         #[source]
         err: std::io::Error,
     },
+    /// `LORRI_DAEMON_SOCKET` is not an absolute path.
+    #[error("{DAEMON_SOCKET_ENV} must be an absolute path, but is {0}")]
+    DaemonSocketNotAbsolute(String),
+    /// `LORRI_DAEMON_SOCKET` starts with `tcp://`, but is not a valid TCP address.
+    #[error("{DAEMON_SOCKET_ENV} is invalid: {0}")]
+    DaemonTcpAddressInvalid(String),
+    /// `LORRI_DAEMON_SOCKET` has no parent directory, e.g. it is `/`.
+    #[error("{DAEMON_SOCKET_ENV} must be the path of a file in a directory, but is {0}")]
+    DaemonSocketHasNoParent(String),
+    /// `LORRI_DAEMON_TOKEN_FILE` is not an absolute path.
+    #[error("{DAEMON_TOKEN_FILE_ENV} must be an absolute path, but is {0}")]
+    DaemonTokenFileNotAbsolute(String),
+    /// `LORRI_GC_ROOT_DIR` is not an absolute path.
+    #[error("{GC_ROOT_DIR_ENV} must be an absolute path, but is {0}")]
+    GcRootDirNotAbsolute(String),
     /// The CAS creation failed.
     #[error("Could not create the CAS directory in {cas_dir}")]
     #[allow(missing_docs)]
@@ -45,13 +68,10 @@ pub enum PathsInitError {
 }
 
 impl Paths {
-    /// Set up project paths, creating directories if necessary.
+    /// Set up project paths, creating the socket and CAS directories if necessary.
     pub fn initialize() -> Result<Paths, PathsInitError> {
         let pd = ProjectDirs::from("com.github.nix-community.lorri", "lorri", "lorri")
             .expect("Could not determine lorri project/cache directories, please set $HOME");
-        let create_dir = |dir: AbsPathBuf| -> std::io::Result<AbsPathBuf> {
-            std::fs::create_dir_all(&dir).and(Ok(dir))
-        };
 
         let cache_dir = pd.cache_dir();
         // canonicalize is used to make tests easier
@@ -66,7 +86,11 @@ impl Paths {
             )
         });
 
-        let gc_root_dir = abs_cache_dir.join("gc_roots");
+        let gc_root_dir = match std::env::var_os(GC_ROOT_DIR_ENV) {
+            Some(dir) if !dir.is_empty() => AbsPathBuf::new(PathBuf::from(dir))
+                .map_err(|d| PathsInitError::GcRootDirNotAbsolute(d.display().to_string()))?,
+            _ => abs_cache_dir.join("gc_roots"),
+        };
         let cas_dir = abs_cache_dir.join("cas");
         let runtime_dir = pd
             .runtime_dir()
@@ -80,20 +104,48 @@ impl Paths {
                 rd.display()
             )
         });
+        let daemon_token_file = match std::env::var_os(DAEMON_TOKEN_FILE_ENV) {
+            Some(path) if !path.is_empty() => AbsPathBuf::new(PathBuf::from(path))
+                .map_err(|p| PathsInitError::DaemonTokenFileNotAbsolute(p.display().to_string()))?,
+            _ => abs_runtime_dir.join("daemon.token"),
+        };
+        let daemon_socket_env = std::env::var_os(DAEMON_SOCKET_ENV).filter(|p| !p.is_empty());
+        // only clients can connect to a TCP address, the daemon keeps the default socket
+        let tcp_address = match daemon_socket_env
+            .as_ref()
+            .and_then(|p| p.to_str())
+            .and_then(|p| DaemonAddress::parse_tcp(p, &daemon_token_file))
+        {
+            Some(addr) => Some(addr.map_err(PathsInitError::DaemonTcpAddressInvalid)?),
+            None => None,
+        };
+        let daemon_socket_file = match daemon_socket_env {
+            Some(path) if tcp_address.is_none() => AbsPathBuf::new(PathBuf::from(path))
+                .map_err(|p| PathsInitError::DaemonSocketNotAbsolute(p.display().to_string()))?,
+            _ => abs_runtime_dir.join("daemon.socket"),
+        };
+        let daemon_address = tcp_address
+            .unwrap_or_else(|| DaemonAddress::Unix(SocketPath::from(daemon_socket_file.clone())));
+        let socket_dir = daemon_socket_file
+            .as_path()
+            .parent()
+            .ok_or_else(|| {
+                PathsInitError::DaemonSocketHasNoParent(daemon_socket_file.display().to_string())
+            })?
+            .to_owned();
 
         Ok(Paths {
-            gc_root_dir: create_dir(gc_root_dir.clone()).map_err(|err| {
-                PathsInitError::GcRootsDirectoryCantBeCreated {
-                    gc_root_dir: gc_root_dir.display().to_string(),
-                    err,
-                }
-            })?,
-            daemon_socket_file: create_dir(abs_runtime_dir.clone())
+            // created by whoever builds a project (see `Project::create_gc_root_dir`),
+            // clients of a multi-user daemon can’t write to it
+            gc_root_dir,
+            daemon_socket_file: std::fs::create_dir_all(&socket_dir)
+                .map(|()| daemon_socket_file)
                 .map_err(|err| PathsInitError::SocketDirCantBeCreated {
-                    socket_dir: abs_runtime_dir.display().to_string(),
+                    socket_dir: socket_dir.display().to_string(),
                     err,
-                })?
-                .join("daemon.socket"),
+                })?,
+            daemon_address,
+            daemon_token_file,
             cas_store: ContentAddressable::new(cas_dir.clone()).map_err(|err| {
                 PathsInitError::CasCantBeCreated {
                     cas_dir: cas_dir.display().to_string(),
@@ -104,7 +156,9 @@ impl Paths {
     }
 
     /// Default location in the user's XDG directories to keep
-    /// GC root pins
+    /// GC root pins.
+    /// It can be overridden with `LORRI_GC_ROOT_DIR`.
+    /// It does not exist before the first build.
     pub fn gc_root_dir(&self) -> &AbsPathBuf {
         &self.gc_root_dir
     }
@@ -112,10 +166,26 @@ impl Paths {
     /// Path to the socket file.
     ///
     /// The daemon uses this path to create its Unix socket.
+    /// It can be overridden with `LORRI_DAEMON_SOCKET`.
     pub fn daemon_socket_file(&self) -> &AbsPathBuf {
         &self.daemon_socket_file
     }
 
+    /// Where clients connect to the daemon.
+    ///
+    /// The daemon socket, unless `LORRI_DAEMON_SOCKET` is a TCP address.
+    pub fn daemon_address(&self) -> &DaemonAddress {
+        &self.daemon_address
+    }
+
+    /// Path to the token file of the daemon’s TCP port.
+    ///
+    /// `lorri daemon --listen-tcp` writes a new token to it, TCP clients read it.
+    /// It can be overridden with `LORRI_DAEMON_TOKEN_FILE`.
+    pub fn daemon_token_file(&self) -> &AbsPathBuf {
+        &self.daemon_token_file
+    }
+
     /// content-addressable store.
     ///
     /// It should be used to reify strings that are needed as files,
@@ -1,27 +1,65 @@
 //! The lorri daemon, watches multiple projects in the background.
 
+pub mod access;
 pub mod client;
 pub mod server;
+pub mod tcp;
 
-use crate::build_loop::{BuildLoop, Event};
+use crate::build_loop::{BuildLoop, BuildRequest, Event, RetryPolicy};
+use crate::builder::{BuildError, Timeouts};
+use crate::nix::env::ClientEnv;
 use crate::nix::options::NixOptions;
 use crate::ops::error::ExitError;
-use crate::socket::communicate;
+use crate::socket::communicate::{
+    self, DaemonStatus, EventFilter, PingAck, ProjectStatus, StampedEvent,
+};
 use crate::socket::path::SocketPath;
+use crate::thread::Cause;
+use crate::watch::{WatchConfig, WatchStatus};
 use crate::{AbsPathBuf, NixFile};
 use crossbeam_channel as chan;
-use slog::debug;
+use slog::{debug, error, info, warn};
 use std::collections::HashMap;
+use std::time::{Duration, Instant};
 
 #[derive(Debug, Clone)]
 /// Events created by the event loop.
 ///
 /// Union of build_loop::Event and NewListener for internal use.
 pub enum LoopHandlerEvent {
-    /// A new listener has joined for event streaming
-    NewListener(chan::Sender<Event>),
+    /// A new listener has joined for event streaming,
+    /// it only gets the events that match the filter, stamped with the time they were recorded
+    NewListener {
+        /// Where to send the events
+        tx: chan::Sender<StampedEvent>,
+        /// Which events to send
+        filter: EventFilter,
+        /// Only send the events of projects built for this user (see `IndicateActivity::uid`)
+        uid: Option<u32>,
+    },
     /// Events from a BuildLoop
-    BuildEvent(Event),
+    BuildEvent {
+        /// The user the project is built for, only set for a multi-user daemon
+        uid: Option<u32>,
+        /// The event
+        event: Event,
+    },
+    /// The file watcher of a BuildLoop changed
+    WatchStatus {
+        /// The nix file of the project
+        nix_file: NixFile,
+        /// The user the project is built for, only set for a multi-user daemon
+        uid: Option<u32>,
+        /// The new status
+        status: WatchStatus,
+    },
+    /// Somebody asked for the current `DaemonStatus`
+    StatusRequest {
+        /// Where to send the status
+        tx: chan::Sender<DaemonStatus>,
+        /// Only report the projects built for this user
+        uid: Option<u32>,
+    },
 }
 
 /// Indicate that the user is interested in a specific nix file.
@@ -36,6 +74,20 @@ pub struct IndicateActivity {
     pub nix_file: NixFile,
     /// Determines when this activity will cause a rebuild.
     pub rebuild: communicate::Rebuild,
+    /// The allowlisted environment of the client, if it sent one.
+    ///
+    /// A project is built with the environment of the first client that sent one.
+    /// Only a `Rebuild::Always` ping (`lorri internal ping`) switches to the environment of
+    /// another client, and rebuilds the project with it.
+    /// A multi-user daemon ignores it and always builds with its own environment.
+    pub env: Option<ClientEnv>,
+    /// Where to report what the daemon did, if the client wants to know.
+    pub ack: Option<chan::Sender<PingAck>>,
+    /// Where to send the result of the build the activity triggers, if the client waits
+    /// for it (see `BuildRequest::done`). Dropped if it triggers no build.
+    pub done: Option<chan::Sender<Event>>,
+    /// The user id of the client, if known.
+    pub uid: Option<u32>,
 }
 
 /// Keeps all state of the running `lorri daemon` service, watches nix files and runs builds.
@@ -45,15 +97,51 @@ pub struct Daemon {
     tx_build_events: chan::Sender<LoopHandlerEvent>,
     rx_build_events: chan::Receiver<LoopHandlerEvent>,
     mon_tx: chan::Sender<LoopHandlerEvent>,
+    /// Settings every `BuildLoop` is started with
+    build_settings: BuildSettings,
+    /// Whether the daemon serves multiple users, see `Daemon::new`
+    multi_user: bool,
+}
+
+/// Settings shared by all `BuildLoop`s of the daemon.
+#[derive(Clone)]
+struct BuildSettings {
     /// Extra options to pass to each nix invocation
     extra_nix_options: NixOptions,
+    /// Default watcher settings for every project
+    watch_config: WatchConfig,
+    /// Limits for the evaluation and build of every project
+    timeouts: Timeouts,
+    /// When to retry failed builds
+    retry: RetryPolicy,
+    /// When to restart crashed `BuildLoop`s
+    restart: RetryPolicy,
 }
 
 impl Daemon {
+    /// How long to wait before restarting a crashed `BuildLoop`.
+    /// Restarts never give up, but the delay doubles with every crash in a row.
+    const RESTART_BACKOFF: RetryPolicy = RetryPolicy {
+        max_retries: u32::MAX,
+        initial_backoff: Duration::from_secs(5),
+        max_backoff: Duration::from_secs(300),
+    };
+
     /// Create a new daemon. Also return an `chan::Receiver` that
     /// receives `LoopHandlerEvent`s for all builders this daemon
     /// supervises.
-    pub fn new(extra_nix_options: NixOptions) -> (Daemon, chan::Receiver<LoopHandlerEvent>) {
+    ///
+    /// A `multi_user` daemon lets every user connect to its socket.
+    /// It only builds nix files the pinging user can read, and
+    /// keeps the GC roots of each user in a directory of their own,
+    /// `<gc_root_dir>/<uid>`.
+    pub fn new(
+        extra_nix_options: NixOptions,
+        watch_config: WatchConfig,
+        timeouts: Timeouts,
+        retry: RetryPolicy,
+        multi_user: bool,
+    ) -> (Daemon, chan::Receiver<LoopHandlerEvent>) {
         let (tx_build_events, rx_build_events) = chan::unbounded();
         let (mon_tx, mon_rx) = chan::unbounded();
         (
@@ -61,7 +149,14 @@ impl Daemon {
                 tx_build_events,
                 rx_build_events,
                 mon_tx,
-                extra_nix_options,
+                build_settings: BuildSettings {
+                    extra_nix_options,
+                    watch_config,
+                    timeouts,
+                    retry,
+                    restart: Self::RESTART_BACKOFF,
+                },
+                multi_user,
             },
             mon_rx,
         )
@@ -71,6 +166,7 @@ impl Daemon {
     pub fn serve(
         &mut self,
         socket_path: &SocketPath,
+        tcp_listener: Option<(std::net::TcpListener, tcp::Token)>,
         gc_root_dir: &AbsPathBuf,
         cas: crate::cas::ContentAddressable,
         logger: &slog::Logger,
@@ -83,7 +179,15 @@ impl Daemon {
         let mut pool = crate::thread::Pool::new(logger.clone());
         let tx_build_events = self.tx_build_events.clone();
 
-        let server = server::Server::new(tx_activity, tx_build_events);
+        let server = server::Server::new(tx_activity, tx_build_events, self.multi_user);
+
+        if let Some((tcp_listener, token)) = tcp_listener {
+            let socket_path = socket_path.clone();
+            let logger = logger.clone();
+            pool.spawn("tcp-accept-loop", move || {
+                tcp::forward(tcp_listener, &socket_path, &token, &logger).never()
+            })?;
+        }
 
         let socket_path = socket_path.clone();
         let logger = logger.clone();
@@ -102,12 +206,14 @@ impl Daemon {
         })?;
 
         let tx_build_events = self.tx_build_events.clone();
-        let extra_nix_options = self.extra_nix_options.clone();
+        let build_settings = self.build_settings.clone();
+        let multi_user = self.multi_user;
         let gc_root_dir = gc_root_dir.clone();
         pool.spawn("build-instruction-handler", move || {
             Self::build_instruction_handler(
                 tx_build_events,
-                extra_nix_options,
+                build_settings,
+                multi_user,
                 rx_activity,
                 &gc_root_dir,
                 cas,
@@ -126,135 +232,895 @@ impl Daemon {
         mon_tx: chan::Sender<LoopHandlerEvent>,
         logger: &slog::Logger,
     ) {
-        let mut project_states: HashMap<NixFile, Event> = HashMap::new();
-        let mut event_listeners: Vec<chan::Sender<Event>> = Vec::new();
+        let mut project_states: HashMap<ProjectKey, StampedEvent> = HashMap::new();
+        let mut event_listeners: Vec<EventListener> = Vec::new();
+        let mut watch_states: HashMap<ProjectKey, WatchStatus> = HashMap::new();
+        // projects whose build loop crashed, until their next successful build
+        let mut crashed: HashMap<ProjectKey, String> = HashMap::new();
 
         for msg in rx_build_events {
             mon_tx
                 .send(msg.clone())
                 .expect("listener still to be there");
-            match &msg {
-                LoopHandlerEvent::BuildEvent(ev) => match ev {
-                    Event::SectionEnd => (),
-                    Event::Started { nix_file, .. }
-                    | Event::Completed { nix_file, .. }
-                    | Event::Failure { nix_file, .. } => {
-                        project_states.insert(nix_file.clone(), ev.clone());
-                        event_listeners.retain(|tx| {
-                            let keep = tx.send(ev.clone()).is_ok();
-                            debug!(logger,"Sent"; "event" => ?ev, "keep" => keep);
-                            keep
-                        })
+            match msg {
+                LoopHandlerEvent::BuildEvent { uid, event } => {
+                    let nix_file = match event.nix_file() {
+                        Some(nix_file) => nix_file.clone(),
+                        None => continue,
+                    };
+                    let key = ProjectKey { nix_file, uid };
+                    match &event {
+                        Event::Completed { .. } => {
+                            crashed.remove(&key);
+                        }
+                        Event::Crashed { reason, .. } => {
+                            crashed.insert(key.clone(), reason.clone());
+                        }
+                        _ => {}
                     }
-                },
-                LoopHandlerEvent::NewListener(tx) => {
-                    debug!(logger, "adding listener");
-                    let keep = project_states.values().all(|event| {
-                        let keeping = tx.send(event.clone()).is_ok();
-                        debug!(logger, "Sent snapshot"; "event" => ?&event, "keep" => keeping);
-                        keeping
+                    let stamped = StampedEvent::now(event, uid);
+                    event_listeners.retain(|listener| {
+                        if !listener.wants(&stamped) {
+                            return true;
+                        }
+                        let keep = listener.tx.send(stamped.clone()).is_ok();
+                        debug!(logger,"Sent"; "event" => ?stamped, "keep" => keep);
+                        keep
                     });
+                    project_states.insert(key, stamped);
+                }
+                LoopHandlerEvent::NewListener { tx, filter, uid } => {
+                    debug!(logger, "adding listener"; "filter" => ?filter, "uid" => uid);
+                    let listener = EventListener { tx, filter, uid };
+                    let keep = project_states
+                        .values()
+                        .filter(|stamped| listener.wants(stamped))
+                        .all(|event| {
+                            let keeping = listener.tx.send(event.clone()).is_ok();
+                            debug!(logger, "Sent snapshot"; "event" => ?&event, "keep" => keeping);
+                            keeping
+                        });
                     debug!(logger,"Finished snapshot"; "keep" => keep);
                     if keep {
-                        event_listeners.push(tx.clone());
+                        event_listeners.push(listener);
                     }
-                    event_listeners.retain(|tx| {
-                        let keep = tx.send(Event::SectionEnd).is_ok();
+                    let section_end = StampedEvent::now(Event::SectionEnd, None);
+                    event_listeners.retain(|listener| {
+                        let keep = listener.tx.send(section_end.clone()).is_ok();
                         debug!(logger, "Sent new listener sectionend"; "keep" => keep);
                         keep
                     })
                 }
+                LoopHandlerEvent::WatchStatus {
+                    nix_file,
+                    uid,
+                    status,
+                } => {
+                    watch_states.insert(ProjectKey { nix_file, uid }, status);
+                }
+                LoopHandlerEvent::StatusRequest { tx, uid } => {
+                    let mut keys = watch_states
+                        .keys()
+                        .chain(crashed.keys())
+                        .filter(|key| key.uid == uid)
+                        .collect::<Vec<_>>();
+                    keys.sort_by_key(|key| key.nix_file.as_absolute_path());
+                    keys.dedup();
+                    let projects = keys
+                        .into_iter()
+                        .map(|key| ProjectStatus {
+                            nix_file: key.nix_file.clone(),
+                            watch: watch_states.get(key).cloned().unwrap_or_default(),
+                            crashed: crashed.get(key).cloned(),
+                        })
+                        .collect();
+                    // the requester might have timed out already
+                    let _ = tx.send(DaemonStatus { projects });
+                }
             }
         }
     }
 
     fn build_instruction_handler(
-        // TODO: use the pool here
-        // pool: &mut crate::thread::Pool,
         tx_build_events: chan::Sender<LoopHandlerEvent>,
-        extra_nix_options: NixOptions,
+        build_settings: BuildSettings,
+        multi_user: bool,
         rx_activity: chan::Receiver<IndicateActivity>,
         gc_root_dir: &AbsPathBuf,
         cas: crate::cas::ContentAddressable,
         logger: &slog::Logger,
     ) {
-        // A thread for each `BuildLoop`, keyed by the nix files listened on.
-        let mut handler_threads: HashMap<NixFile, chan::Sender<()>> = HashMap::new();
-
-        // For each build instruction, add the corresponding file
-        // to the watch list.
-        for IndicateActivity { nix_file, rebuild } in rx_activity {
-            let project = crate::project::Project::new(nix_file, gc_root_dir, cas.clone())
-                // TODO: the project needs to create its gc root dir
-                .unwrap();
-
-            let key = project.nix_file.clone();
-            let project_is_watched = handler_threads.get(&key);
+        let spawner = BuildLoopSpawner {
+            tx_build_events: tx_build_events.clone(),
+            build_settings,
+            gc_root_dir: gc_root_dir.clone(),
+            cas,
+            logger: logger.clone(),
+        };
+        // The `BuildLoop` threads. We spawn into the pool while joining,
+        // so that dead build loops are noticed as soon as they die.
+        let mut pool: crate::thread::Pool<String> = crate::thread::Pool::new(logger.clone());
+        let rx_dead = pool.deaths();
+        // A `BuildLoop` for each nix file listened on.
+        let mut projects: HashMap<ProjectKey, ProjectHandle> = HashMap::new();
+        // The project each `BuildLoop` thread builds.
+        let mut threads: HashMap<std::thread::ThreadId, ProjectKey> = HashMap::new();
+        // Crashed build loops, and when to restart them.
+        let mut restarts: Vec<(Instant, ProjectKey)> = Vec::new();
 
-            let send_ping =
-                |to: &chan::Sender<()>| to.send(()).expect("could not ping the build loop");
+        let send_ping = |to: &chan::Sender<BuildRequest>,
+                         env: Option<ClientEnv>,
+                         done: Option<chan::Sender<Event>>| {
+            to.send(BuildRequest { env, done })
+                .expect("could not ping the build loop")
+        };
 
-            match (project_is_watched, rebuild) {
-                (Some(builder), communicate::Rebuild::Always) => {
-                    debug!(logger, "triggering rebuild"; "project" => key, "cause" => "unconditional ping");
-                    send_ping(builder)
-                }
-                (Some(_), communicate::Rebuild::OnlyIfNotYetWatching) => {
-                    debug!(logger, "skipping rebuild"; "project" => key, "cause" => "already watching");
-                }
-                // only add if there is no no build_loop for this file yet.
-                (None, _) => {
-                    let (tx_ping, rx_ping) = chan::unbounded();
-                    // cloning the tx means the daemon’s rx gets all
-                    // messages from all builders.
-                    let tx_build_events = tx_build_events.clone();
-                    let extra_nix_options = extra_nix_options.clone();
-                    let logger = logger.clone();
-                    let logger2 = logger.clone();
-                    // TODO: how to use the pool here?
-                    // We cannot just spawn new threads once messages come in,
-                    // because then then pool objects is stuck in this loop
-                    // and will never start to wait for joins, which means
-                    // we don’t catch panics as they happen!
-                    // If we can get the pool to “wait for join but also spawn new
-                    // thread when you get a message” that could work!
-                    // pool.spawn(format!("build_loop for {}", nix_file.display()),
-                    let _ = std::thread::spawn(move || {
-                        match BuildLoop::new(&project, extra_nix_options, logger) {
-                            Ok(mut build_loop) => {
-                                build_loop.forever(tx_build_events, rx_ping).never()
-                            }
-                            Err(err) =>
-                            // TODO: omg this is so bad, too many layers of wrapping
-                            {
-                                tx_build_events
-                                    .send(LoopHandlerEvent::BuildEvent(Event::Failure {
-                                        nix_file: project.nix_file.clone(),
-                                        failure: crate::builder::BuildError::Io {
-                                            msg: err
-                                                .context(format!(
-                                                    "could not start the watcher for {}",
-                                                    &project.nix_file.display()
-                                                ))
-                                                .to_string(),
-                                        },
-                                    }))
-                                    .expect("rx_build_events hung up")
+        loop {
+            let next_restart = restarts
+                .iter()
+                .map(|(at, _)| *at)
+                .min()
+                .map_or_else(chan::never, chan::at);
+            chan::select! {
+                // For each build instruction, add the corresponding file
+                // to the watch list.
+                recv(rx_activity) -> msg => {
+                    let IndicateActivity { nix_file, rebuild, env, ack, done, uid } = match msg {
+                        Ok(activity) => activity,
+                        // the server is gone, the daemon is shutting down
+                        Err(chan::RecvError) => return,
+                    };
+                    // A multi-user daemon evaluates as its own user, so clients must not configure
+                    // nix for it (e.g. `NIX_CONFIG=allow-unsafe-native-code-during-evaluation = true`).
+                    let env = if multi_user {
+                        if env.is_some() {
+                            debug!(logger, "ignoring the environment of the client"; "uid" => uid);
+                        }
+                        None
+                    } else {
+                        env
+                    };
+                    // every user gets their own build of a project, with their own GC roots
+                    let key = ProjectKey { nix_file, uid: if multi_user { uid } else { None } };
+                    let permitted = !multi_user
+                        || uid.is_some_and(|uid| access::can_read(uid, key.nix_file.as_absolute_path()));
+                    let acknowledgement = match (projects.get_mut(&key), rebuild) {
+                        _ if !permitted => {
+                            warn!(logger, "ignoring ping, the client can’t read the nix file"; "project" => &key.nix_file, "uid" => uid);
+                            PingAck::PermissionDenied
+                        }
+                        (Some(project), communicate::Rebuild::Always) => {
+                            debug!(logger, "triggering rebuild"; "project" => &key.nix_file, "cause" => "unconditional ping");
+                            if env.is_some() {
+                                project.env.clone_from(&env);
                             }
+                            send_ping(&project.tx_ping, env, done);
+                            PingAck::RebuildScheduled
+                        }
+                        // the project was built with the daemon’s environment so far
+                        (Some(project), communicate::Rebuild::OnlyIfNotYetWatching)
+                            if project.env.is_none() && env.is_some() =>
+                        {
+                            debug!(logger, "triggering rebuild"; "project" => &key.nix_file, "cause" => "client sent its environment", "env" => ?&env);
+                            project.env.clone_from(&env);
+                            send_ping(&project.tx_ping, env, done);
+                            PingAck::RebuildScheduled
+                        }
+                        // Another client’s environment is in use. Switching to this one would make
+                        // two shells with different environments rebuild the project back and forth.
+                        (Some(project), communicate::Rebuild::OnlyIfNotYetWatching)
+                            if env.is_some() && env != project.env =>
+                        {
+                            info!(logger, "skipping rebuild, the project is built with the environment of another client; run `lorri internal ping` to switch"; "project" => &key.nix_file, "env" => ?&env, "current_env" => ?&project.env);
+                            PingAck::AlreadyWatching
+                        }
+                        (Some(_), communicate::Rebuild::OnlyIfNotYetWatching) => {
+                            debug!(logger, "skipping rebuild"; "project" => &key.nix_file, "cause" => "already watching");
+                            PingAck::AlreadyWatching
+                        }
+                        // only add if there is no no build_loop for this file yet.
+                        (None, _) => {
+                            let (tx_ping, rx_ping) = chan::unbounded();
+                            let thread_id = spawner
+                                .spawn(&mut pool, &key, rx_ping.clone())
+                                .expect("could not spawn a build loop thread");
+                            threads.insert(thread_id, key.clone());
+                            debug!(logger, "triggering rebuild"; "project" => &key.nix_file, "cause" => "new project");
+                            send_ping(&tx_ping, env.clone(), done);
+                            projects.insert(
+                                key,
+                                ProjectHandle {
+                                    tx_ping,
+                                    rx_ping,
+                                    env,
+                                    crashes: 0,
+                                    started: Instant::now(),
+                                },
+                            );
+                            PingAck::Accepted
+                        }
+                    };
+                    if let Some(ack) = ack {
+                        // the client might have hung up already
+                        let _ = ack.send(acknowledgement);
+                    }
+                },
+                recv(rx_dead) -> msg => {
+                    let death = msg.expect("the thread pool is still alive");
+                    let key = threads
+                        .remove(&death.thread_id)
+                        .expect("a build loop thread we don’t know about died");
+                    let reason = match pool.join_dead(death).1 {
+                        Cause::Natural(Ok(())) => "stopped".to_string(),
+                        Cause::Natural(Err(reason)) => reason,
+                        Cause::Paniced(panic) => {
+                            format!("panicked: {}", crate::thread::panic_message(&*panic))
                         }
+                    };
+                    let project = projects
+                        .get_mut(&key)
+                        .expect("every build loop belongs to a project");
+                    let restart = &spawner.build_settings.restart;
+                    // a build loop that ran for a while is not crashing in a loop
+                    if project.started.elapsed() > restart.max_backoff {
+                        project.crashes = 0;
+                    }
+                    project.crashes += 1;
+                    let delay = restart.backoff(project.crashes);
+                    error!(logger, "build loop crashed, restarting"; "project" => &key.nix_file, "reason" => &reason, "crashes" => project.crashes, "delay" => ?delay);
+                    if tx_build_events
+                        .send(LoopHandlerEvent::BuildEvent {
+                            uid: key.uid,
+                            event: Event::Crashed {
+                                nix_file: key.nix_file.clone(),
+                                reason,
+                                restart_in_secs: delay.as_secs(),
+                            },
+                        })
+                        .is_err()
+                    {
+                        // the daemon is shutting down
+                        return;
+                    }
+                    restarts.push((Instant::now() + delay, key));
+                },
+                recv(next_restart) -> _ => {
+                    let now = Instant::now();
+                    let (due, pending) = restarts.into_iter().partition(|(at, _)| *at <= now);
+                    restarts = pending;
+                    for (_, key) in due {
+                        let project = projects
+                            .get_mut(&key)
+                            .expect("every restart belongs to a project");
+                        let thread_id = spawner
+                            .spawn(&mut pool, &key, project.rx_ping.clone())
+                            .expect("could not spawn a build loop thread");
+                        threads.insert(thread_id, key.clone());
+                        project.started = Instant::now();
+                        debug!(logger, "triggering rebuild"; "project" => &key.nix_file, "cause" => "build loop restarted");
+                        // build right away, the crash might have happened before the build finished
+                        send_ping(&project.tx_ping, project.env.clone(), None);
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// Identifies a project the daemon builds.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+struct ProjectKey {
+    /// The nix file of the project; for a multi-user daemon, the path the server
+    /// resolved and the access check ran on, which is built without resolving it again
+    nix_file: NixFile,
+    /// The user the project is built for, only set for a multi-user daemon
+    uid: Option<u32>,
+}
+
+/// A client that streams events, see `LoopHandlerEvent::NewListener`.
+struct EventListener {
+    tx: chan::Sender<StampedEvent>,
+    filter: EventFilter,
+    uid: Option<u32>,
+}
+
+impl EventListener {
+    /// Whether the listener wants to get `stamped`.
+    fn wants(&self, stamped: &StampedEvent) -> bool {
+        stamped.uid == self.uid && self.filter.matches(&stamped.event)
+    }
+}
+
+/// A project the daemon builds.
+struct ProjectHandle {
+    /// Pings the project’s `BuildLoop`.
+    tx_ping: chan::Sender<BuildRequest>,
+    /// Kept to hand it to the next `BuildLoop` if the current one dies.
+    rx_ping: chan::Receiver<BuildRequest>,
+    /// The environment the project is built with (see `IndicateActivity::env`);
+    /// a restarted `BuildLoop` builds with it.
+    env: Option<ClientEnv>,
+    /// How often the `BuildLoop` crashed in a row.
+    crashes: u32,
+    /// When the current `BuildLoop` was started.
+    started: Instant,
+}
+
+/// Everything needed to start a `BuildLoop` thread for a project.
+struct BuildLoopSpawner {
+    tx_build_events: chan::Sender<LoopHandlerEvent>,
+    build_settings: BuildSettings,
+    gc_root_dir: AbsPathBuf,
+    cas: crate::cas::ContentAddressable,
+    logger: slog::Logger,
+}
+
+impl BuildLoopSpawner {
+    /// Spawn a thread into `pool` that builds the project whenever `rx_ping` receives a request.
+    /// The thread only returns if the `BuildLoop` cannot go on, after reporting why as `Event::Failure`.
+    fn spawn(
+        &self,
+        pool: &mut crate::thread::Pool<String>,
+        key: &ProjectKey,
+        rx_ping: chan::Receiver<BuildRequest>,
+    ) -> std::io::Result<std::thread::ThreadId> {
+        let tx_build_events = self.tx_build_events.clone();
+        let BuildSettings {
+            extra_nix_options,
+            watch_config,
+            timeouts,
+            retry,
+            ..
+        } = self.build_settings.clone();
+        let gc_root_dir = match key.uid {
+            Some(uid) => self.gc_root_dir.join(uid.to_string()),
+            None => self.gc_root_dir.clone(),
+        };
+        let cas = self.cas.clone();
+        let logger = self.logger.clone();
+        let nix_file = key.nix_file.clone();
+        let uid = key.uid;
+        pool.spawn(
+            format!("build_loop for {}", nix_file.display()),
+            std::panic::AssertUnwindSafe(move || -> Result<(), String> {
+                let report = |msg: String| {
+                    let failure = BuildError::Io {
+                        msg,
+                        transient: false,
+                    };
+                    let reason = failure.to_string();
+                    // the daemon might be shutting down
+                    let _ = tx_build_events.send(LoopHandlerEvent::BuildEvent {
+                        uid,
+                        event: Event::Failure {
+                            nix_file: nix_file.clone(),
+                            failure,
+                        },
                     });
+                    reason
+                };
+                let project = crate::project::Project::new(nix_file.clone(), &gc_root_dir, cas);
+                // also creates `<gc_root_dir>/<uid>` for the clients of a multi-user daemon,
+                // which can’t do it themselves
+                project
+                    .create_gc_root_dir()
+                    .map_err(|err| report(format!("could not set up the project: {}", err)))?;
+                let mut build_loop = BuildLoop::new(
+                    &project,
+                    extra_nix_options,
+                    watch_config,
+                    timeouts,
+                    retry,
+                    logger,
+                )
+                .map_err(|err| report(format!("could not start the watcher: {:#}", err)))?;
+                // `forever` reports its errors itself
+                build_loop
+                    .forever(uid, tx_build_events, rx_ping)
+                    .map(|never| never.never())
+                    .map_err(|err| err.to_string())
+            }),
+        )
+    }
+}
 
-                    let e = handler_threads.insert(key.clone(), tx_ping.clone());
-                    match e {
-                        None => {}
-                        Some(_) => {
-                            panic!("handler_threads had the key, but we already checked before")
-                        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn multi_user_daemon_ignores_unreadable_projects() {
+        let dir = tempfile::tempdir().unwrap();
+        let shell_nix = dir.path().join("shell.nix");
+        std::fs::write(&shell_nix, "{}").unwrap();
+        std::fs::set_permissions(&shell_nix, std::fs::Permissions::from_mode(0o600)).unwrap();
+        let nix_file = NixFile::from(AbsPathBuf::new(shell_nix).unwrap());
+        let me = nix::unistd::getuid().as_raw();
+        let other = if me == 65534 { 65533 } else { 65534 };
+
+        let (daemon, _rx) = Daemon::new(
+            NixOptions::empty(),
+            WatchConfig::default(),
+            Timeouts::default(),
+            RetryPolicy::default(),
+            true,
+        );
+        let gc_root_dir = AbsPathBuf::new(dir.path().join("gc_roots")).unwrap();
+        let cas =
+            crate::cas::ContentAddressable::new(AbsPathBuf::new(dir.path().join("cas")).unwrap())
+                .unwrap();
+        let logger = crate::logging::test_logger("multi_user_daemon_ignores_unreadable_projects");
+        let (tx_activity, rx_activity) = chan::unbounded();
+        let handler = std::thread::spawn(move || {
+            Daemon::build_instruction_handler(
+                daemon.tx_build_events.clone(),
+                daemon.build_settings.clone(),
+                daemon.multi_user,
+                rx_activity,
+                &gc_root_dir,
+                cas,
+                &logger,
+            )
+        });
+
+        for uid in [Some(other), None] {
+            let (tx_ack, rx_ack) = chan::bounded(1);
+            tx_activity
+                .send(IndicateActivity {
+                    nix_file: nix_file.clone(),
+                    rebuild: communicate::Rebuild::Always,
+                    env: None,
+                    ack: Some(tx_ack),
+                    done: None,
+                    uid,
+                })
+                .unwrap();
+            assert_eq!(rx_ack.recv().unwrap(), PingAck::PermissionDenied);
+        }
+        drop(tx_activity);
+        handler.join().unwrap();
+        // nothing was built for anybody
+        assert!(!dir.path().join("gc_roots").exists());
+    }
+
+    #[test]
+    fn multi_user_daemon_ignores_client_environments() {
+        let dir = tempfile::tempdir().unwrap();
+        let shell_nix = dir.path().join("shell.nix");
+        std::fs::write(&shell_nix, "{}").unwrap();
+        let nix_file = NixFile::from(AbsPathBuf::new(shell_nix).unwrap());
+        let me = nix::unistd::getuid().as_raw();
+        let env = Some(ClientEnv::from_vars(vec![(
+            "NIX_CONFIG".to_string(),
+            "allow-unsafe-native-code-during-evaluation = true".to_string(),
+        )]));
+
+        let (daemon, _rx) = Daemon::new(
+            NixOptions::empty(),
+            WatchConfig::default(),
+            Timeouts::default(),
+            RetryPolicy::default(),
+            true,
+        );
+        let gc_root_dir = AbsPathBuf::new(dir.path().join("gc_roots")).unwrap();
+        let cas =
+            crate::cas::ContentAddressable::new(AbsPathBuf::new(dir.path().join("cas")).unwrap())
+                .unwrap();
+        let logger = crate::logging::test_logger("multi_user_daemon_ignores_client_environments");
+        let (tx_activity, rx_activity) = chan::unbounded();
+        let handler = std::thread::spawn(move || {
+            Daemon::build_instruction_handler(
+                daemon.tx_build_events.clone(),
+                daemon.build_settings.clone(),
+                daemon.multi_user,
+                rx_activity,
+                &gc_root_dir,
+                cas,
+                &logger,
+            )
+        });
+        let ping = |rebuild, env| {
+            let (tx_ack, rx_ack) = chan::bounded(1);
+            tx_activity
+                .send(IndicateActivity {
+                    nix_file: nix_file.clone(),
+                    rebuild,
+                    env,
+                    ack: Some(tx_ack),
+                    done: None,
+                    uid: Some(me),
+                })
+                .unwrap();
+            rx_ack.recv().unwrap()
+        };
+        use communicate::Rebuild::{Always, OnlyIfNotYetWatching};
+
+        assert_eq!(ping(OnlyIfNotYetWatching, None), PingAck::Accepted);
+        // a single-user daemon would rebuild with the environment of the client,
+        // a multi-user daemon keeps building with its own
+        assert_eq!(
+            ping(OnlyIfNotYetWatching, env.clone()),
+            PingAck::AlreadyWatching
+        );
+        assert_eq!(ping(Always, env.clone()), PingAck::RebuildScheduled);
+        // the explicit rebuild didn’t switch the project to the client’s environment either
+        assert_eq!(ping(OnlyIfNotYetWatching, env), PingAck::AlreadyWatching);
+
+        drop(tx_activity);
+        handler.join().unwrap();
+    }
+
+    #[test]
+    fn the_first_client_environment_wins() {
+        let dir = tempfile::tempdir().unwrap();
+        let shell_nix = dir.path().join("shell.nix");
+        std::fs::write(&shell_nix, "{}").unwrap();
+        let nix_file = NixFile::from(AbsPathBuf::new(shell_nix).unwrap());
+        let env = |nix_path: &str| {
+            Some(ClientEnv::from_vars(vec![(
+                "NIX_PATH".to_string(),
+                nix_path.to_string(),
+            )]))
+        };
+
+        let (daemon, _rx) = Daemon::new(
+            NixOptions::empty(),
+            WatchConfig::default(),
+            Timeouts::default(),
+            RetryPolicy::default(),
+            false,
+        );
+        let gc_root_dir = AbsPathBuf::new(dir.path().join("gc_roots")).unwrap();
+        let cas =
+            crate::cas::ContentAddressable::new(AbsPathBuf::new(dir.path().join("cas")).unwrap())
+                .unwrap();
+        let logger = crate::logging::test_logger("the_first_client_environment_wins");
+        let (tx_activity, rx_activity) = chan::unbounded();
+        let handler = std::thread::spawn(move || {
+            Daemon::build_instruction_handler(
+                daemon.tx_build_events.clone(),
+                daemon.build_settings.clone(),
+                daemon.multi_user,
+                rx_activity,
+                &gc_root_dir,
+                cas,
+                &logger,
+            )
+        });
+        let ping = |rebuild, env| {
+            let (tx_ack, rx_ack) = chan::bounded(1);
+            tx_activity
+                .send(IndicateActivity {
+                    nix_file: nix_file.clone(),
+                    rebuild,
+                    env,
+                    ack: Some(tx_ack),
+                    done: None,
+                    uid: None,
+                })
+                .unwrap();
+            rx_ack.recv().unwrap()
+        };
+        use communicate::Rebuild::{Always, OnlyIfNotYetWatching};
+
+        // a client without an environment, e.g. an old lorri
+        assert_eq!(ping(OnlyIfNotYetWatching, None), PingAck::Accepted);
+        assert_eq!(
+            ping(OnlyIfNotYetWatching, env("a")),
+            PingAck::RebuildScheduled
+        );
+        assert_eq!(
+            ping(OnlyIfNotYetWatching, env("a")),
+            PingAck::AlreadyWatching
+        );
+        // a second shell with another NIX_PATH doesn’t take over
+        assert_eq!(
+            ping(OnlyIfNotYetWatching, env("b")),
+            PingAck::AlreadyWatching
+        );
+        assert_eq!(
+            ping(OnlyIfNotYetWatching, env("a")),
+            PingAck::AlreadyWatching
+        );
+        // unless it pings explicitly
+        assert_eq!(ping(Always, env("b")), PingAck::RebuildScheduled);
+        assert_eq!(
+            ping(OnlyIfNotYetWatching, env("a")),
+            PingAck::AlreadyWatching
+        );
+
+        drop(tx_activity);
+        handler.join().unwrap();
+    }
+
+    #[test]
+    fn snapshots_carry_the_time_events_were_recorded() {
+        let logger = crate::logging::test_logger("snapshots_carry_the_time_events_were_recorded");
+        let nix_file = NixFile::from(AbsPathBuf::new("/p/shell.nix".into()).unwrap());
+        let (tx, rx) = chan::unbounded();
+        let (mon_tx, _mon_rx) = chan::unbounded();
+        let build_loop = std::thread::spawn(move || Daemon::build_loop(rx, mon_tx, &logger));
+
+        let before = std::time::SystemTime::now();
+        tx.send(LoopHandlerEvent::BuildEvent {
+            uid: None,
+            event: Event::Started {
+                nix_file,
+                reason: crate::build_loop::ReasonI::PingReceived,
+            },
+        })
+        .unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        let (tx_event, rx_event) = chan::unbounded();
+        tx.send(LoopHandlerEvent::NewListener {
+            tx: tx_event,
+            filter: EventFilter::default(),
+            uid: None,
+        })
+        .unwrap();
+        let started = rx_event.recv().unwrap();
+        let section_end = rx_event.recv().unwrap();
+        assert!(matches!(started.event, Event::Started { .. }));
+        assert!(matches!(section_end.event, Event::SectionEnd));
+        // millisecond precision
+        assert!(started.time() + Duration::from_millis(1) >= before);
+        assert!(section_end.time() >= started.time() + Duration::from_millis(50));
+
+        drop(tx);
+        build_loop.join().unwrap();
+    }
+
+    #[test]
+    fn users_only_see_their_own_projects() {
+        let logger = crate::logging::test_logger("users_only_see_their_own_projects");
+        let nix_file = NixFile::from(AbsPathBuf::new("/p/shell.nix".into()).unwrap());
+        let (tx, rx) = chan::unbounded();
+        let (mon_tx, _mon_rx) = chan::unbounded();
+        let build_loop = std::thread::spawn(move || Daemon::build_loop(rx, mon_tx, &logger));
+
+        // two users build the same nix file
+        let (alice, bob) = (Some(1000), Some(1001));
+        let events = [
+            (
+                alice,
+                Event::Started {
+                    nix_file: nix_file.clone(),
+                    reason: crate::build_loop::ReasonI::PingReceived,
+                },
+            ),
+            (
+                bob,
+                Event::Crashed {
+                    nix_file: nix_file.clone(),
+                    reason: "oops".into(),
+                    restart_in_secs: 5,
+                },
+            ),
+        ];
+        for (uid, event) in events {
+            tx.send(LoopHandlerEvent::BuildEvent { uid, event })
+                .unwrap();
+        }
+        for (uid, watched) in [(alice, 3), (bob, 7)] {
+            tx.send(LoopHandlerEvent::WatchStatus {
+                nix_file: nix_file.clone(),
+                uid,
+                status: WatchStatus {
+                    watched,
+                    ..WatchStatus::default()
+                },
+            })
+            .unwrap();
+        }
+
+        let snapshot = |uid| {
+            let (tx_event, rx_event) = chan::unbounded();
+            tx.send(LoopHandlerEvent::NewListener {
+                tx: tx_event,
+                filter: EventFilter::default(),
+                uid,
+            })
+            .unwrap();
+            rx_event
+                .iter()
+                .take_while(|stamped| !matches!(stamped.event, Event::SectionEnd))
+                .collect::<Vec<_>>()
+        };
+        let status = |uid| {
+            let (tx_status, rx_status) = chan::bounded(1);
+            tx.send(LoopHandlerEvent::StatusRequest { tx: tx_status, uid })
+                .unwrap();
+            rx_status.recv().unwrap().projects
+        };
+
+        let alices = snapshot(alice);
+        assert!(
+            matches!(alices.as_slice(), [s] if s.uid == alice && matches!(s.event, Event::Started { .. })),
+            "{:?}",
+            alices
+        );
+        let bobs = snapshot(bob);
+        assert!(
+            matches!(bobs.as_slice(), [s] if s.uid == bob && matches!(s.event, Event::Crashed { .. })),
+            "{:?}",
+            bobs
+        );
+        assert!(snapshot(Some(1002)).is_empty());
+
+        let alices = status(alice);
+        assert_eq!(alices.len(), 1);
+        assert_eq!(alices[0].watch.watched, 3);
+        assert_eq!(alices[0].crashed, None);
+        let bobs = status(bob);
+        assert_eq!(bobs.len(), 1);
+        assert_eq!(bobs[0].watch.watched, 7);
+        assert_eq!(bobs[0].crashed.as_deref(), Some("oops"));
+        assert!(status(Some(1002)).is_empty());
+
+        drop(tx);
+        build_loop.join().unwrap();
+    }
+
+    /// Wait for the next `BuildEvent` that `pred` accepts, remembering all events on the way.
+    fn wait_for_event(
+        rx: &chan::Receiver<LoopHandlerEvent>,
+        seen: &mut Vec<Event>,
+        pred: impl Fn(&Event) -> bool,
+    ) -> Event {
+        loop {
+            match rx.recv_timeout(Duration::from_secs(30)) {
+                Ok(LoopHandlerEvent::BuildEvent { event, .. }) => {
+                    seen.push(event.clone());
+                    if pred(&event) {
+                        return event;
                     }
-                    debug!(logger2, "triggering rebuild"; "project" => key, "cause" => "new project");
-                    send_ping(&tx_ping);
                 }
+                Ok(_) => {}
+                Err(err) => panic!("no matching event: {}, got {:?}", err, seen),
             }
         }
     }
+
+    #[test]
+    fn crashed_build_loops_are_reported_and_restarted() {
+        let logger = crate::logging::test_logger("crashed_build_loops_are_reported_and_restarted");
+        let dir = tempfile::tempdir().unwrap();
+        let nix_file = |name: &str| {
+            let path = dir.path().join(name).join("shell.nix");
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, "{}").unwrap();
+            NixFile::from(AbsPathBuf::new(path).unwrap())
+        };
+        let (broken, healthy) = (nix_file("broken"), nix_file("healthy"));
+        // a file where the project’s GC root directory should be
+        let gc_root_dir = AbsPathBuf::new(dir.path().join("gc_roots")).unwrap();
+        let blocker = gc_root_dir.as_path().join(format!(
+            "{:x}",
+            md5::compute(broken.as_absolute_path().as_os_str().as_encoded_bytes())
+        ));
+        std::fs::create_dir_all(gc_root_dir.as_path()).unwrap();
+        std::fs::write(&blocker, "").unwrap();
+
+        let (mut daemon, mon_rx) = Daemon::new(
+            NixOptions::empty(),
+            WatchConfig::default(),
+            Timeouts::default(),
+            RetryPolicy::default(),
+            false,
+        );
+        daemon.build_settings.restart = RetryPolicy {
+            max_retries: u32::MAX,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_millis(50),
+        };
+        let cas =
+            crate::cas::ContentAddressable::new(AbsPathBuf::new(dir.path().join("cas")).unwrap())
+                .unwrap();
+        // the build loop thread insists on somebody receiving its events
+        let (tx_events, rx_events) = chan::unbounded();
+        std::thread::spawn(move || {
+            for event in mon_rx {
+                let _ = tx_events.send(event);
+            }
+        });
+        let (rx_build_events, mon_tx) = (daemon.rx_build_events.clone(), daemon.mon_tx.clone());
+        let logger2 = logger.clone();
+        std::thread::spawn(move || Daemon::build_loop(rx_build_events, mon_tx, &logger2));
+        let (tx_activity, rx_activity) = chan::unbounded();
+        let tx_build_events = daemon.tx_build_events.clone();
+        std::thread::spawn(move || {
+            Daemon::build_instruction_handler(
+                daemon.tx_build_events.clone(),
+                daemon.build_settings.clone(),
+                daemon.multi_user,
+                rx_activity,
+                &gc_root_dir,
+                cas,
+                &logger,
+            )
+        });
+        let ping = |nix_file: &NixFile| {
+            let (tx_ack, rx_ack) = chan::bounded(1);
+            tx_activity
+                .send(IndicateActivity {
+                    nix_file: nix_file.clone(),
+                    rebuild: communicate::Rebuild::Always,
+                    env: None,
+                    ack: Some(tx_ack),
+                    done: None,
+                    uid: None,
+                })
+                .unwrap();
+            rx_ack.recv().unwrap()
+        };
+        let mut seen = vec![];
+
+        assert_eq!(ping(&broken), PingAck::Accepted);
+        assert_eq!(ping(&healthy), PingAck::Accepted);
+        let is = |nix_file: &NixFile| {
+            let nix_file = nix_file.clone();
+            move |event: &Event| event.nix_file() == Some(&nix_file)
+        };
+        match wait_for_event(&rx_events, &mut seen, is(&broken)) {
+            Event::Failure { failure, .. } => assert!(
+                failure.to_string().contains("could not set up the project"),
+                "{}",
+                failure
+            ),
+            event => panic!("expected the failure to be reported first, got {:?}", event),
+        }
+        assert!(matches!(
+            wait_for_event(&rx_events, &mut seen, is(&broken)),
+            Event::Crashed { .. }
+        ));
+
+        let (tx_status, rx_status) = chan::bounded(1);
+        tx_build_events
+            .send(LoopHandlerEvent::StatusRequest {
+                tx: tx_status,
+                uid: None,
+            })
+            .unwrap();
+        let status = rx_status.recv().unwrap();
+        assert!(status.project(&broken).unwrap().crashed.is_some());
+
+        // the restarted build loop fails the same way, until the problem goes away
+        assert!(matches!(
+            wait_for_event(&rx_events, &mut seen, is(&broken)),
+            Event::Failure { .. }
+        ));
+        std::fs::remove_file(&blocker).unwrap();
+        wait_for_event(
+            &rx_events,
+            &mut seen,
+            |event| matches!(event, Event::Started { nix_file, .. } if nix_file == &broken),
+        );
+
+        // the other project was not affected, and still builds
+        assert_eq!(ping(&healthy), PingAck::RebuildScheduled);
+        wait_for_event(&rx_events, &mut seen, is(&healthy));
+        assert!(
+            !seen.iter().any(
+                |event| matches!(event, Event::Crashed { nix_file, .. } if nix_file == &healthy)
+            ),
+            "{:?}",
+            seen
+        );
+        let (tx_status, rx_status) = chan::bounded(1);
+        tx_build_events
+            .send(LoopHandlerEvent::StatusRequest {
+                tx: tx_status,
+                uid: None,
+            })
+            .unwrap();
+        assert_eq!(
+            rx_status
+                .recv()
+                .unwrap()
+                .project(&healthy)
+                .and_then(|project| project.crashed.clone()),
+            None
+        );
+    }
 }
@@ -0,0 +1,135 @@
+//! Check what the clients of a shared daemon may do.
+//!
+//! A daemon that serves multiple users runs with more permissions than each of them,
+//! so it only builds nix files the requesting user could read themselves.
+
+use crate::{AbsPathBuf, NixFile};
+use std::fs::Metadata;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+/// Read permission in the mode bits.
+const READ: u32 = 0o4;
+/// Search (execute) permission in the mode bits.
+const SEARCH: u32 = 0o1;
+
+/// Whether the user `uid` can read the file at `path`,
+/// going by the Unix permission bits of the file and all its parent directories.
+/// ACLs are not taken into account. Paths the daemon itself can’t access are not readable.
+///
+/// `path` must be `resolve`d, it is checked as it is and never resolved again,
+/// so that the daemon builds the file it checked. If a symlink appeared in the
+/// path since, the file is not readable.
+pub fn can_read(uid: u32, path: &Path) -> bool {
+    if uid == 0 {
+        return true;
+    }
+    let groups = groups(uid);
+    let allowed = |path: &Path, bits: u32| {
+        std::fs::symlink_metadata(path)
+            .is_ok_and(|meta| !meta.file_type().is_symlink() && permits(&meta, uid, &groups, bits))
+    };
+    path.ancestors().skip(1).all(|dir| allowed(dir, SEARCH)) && allowed(path, READ)
+}
+
+/// `nix_file` with all symlinks resolved, so that a symlink can’t give access to a file
+/// in a directory the user can’t search. `nix_file` itself if it can’t be resolved.
+pub fn resolve(nix_file: NixFile) -> NixFile {
+    match std::fs::canonicalize(nix_file.as_absolute_path()).map(AbsPathBuf::new) {
+        Ok(Ok(path)) => NixFile::from(path),
+        _ => nix_file,
+    }
+}
+
+/// Whether the owner, group or other bits of `meta` (whichever apply to the user) contain `bits`.
+fn permits(meta: &Metadata, uid: u32, groups: &[u32], bits: u32) -> bool {
+    let shift = if meta.uid() == uid {
+        6
+    } else if groups.contains(&meta.gid()) {
+        3
+    } else {
+        0
+    };
+    (meta.mode() >> shift) & bits == bits
+}
+
+/// The primary and supplementary groups of the user `uid`, from the user database.
+#[cfg(target_os = "linux")]
+fn groups(uid: u32) -> Vec<u32> {
+    use nix::unistd::{getgrouplist, Uid, User};
+    match User::from_uid(Uid::from_raw(uid)) {
+        Ok(Some(user)) => std::ffi::CString::new(user.name)
+            .ok()
+            .and_then(|name| getgrouplist(&name, user.gid).ok())
+            .unwrap_or_else(|| vec![user.gid])
+            .into_iter()
+            .map(|gid| gid.as_raw())
+            .collect(),
+        // a user that doesn’t exist is in no group
+        _ => vec![],
+    }
+}
+
+/// The primary group of the user `uid`, from the user database.
+#[cfg(not(target_os = "linux"))]
+fn groups(uid: u32) -> Vec<u32> {
+    use nix::unistd::{Uid, User};
+    match User::from_uid(Uid::from_raw(uid)) {
+        Ok(Some(user)) => vec![user.gid.as_raw()],
+        _ => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{can_read, resolve};
+    use crate::{AbsPathBuf, NixFile};
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn other_users_need_permissions() {
+        let me = nix::unistd::getuid().as_raw();
+        // nobody, unless the tests run as nobody
+        let other = if me == 65534 { 65533 } else { 65534 };
+        let dir = tempfile::tempdir().unwrap();
+        let shell_nix = dir.path().join("shell.nix");
+        std::fs::write(&shell_nix, "{}").unwrap();
+        let chmod = |path: &std::path::Path, mode| {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).unwrap()
+        };
+
+        chmod(dir.path(), 0o755);
+        chmod(&shell_nix, 0o644);
+        assert!(can_read(me, &shell_nix));
+        assert!(can_read(other, &shell_nix));
+        assert!(can_read(0, &shell_nix));
+
+        chmod(&shell_nix, 0o600);
+        assert!(can_read(me, &shell_nix));
+        assert!(!can_read(other, &shell_nix));
+
+        // a readable file in a directory others can’t enter
+        chmod(&shell_nix, 0o644);
+        chmod(dir.path(), 0o700);
+        assert!(!can_read(other, &shell_nix));
+
+        assert!(!can_read(other, &dir.path().join("missing.nix")));
+
+        // a symlink to a readable file in a directory others can’t enter
+        chmod(dir.path(), 0o755);
+        let private = dir.path().join("private");
+        std::fs::create_dir(&private).unwrap();
+        std::fs::rename(&shell_nix, private.join("shell.nix")).unwrap();
+        chmod(&private, 0o700);
+        std::os::unix::fs::symlink(private.join("shell.nix"), &shell_nix).unwrap();
+        let resolved = resolve(NixFile::from(AbsPathBuf::new(shell_nix.clone()).unwrap()));
+        let resolved = resolved.as_absolute_path();
+        assert_eq!(resolved, private.join("shell.nix"));
+        assert!(can_read(me, resolved));
+        assert!(!can_read(other, resolved));
+        chmod(&private, 0o755);
+        assert!(can_read(other, resolved));
+        // paths are checked as they are, without following symlinks
+        assert!(!can_read(other, &shell_nix));
+    }
+}
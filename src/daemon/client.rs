@@ -1,12 +1,15 @@
 //! Create clients for the daemon.
 use crate::constants::Paths;
 use crate::socket::communicate;
-use crate::socket::communicate::client::InitError;
-use crate::socket::communicate::{client::Client, Handler};
-use crate::socket::path::SocketPath;
+pub use crate::socket::communicate::client::{Client, InitError, PingClient};
+use crate::socket::communicate::Handler;
+use crate::socket::path::DaemonAddress;
 use slog::debug;
 
-pub use crate::socket::communicate::{DaemonInfo, Ping, Rebuild, StreamEvents};
+pub use crate::socket::communicate::{
+    capability, AcknowledgedPing, DaemonInfo, EventFilter, FilteredStreamEvents, Ping, PingAck,
+    PingResponse, Rebuild, StampedEvent, StreamEvents,
+};
 pub use crate::socket::read_writer::Timeout;
 
 /// Create a connected client or exit.
@@ -18,10 +21,21 @@ pub fn create<H>(
 where
     H: Handler,
 {
-    let address = paths.daemon_socket_file().clone();
-    debug!(logger, "connecting to socket"; "socket" => address.as_path().display());
+    let address = paths.daemon_address();
+    debug!(logger, "connecting to daemon"; "address" => %address);
 
-    let client = communicate::client::new::<H>(timeout).connect(&SocketPath::from(address))?;
+    let client = communicate::client::new::<H>(timeout).connect(address)?;
 
     Ok(client)
 }
+
+/// Create a connected client for pinging the daemon, see `communicate::client::connect_ping`.
+pub fn create_ping(
+    address: &DaemonAddress,
+    timeout: Timeout,
+    logger: &slog::Logger,
+) -> Result<PingClient, InitError> {
+    debug!(logger, "connecting to daemon"; "address" => %address);
+
+    communicate::client::connect_ping(timeout, address)
+}
@@ -1,14 +1,19 @@
 //! Serve the lorri daemon on a unix socket.
-use crate::daemon::{IndicateActivity, LoopHandlerEvent};
+use crate::build_loop::Event;
+use crate::daemon::{access, IndicateActivity, LoopHandlerEvent};
 use crate::run_async::Async;
-use crate::socket::communicate::listener::{Connection, Listener};
-use crate::socket::communicate::{self};
-use crate::socket::communicate::{CommunicationType, Ping, StreamEvents};
+use crate::socket::communicate::listener::{AcceptError, Connection, Listener};
+use crate::socket::communicate::{self, capability};
+use crate::socket::communicate::{
+    AcknowledgedPing, CommunicationType, DaemonStatus, EventFilter, FilteredStreamEvents, Ping,
+    PingAck, PingResponse, StampedEvent, StreamEvents,
+};
 use crate::socket::path::{BindError, SocketPath};
-use crate::Never;
+use crate::socket::read_writer::Encoding;
+use crate::{Never, NixFile};
 use communicate::DaemonInfo;
 use crossbeam_channel as chan;
-use slog::{debug, info};
+use slog::{debug, info, warn};
 use std::collections::HashMap;
 use std::thread;
 
@@ -16,6 +21,8 @@ use std::thread;
 pub struct Server {
     tx_activity: chan::Sender<IndicateActivity>,
     tx_build: chan::Sender<LoopHandlerEvent>,
+    /// Whether every user may connect to the socket
+    multi_user: bool,
 }
 
 impl Server {
@@ -23,10 +30,12 @@ impl Server {
     pub fn new(
         tx_activity: chan::Sender<IndicateActivity>,
         tx_build: chan::Sender<LoopHandlerEvent>,
+        multi_user: bool,
     ) -> Self {
         Server {
             tx_activity,
             tx_build,
+            multi_user,
         }
     }
 
@@ -37,6 +46,13 @@ impl Server {
         logger: &slog::Logger,
     ) -> Result<Never, BindError> {
         let listener = Listener::new(socket_path)?;
+        if self.multi_user {
+            // connecting needs write permission on the socket
+            std::fs::set_permissions(
+                socket_path.as_absolute_path(),
+                std::os::unix::fs::PermissionsExt::from_mode(0o666),
+            )?;
+        }
 
         // We have to continuously be joining threads,
         // otherwise they turn into zombies and we eventually run out of processes on linux.
@@ -53,6 +69,15 @@ impl Server {
                 Ok(connection) => {
                     self.handle_client(connection, tx_new_thread.clone(), tx_done_thread, logger)
                 }
+                Err(AcceptError::LegacyClient) => {
+                    warn!(
+                        logger,
+                        "A client of an older lorri version connected, please upgrade it"
+                    );
+                }
+                Err(AcceptError::VersionMismatch(client)) => {
+                    warn!(logger, "A client speaking another protocol version connected"; "client_version" => &client.lorri_version, "client_protocol_version" => client.protocol_version);
+                }
                 Err(accept_err) => {
                     info!(logger, "Failed accepting a client connection"; "accept_error" => format!("{:?}", accept_err));
                     // If we hit an error like `too many open file descriptors`, avoid retrying
@@ -73,6 +98,8 @@ impl Server {
         let Connection {
             handlers,
             communication_type,
+            client,
+            peer_uid,
         } = conn;
 
         // We can’t display thread ids, so let’s generate a short random string to identify a thread
@@ -84,10 +111,21 @@ impl Server {
         let tx_activity = self.tx_activity.clone();
         let tx_build = self.tx_build.clone();
         let logger = logger.clone();
+        // in a multi-user daemon, clients only see the projects built for their own user
+        let owner = if self.multi_user { peer_uid } else { None };
+        // and it builds the file it checked the permissions of
+        let multi_user = self.multi_user;
+        let resolve = move |nix_file| {
+            if multi_user {
+                access::resolve(nix_file)
+            } else {
+                nix_file
+            }
+        };
 
         let new_thread = std::thread::spawn(move || {
             let id = thread::current().id();
-            debug!(&logger, "New client connection accepted"; "message_type" => format!("{:?}", communication_type), "thread_id" => &display_id);
+            debug!(&logger, "New client connection accepted"; "message_type" => format!("{:?}", communication_type), "client_version" => &client.lorri_version, "uid" => peer_uid, "thread_id" => &display_id);
 
             let err = |ct, e| debug!(logger, "Unable to communicate with client"; "communication_type" => format!("{:?}", ct), "error" => format!("{:?}", e));
 
@@ -102,43 +140,186 @@ impl Server {
                             .read(communicate::DEFAULT_READ_TIMEOUT)
                         {
                             Ok(DaemonInfo {}) => {
+                                let (tx_status, rx_status) = chan::bounded(1);
+                                tx_build
+                                    .send(LoopHandlerEvent::StatusRequest {
+                                        tx: tx_status,
+                                        uid: owner,
+                                    })
+                                    .expect("Unable to send a status request to the build_loop");
+                                let status = rx_status
+                                    .recv_timeout(std::time::Duration::from_millis(100))
+                                    .unwrap_or_else(|_| DaemonStatus::default());
                                 let mut rw = handlers.daemon_info();
-                                // TODO: the server could return its info here
-                                match rw.write(communicate::DEFAULT_READ_TIMEOUT, &()) {
+                                match rw.write_wire(communicate::DEFAULT_READ_TIMEOUT, &status) {
                                     Ok(()) => {}
                                     Err(err) => {
                                         debug!(logger, "client vanished, closing socket"; "communication_type" => format!("{:?}", communication_type), "error" => format!("{:?}", err));
                                     }
                                 }
                             }
-                            Err(_) => todo!(),
+                            Err(e) => err(communication_type, e),
                         }
                     }
                     CommunicationType::Ping => {
                         match handlers.ping().read(communicate::DEFAULT_READ_TIMEOUT) {
-                            Ok(Ping { nix_file, rebuild }) => tx_activity
-                                .send(IndicateActivity { nix_file, rebuild })
+                            Ok(Ping {
+                                nix_file,
+                                rebuild,
+                                env,
+                            }) => tx_activity
+                                .send(IndicateActivity {
+                                    nix_file: resolve(nix_file),
+                                    rebuild,
+                                    env,
+                                    ack: None,
+                                    done: None,
+                                    uid: peer_uid,
+                                })
                                 .expect("Unable to send a ping from listener"),
                             Err(e) => err(communication_type, e),
                         }
                     }
+                    CommunicationType::AcknowledgedPing => {
+                        let mut rw = handlers.acknowledged_ping();
+                        match rw.read(communicate::DEFAULT_READ_TIMEOUT) {
+                            Ok(AcknowledgedPing {
+                                ping:
+                                    Ping {
+                                        nix_file,
+                                        rebuild,
+                                        env,
+                                    },
+                                wait,
+                            }) => {
+                                let nix_file = resolve(nix_file);
+                                // listen before pinging, so that we don’t miss the build
+                                let (tx_done, rx_done) = if wait {
+                                    let (tx, rx) = chan::bounded(1);
+                                    (Some(tx), rx)
+                                } else {
+                                    (None, chan::never())
+                                };
+                                let rx_event = if wait {
+                                    let (tx_event, rx_event) = chan::unbounded();
+                                    let filter = EventFilter {
+                                        nix_files: vec![nix_file.clone()],
+                                        ..EventFilter::default()
+                                    };
+                                    tx_build
+                                        .send(LoopHandlerEvent::NewListener {
+                                            tx: tx_event,
+                                            filter,
+                                            uid: owner,
+                                        })
+                                        .expect("Unable to send a new listener to the build_loop");
+                                    Some(rx_event)
+                                } else {
+                                    None
+                                };
+                                let (tx_ack, rx_ack) = chan::bounded(1);
+                                tx_activity
+                                    .send(IndicateActivity {
+                                        nix_file: nix_file.clone(),
+                                        rebuild,
+                                        env,
+                                        ack: Some(tx_ack),
+                                        done: tx_done,
+                                        uid: peer_uid,
+                                    })
+                                    .expect("Unable to send a ping from listener");
+                                let ack = match rx_ack.recv() {
+                                    Ok(ack) => ack,
+                                    Err(chan::RecvError) => {
+                                        // the daemon is shutting down
+                                        let res = rw.write_wire(
+                                            communicate::DEFAULT_READ_TIMEOUT,
+                                            &PingResponse::Error(
+                                                "the daemon did not acknowledge the ping"
+                                                    .to_string(),
+                                            ),
+                                        );
+                                        if let Err(err) = res {
+                                            debug!(logger, "client vanished, closing socket"; "communication_type" => format!("{:?}", communication_type), "error" => format!("{:?}", err));
+                                        }
+                                        return;
+                                    }
+                                };
+                                let res = rw
+                                    .write_wire(
+                                        communicate::DEFAULT_READ_TIMEOUT,
+                                        &PingResponse::Ack(ack),
+                                    )
+                                    .and_then(|()| {
+                                        let rx = match rx_event {
+                                            Some(rx) => rx,
+                                            None => return Ok(()),
+                                        };
+                                        // stop waiting if the client is gone
+                                        let hang_up = handlers.hang_up();
+                                        let never = chan::never();
+                                        let hung_up = hang_up.as_ref().map_or(&never, |h| h.chan());
+                                        match wait_for_build(
+                                            &rx, &rx_done, hung_up, &nix_file, owner, ack,
+                                        ) {
+                                            Some(event) => rw.write_wire(
+                                                communicate::DEFAULT_READ_TIMEOUT,
+                                                &PingResponse::Finished(event),
+                                            ),
+                                            None => Ok(()),
+                                        }
+                                    });
+                                if let Err(err) = res {
+                                    debug!(logger, "client vanished, closing socket"; "communication_type" => format!("{:?}", communication_type), "error" => format!("{:?}", err));
+                                }
+                            }
+                            Err(e) => err(communication_type, e),
+                        }
+                    }
                     CommunicationType::StreamEvents => {
                         let mut rw = handlers.stream_events();
                         match rw.read(communicate::DEFAULT_READ_TIMEOUT) {
                             Ok(StreamEvents {}) => {
-                                let (tx_event, rx_event) = chan::unbounded();
-                                tx_build
-                                    .send(LoopHandlerEvent::NewListener(tx_event))
-                                    .expect("Unable to send a new listener to the build_loop");
-                                for event in rx_event {
-                                    match rw.write(communicate::DEFAULT_READ_TIMEOUT, &event) {
-                                        Ok(()) => {}
-                                        Err(err) => {
-                                            debug!(logger, "client vanished, closing socket"; "communication_type" => format!("{:?}", communication_type), "error" => format!("{:?}", err));
-                                            // break out of the loop or the handler is not stopped
-                                            break;
-                                        }
+                                // older clients only know the bare events
+                                let res = stream_events(
+                                    &tx_build,
+                                    EventFilter::default(),
+                                    owner,
+                                    |stamped| {
+                                        rw.write_wire(
+                                            communicate::DEFAULT_READ_TIMEOUT,
+                                            &stamped.event,
+                                        )
+                                    },
+                                );
+                                if let Err(err) = res {
+                                    debug!(logger, "client vanished, closing socket"; "communication_type" => format!("{:?}", communication_type), "error" => format!("{:?}", err));
+                                }
+                            }
+                            Err(e) => err(communication_type, e),
+                        }
+                    }
+                    CommunicationType::FilteredStreamEvents => {
+                        let mut rw = handlers.filtered_stream_events();
+                        match rw.read(communicate::DEFAULT_READ_TIMEOUT) {
+                            Ok(FilteredStreamEvents { filter }) => {
+                                // older clients expect bare events,
+                                // the JSON wire events always carried their timestamp
+                                let stamped = client.has_capability(capability::STAMPED_EVENTS)
+                                    || handlers.encoding() == Encoding::Json;
+                                let mut unstamped = handlers.filtered_stream_events_unstamped();
+                                let res = stream_events(&tx_build, filter, owner, |event| {
+                                    if stamped {
+                                        rw.write_wire(communicate::DEFAULT_READ_TIMEOUT, &event)
+                                    } else {
+                                        unstamped.write_wire(
+                                            communicate::DEFAULT_READ_TIMEOUT,
+                                            &event.event,
+                                        )
                                     }
+                                });
+                                if let Err(err) = res {
+                                    debug!(logger, "client vanished, closing socket"; "communication_type" => format!("{:?}", communication_type), "error" => format!("{:?}", err));
                                 }
                             }
                             Err(e) => err(communication_type, e),
@@ -166,6 +347,103 @@ impl Server {
     }
 }
 
+/// Wait for the result of the build of `nix_file` for `uid` that the ping triggered,
+/// which the build loop sends to `rx_done`, given the events of a new listener.
+/// If the ping did not trigger a rebuild, that is the running or last build.
+/// `None` if the daemon is shutting down or ignored the ping,
+/// or if `hung_up` disconnects because the client is gone.
+fn wait_for_build(
+    rx_event: &chan::Receiver<StampedEvent>,
+    rx_done: &chan::Receiver<Event>,
+    hung_up: &chan::Receiver<()>,
+    nix_file: &NixFile,
+    uid: Option<u32>,
+    ack: PingAck,
+) -> Option<Event> {
+    let is_project =
+        |stamped: &StampedEvent| stamped.uid == uid && stamped.event.nix_file() == Some(nix_file);
+    let mut events = std::iter::from_fn(|| {
+        chan::select! {
+            recv(rx_event) -> stamped => stamped.ok(),
+            recv(hung_up) -> _ => None,
+        }
+    });
+    match ack {
+        PingAck::PermissionDenied => None,
+        PingAck::AlreadyWatching => {
+            // the snapshot contains the last event of every project
+            let last = events
+                .by_ref()
+                .take_while(|stamped| !matches!(stamped.event, Event::SectionEnd))
+                .filter(is_project)
+                .map(|stamped| stamped.event)
+                .last();
+            let mut started = match last {
+                Some(last @ Event::Completed { .. })
+                | Some(last @ Event::Failure { .. })
+                | Some(last @ Event::Crashed { .. }) => return Some(last),
+                Some(_) => true,
+                None => false,
+            };
+            events
+                .filter(is_project)
+                .map(|stamped| stamped.event)
+                .find(|event| match event {
+                    Event::Started { .. } => {
+                        started = true;
+                        false
+                    }
+                    Event::Completed { .. } | Event::Failure { .. } => started,
+                    Event::Crashed { .. } => true,
+                    Event::Retrying { .. } | Event::SectionEnd => false,
+                })
+        }
+        // Events can’t tell whether a build started before or after the ping,
+        // so the build loop reports the result of the first build after it.
+        PingAck::Accepted | PingAck::RebuildScheduled => {
+            let mut rx_done = rx_done.clone();
+            loop {
+                chan::select! {
+                    recv(rx_done) -> event => match event {
+                        Ok(event) => return Some(event),
+                        // the build loop is gone, its `Crashed` event follows
+                        Err(chan::RecvError) => rx_done = chan::never(),
+                    },
+                    recv(rx_event) -> stamped => match stamped {
+                        Ok(stamped) if is_project(&stamped) => {
+                            if let Event::Crashed { .. } = stamped.event {
+                                return Some(stamped.event);
+                            }
+                        }
+                        Ok(_) => {}
+                        Err(chan::RecvError) => return None,
+                    },
+                    recv(hung_up) -> _ => return None,
+                }
+            }
+        }
+    }
+}
+
+/// Register a listener for the events of `uid`’s projects that match `filter`,
+/// and `send` them to the client until it fails.
+fn stream_events<E>(
+    tx_build: &chan::Sender<LoopHandlerEvent>,
+    filter: EventFilter,
+    uid: Option<u32>,
+    mut send: impl FnMut(StampedEvent) -> Result<(), E>,
+) -> Result<(), E> {
+    let (tx_event, rx_event) = chan::unbounded();
+    tx_build
+        .send(LoopHandlerEvent::NewListener {
+            tx: tx_event,
+            filter,
+            uid,
+        })
+        .expect("Unable to send a new listener to the build_loop");
+    rx_event.into_iter().try_for_each(&mut send)
+}
+
 /// Join threads continuously, so that we don’t generate too many zombies.
 /// Every new thread it signalled by `rx_new`, while every thread that is finishe
 /// sends its id to `rx_done`.
@@ -204,3 +482,471 @@ fn join_continuously(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{wait_for_build, Server};
+    use crate::build_loop::Event;
+    use crate::build_loop::ReasonI;
+    use crate::builder::{BuildError, OutputPath};
+    use crate::daemon::{IndicateActivity, LoopHandlerEvent};
+    use crate::project::RootPath;
+    use crate::socket::communicate::listener::HandshakeResponse;
+    use crate::socket::communicate::{
+        self, CommunicationType, DaemonStatus, EventType, FilteredStreamEvents, HandshakeRequest,
+        NoMessage, PingAck, ProjectStatus, Rebuild, StampedEvent, Version,
+    };
+    use crate::socket::path::SocketPath;
+    use crate::socket::read_writer::{ReadWriter, Timeout};
+    use crate::watch::{WatchLimitExceeded, WatchStatus};
+    use crate::{AbsPathBuf, NixFile};
+    use crossbeam_channel as chan;
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixStream;
+    use std::time::Duration;
+
+    /// Connect with the JSON encoding and return the handshake response.
+    fn connect_json(socket_path: &SocketPath, communication_type: &str) -> BufReader<UnixStream> {
+        let mut socket = socket_path.connect().unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        writeln!(
+            socket,
+            r#"{{"client": {{"protocol_version": {}}}, "communication_type": "{}"}}"#,
+            communicate::PROTOCOL_VERSION,
+            communication_type
+        )
+        .unwrap();
+        let mut reader = BufReader::new(socket);
+        let response = read_json(&mut reader);
+        assert_eq!(
+            response["Ok"]["daemon"]["protocol_version"],
+            communicate::PROTOCOL_VERSION
+        );
+        reader
+    }
+
+    fn read_json(reader: &mut BufReader<UnixStream>) -> serde_json::Value {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    /// A running `Server` and the receiving ends of its channels.
+    struct TestServer {
+        // the socket is removed with the directory
+        #[allow(dead_code)]
+        dir: tempfile::TempDir,
+        socket_path: SocketPath,
+        rx_activity: chan::Receiver<IndicateActivity>,
+        rx_build: chan::Receiver<LoopHandlerEvent>,
+    }
+
+    fn start_server() -> TestServer {
+        let logger = crate::logging::test_logger("server");
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path =
+            SocketPath::from(AbsPathBuf::new(dir.path().join("daemon.socket")).unwrap());
+        let (tx_activity, rx_activity) = chan::unbounded();
+        let (tx_build, rx_build) = chan::unbounded();
+        let server = Server::new(tx_activity, tx_build, false);
+        let listen_path = socket_path.clone();
+        std::thread::spawn(move || server.listen(&listen_path, &logger));
+        // wait for the server to bind the socket
+        for _ in 0..100 {
+            if socket_path.connect().is_ok() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        TestServer {
+            dir,
+            socket_path,
+            rx_activity,
+            rx_build,
+        }
+    }
+
+    #[test]
+    fn json_clients() {
+        let TestServer {
+            dir: _dir,
+            socket_path,
+            rx_activity,
+            rx_build,
+        } = start_server();
+
+        // Ping
+        let mut ping = connect_json(&socket_path, "Ping");
+        writeln!(
+            ping.get_mut(),
+            r#"{{"nix_file": "/project/shell.nix", "rebuild": "always", "env": {{"NIX_PATH": "nixpkgs=/nixpkgs"}}}}"#
+        )
+        .unwrap();
+        let activity = rx_activity.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(
+            activity.nix_file.as_absolute_path(),
+            std::path::Path::new("/project/shell.nix")
+        );
+        assert!(matches!(activity.rebuild, Rebuild::Always));
+        assert_eq!(
+            activity.env.unwrap().get("NIX_PATH"),
+            Some("nixpkgs=/nixpkgs")
+        );
+
+        // AcknowledgedPing
+        let mut ping = connect_json(&socket_path, "AcknowledgedPing");
+        writeln!(
+            ping.get_mut(),
+            r#"{{"ping": {{"nix_file": "/project/shell.nix", "rebuild": "only_if_not_yet_watching", "env": null}}, "wait": false}}"#
+        )
+        .unwrap();
+        let activity = rx_activity.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(matches!(activity.rebuild, Rebuild::OnlyIfNotYetWatching));
+        activity
+            .ack
+            .unwrap()
+            .send(PingAck::AlreadyWatching)
+            .unwrap();
+        assert_eq!(
+            read_json(&mut ping),
+            serde_json::json!({"type": "ack", "ack": "already_watching"})
+        );
+
+        // DaemonInfo
+        let mut info = connect_json(&socket_path, "DaemonInfo");
+        writeln!(info.get_mut(), "{{}}").unwrap();
+        match rx_build.recv_timeout(Duration::from_secs(5)).unwrap() {
+            LoopHandlerEvent::StatusRequest { tx, .. } => tx
+                .send(DaemonStatus {
+                    projects: vec![ProjectStatus {
+                        nix_file: NixFile::from(
+                            AbsPathBuf::new("/project/shell.nix".into()).unwrap(),
+                        ),
+                        watch: WatchStatus {
+                            watched: 42,
+                            polled: 2,
+                            limit_exceeded: Some(WatchLimitExceeded {
+                                limit: Some(8192),
+                                watched: 8000,
+                            }),
+                            unreadable: vec!["/project/secret.nix".into()],
+                        },
+                        crashed: None,
+                    }],
+                })
+                .unwrap(),
+            _ => panic!("expected a status request"),
+        }
+        assert_eq!(
+            read_json(&mut info),
+            serde_json::json!({"projects": [{
+                "nix_file": "/project/shell.nix",
+                "watch": {
+                    "watched": 42,
+                    "polled": 2,
+                    "limit_exceeded": {"limit": 8192, "watched": 8000},
+                    "unreadable": ["/project/secret.nix"]
+                },
+                "crashed": null
+            }]})
+        );
+
+        // StreamEvents
+        let mut events = connect_json(&socket_path, "StreamEvents");
+        writeln!(events.get_mut(), "{{}}").unwrap();
+        match rx_build.recv_timeout(Duration::from_secs(5)).unwrap() {
+            LoopHandlerEvent::NewListener { tx, .. } => {
+                tx.send(StampedEvent::now(Event::SectionEnd, None)).unwrap()
+            }
+            _ => panic!("expected a new listener"),
+        }
+        let event = read_json(&mut events);
+        assert_eq!(event["format_version"], 1);
+        assert_eq!(event["type"], "snapshot_end");
+
+        // FilteredStreamEvents
+        let mut events = connect_json(&socket_path, "FilteredStreamEvents");
+        writeln!(
+            events.get_mut(),
+            r#"{{"filter": {{"types": ["crashed"]}}}}"#
+        )
+        .unwrap();
+        match rx_build.recv_timeout(Duration::from_secs(5)).unwrap() {
+            LoopHandlerEvent::NewListener { tx, filter, .. } => {
+                assert_eq!(filter.types, vec![EventType::Crashed]);
+                tx.send(StampedEvent {
+                    timestamp: 1234,
+                    uid: None,
+                    event: Event::SectionEnd,
+                })
+                .unwrap()
+            }
+            _ => panic!("expected a new listener"),
+        }
+        assert_eq!(
+            read_json(&mut events),
+            serde_json::json!({"format_version": 1, "timestamp": 1234, "uid": null, "type": "snapshot_end"})
+        );
+    }
+
+    #[test]
+    fn older_clients_get_unstamped_filtered_events() {
+        let TestServer {
+            dir: _dir,
+            socket_path,
+            rx_build,
+            ..
+        } = start_server();
+        // a client from before `capability::STAMPED_EVENTS`
+        let socket = socket_path.connect().unwrap();
+        ReadWriter::<NoMessage, u32>::new(&socket)
+            .write(
+                communicate::DEFAULT_READ_TIMEOUT,
+                &communicate::HANDSHAKE_MAGIC,
+            )
+            .unwrap();
+        let mut client = Version::current();
+        client
+            .capabilities
+            .retain(|c| c != communicate::capability::STAMPED_EVENTS);
+        let response: HandshakeResponse = ReadWriter::new(&socket)
+            .communicate(
+                communicate::DEFAULT_READ_TIMEOUT,
+                &HandshakeRequest {
+                    client,
+                    communication_type: CommunicationType::FilteredStreamEvents,
+                },
+            )
+            .unwrap();
+        assert!(response.is_ok());
+        let mut rw: ReadWriter<Event, FilteredStreamEvents> = ReadWriter::new(&socket);
+        rw.write(
+            communicate::DEFAULT_READ_TIMEOUT,
+            &FilteredStreamEvents::default(),
+        )
+        .unwrap();
+        match rx_build.recv_timeout(Duration::from_secs(5)).unwrap() {
+            LoopHandlerEvent::NewListener { tx, .. } => tx
+                .send(StampedEvent::now(Event::SectionEnd, Some(1000)))
+                .unwrap(),
+            _ => panic!("expected a new listener"),
+        }
+        assert!(matches!(
+            rw.read(Timeout::from_millis(5000)),
+            Ok(Event::SectionEnd)
+        ));
+    }
+
+    #[test]
+    fn unacknowledged_pings_get_an_error() {
+        let TestServer {
+            dir: _dir,
+            socket_path,
+            rx_activity,
+            rx_build: _rx_build,
+        } = start_server();
+        let mut ping = connect_json(&socket_path, "AcknowledgedPing");
+        writeln!(
+            ping.get_mut(),
+            r#"{{"ping": {{"nix_file": "/project/shell.nix", "rebuild": "always", "env": null}}, "wait": true}}"#
+        )
+        .unwrap();
+        // the daemon drops the ping without acknowledging it
+        drop(rx_activity.recv_timeout(Duration::from_secs(5)).unwrap());
+        let response = read_json(&mut ping);
+        assert_eq!(response["type"], "error");
+        // and closes the connection
+        let mut line = String::new();
+        assert_eq!(ping.read_line(&mut line).unwrap(), 0);
+    }
+
+    #[test]
+    fn stop_waiting_for_the_build_when_the_client_hangs_up() {
+        let TestServer {
+            dir: _dir,
+            socket_path,
+            rx_activity,
+            rx_build,
+        } = start_server();
+        let mut ping = connect_json(&socket_path, "AcknowledgedPing");
+        writeln!(
+            ping.get_mut(),
+            r#"{{"ping": {{"nix_file": "/project/shell.nix", "rebuild": "always", "env": null}}, "wait": true}}"#
+        )
+        .unwrap();
+        let tx_event = match rx_build.recv_timeout(Duration::from_secs(5)).unwrap() {
+            LoopHandlerEvent::NewListener { tx, .. } => tx,
+            _ => panic!("expected a new listener"),
+        };
+        let activity = rx_activity.recv_timeout(Duration::from_secs(5)).unwrap();
+        activity.ack.unwrap().send(PingAck::Accepted).unwrap();
+        assert_eq!(read_json(&mut ping)["ack"], "accepted");
+        drop(ping);
+        // the server stops listening for events
+        for _ in 0..100 {
+            if tx_event
+                .send(StampedEvent::now(Event::SectionEnd, None))
+                .is_err()
+            {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        panic!("the server still waits for the build");
+    }
+
+    #[test]
+    fn wait_for_the_build_after_the_ping() {
+        let nix_file = |name: &str| {
+            NixFile::from(AbsPathBuf::new(std::path::PathBuf::from("/").join(name)).unwrap())
+        };
+        let started = |name| Event::Started {
+            nix_file: nix_file(name),
+            reason: ReasonI::PingReceived,
+        };
+        let completed = |name, root: &str| Event::Completed {
+            nix_file: nix_file(name),
+            rooted_output_paths: OutputPath {
+                shell_gc_root: RootPath(AbsPathBuf::new(root.into()).unwrap()),
+            },
+        };
+        let failure = |name| Event::Failure {
+            nix_file: nix_file(name),
+            failure: BuildError::io("oops"),
+        };
+        let events = |events: Vec<Event>| {
+            let (tx, rx) = chan::unbounded();
+            for event in events {
+                tx.send(StampedEvent::now(event, None)).unwrap();
+            }
+            rx
+        };
+        let root = |event: Option<Event>| match event {
+            Some(Event::Completed {
+                rooted_output_paths,
+                ..
+            }) => rooted_output_paths.shell_gc_root.display().to_string(),
+            other => panic!("expected a completed build, got {:?}", other),
+        };
+
+        let done = |event: Option<Event>| {
+            let (tx, rx) = chan::unbounded();
+            if let Some(event) = event {
+                tx.send(event).unwrap();
+            }
+            (tx, rx)
+        };
+        let crashed = |name| Event::Crashed {
+            nix_file: nix_file(name),
+            reason: "panic".to_string(),
+            restart_in_secs: 1,
+        };
+
+        // the build that was running during the ping is not ours, the build loop reports ours
+        let rx = events(vec![
+            completed("a.nix", "/old"),
+            Event::SectionEnd,
+            started("a.nix"),
+            completed("a.nix", "/running"),
+        ]);
+        let (_tx_done, rx_done) = done(Some(completed("a.nix", "/new")));
+        assert_eq!(
+            root(wait_for_build(
+                &rx,
+                &rx_done,
+                &chan::never(),
+                &nix_file("a.nix"),
+                None,
+                PingAck::RebuildScheduled
+            )),
+            "/new"
+        );
+
+        // the build loop crashed before it built
+        let rx = events(vec![Event::SectionEnd, crashed("b.nix"), crashed("a.nix")]);
+        let (tx_done, rx_done) = done(None);
+        drop(tx_done);
+        assert!(matches!(
+            wait_for_build(
+                &rx,
+                &rx_done,
+                &chan::never(),
+                &nix_file("a.nix"),
+                None,
+                PingAck::Accepted
+            ),
+            Some(Event::Crashed { nix_file: crashed, .. }) if crashed == nix_file("a.nix")
+        ));
+
+        // without a rebuild, the last result counts
+        let rx = events(vec![failure("a.nix"), Event::SectionEnd]);
+        assert!(matches!(
+            wait_for_build(
+                &rx,
+                &chan::never(),
+                &chan::never(),
+                &nix_file("a.nix"),
+                None,
+                PingAck::AlreadyWatching
+            ),
+            Some(Event::Failure { .. })
+        ));
+        // … or the running build
+        let rx = events(vec![
+            started("a.nix"),
+            Event::SectionEnd,
+            completed("a.nix", "/running"),
+        ]);
+        assert_eq!(
+            root(wait_for_build(
+                &rx,
+                &chan::never(),
+                &chan::never(),
+                &nix_file("a.nix"),
+                None,
+                PingAck::AlreadyWatching
+            )),
+            "/running"
+        );
+
+        // the daemon went away
+        let rx = events(vec![Event::SectionEnd, started("a.nix")]);
+        let (_tx_done, rx_done) = done(None);
+        assert!(wait_for_build(
+            &rx,
+            &rx_done,
+            &chan::never(),
+            &nix_file("a.nix"),
+            None,
+            PingAck::Accepted
+        )
+        .is_none());
+
+        // the builds of the same nix file for other users don’t count
+        let (alice, bob) = (Some(1000), Some(1001));
+        let (tx, rx) = chan::unbounded();
+        for (uid, event) in [
+            (bob, completed("a.nix", "/bob/old")),
+            (None, Event::SectionEnd),
+            (bob, started("a.nix")),
+            (bob, completed("a.nix", "/bob")),
+            (alice, started("a.nix")),
+            (alice, completed("a.nix", "/alice")),
+        ] {
+            tx.send(StampedEvent::now(event, uid)).unwrap();
+        }
+        assert_eq!(
+            root(wait_for_build(
+                &rx,
+                &chan::never(),
+                &chan::never(),
+                &nix_file("a.nix"),
+                alice,
+                PingAck::AlreadyWatching
+            )),
+            "/alice"
+        );
+    }
+}
@@ -0,0 +1,271 @@
+//! Forward connections on a loopback TCP port to the daemon socket,
+//! for clients that can’t reach the socket, e.g. in containers.
+//!
+//! Every user of the machine can connect to the port, so a client first has to send
+//! the daemon’s `Token` on a line of its own. After that, the connection is handled
+//! like every other client of the socket, so it speaks the same protocol
+//! (see `contrib/socket-protocol.md`).
+//! lorri clients connect to the port with `connect`.
+
+use crate::socket::path::SocketPath;
+use crate::{AbsPathBuf, Never};
+use slog::{debug, info};
+use std::fs::OpenOptions;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+/// How long a TCP client has to send the token.
+const TOKEN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The secret a TCP client sends before it is forwarded to the daemon socket.
+///
+/// The daemon writes it to a file only its user can read (`Paths::daemon_token_file`),
+/// so the port gives nobody more access than the socket’s permissions do.
+#[derive(Clone)]
+pub struct Token(String);
+
+impl Token {
+    /// Length of a token line, without the newline.
+    const LEN: usize = 64;
+
+    /// Generate a new token and write it to `path`, readable only by the current user.
+    pub fn create(path: &AbsPathBuf) -> io::Result<Token> {
+        let mut bytes = [0u8; Self::LEN / 2];
+        std::fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+        let token = Token(bytes.iter().map(|b| format!("{:02x}", b)).collect());
+        // a fresh file, so that an old one with laxer permissions can’t be reused
+        match std::fs::remove_file(path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)?;
+        writeln!(file, "{}", token.0)?;
+        Ok(token)
+    }
+
+    /// Read the token the daemon wrote to `path`.
+    pub fn read(path: &AbsPathBuf) -> io::Result<Token> {
+        Ok(Token(std::fs::read_to_string(path)?.trim_end().to_string()))
+    }
+
+    /// Compare without exiting early, so that the time it takes doesn’t tell
+    /// how much of a guess was right.
+    fn matches(&self, line: &[u8]) -> bool {
+        line.len() == self.0.len()
+            && line
+                .iter()
+                .zip(self.0.as_bytes())
+                .fold(0, |acc, (a, b)| acc | (a ^ b))
+                == 0
+    }
+}
+
+/// Parse the address to listen on: a port on localhost, or a loopback address and port.
+///
+/// Other addresses are refused, the daemon builds whatever its clients ask for.
+pub fn parse_loopback_address(s: &str) -> Result<SocketAddr, String> {
+    let addr = match s.parse::<u16>() {
+        Ok(port) => SocketAddr::from(([127, 0, 0, 1], port)),
+        Err(_) => s
+            .parse::<SocketAddr>()
+            .map_err(|_| format!("{} is neither a port nor an address like 127.0.0.1:4242", s))?,
+    };
+    if addr.ip().is_loopback() {
+        Ok(addr)
+    } else {
+        Err(format!(
+            "{} is not a loopback address, lorri only listens on 127.0.0.1 or ::1",
+            addr.ip()
+        ))
+    }
+}
+
+/// Accept TCP clients on `listener` and forward each of them to the daemon socket,
+/// if it sends the `token` first.
+/// Goes into an accept() loop, thus blocks.
+pub fn forward(
+    listener: TcpListener,
+    socket_path: &SocketPath,
+    token: &Token,
+    logger: &slog::Logger,
+) -> Never {
+    loop {
+        match listener.accept() {
+            Ok((tcp, peer)) => {
+                debug!(logger, "TCP client connected"; "peer" => %peer);
+                let socket_path = socket_path.clone();
+                let token = token.clone();
+                let logger = logger.clone();
+                std::thread::spawn(move || match authenticate(&tcp, &token) {
+                    Ok(true) => match socket_path.connect() {
+                        Ok(unix) => {
+                            if let Err(err) = relay(tcp, unix) {
+                                debug!(logger, "TCP client connection failed"; "peer" => %peer, "error" => %err);
+                            }
+                        }
+                        Err(err) => {
+                            info!(logger, "Could not forward a TCP client to the daemon socket"; "error" => %err)
+                        }
+                    },
+                    Ok(false) => {
+                        info!(logger, "Refused a TCP client without the daemon token"; "peer" => %peer)
+                    }
+                    Err(err) => {
+                        debug!(logger, "TCP client didn’t send a token"; "peer" => %peer, "error" => %err)
+                    }
+                });
+            }
+            Err(err) => {
+                info!(logger, "Failed accepting a TCP client connection"; "accept_error" => %err);
+                // don’t busy loop on errors like `too many open file descriptors`
+                std::thread::sleep(std::time::Duration::from_millis(100));
+            }
+        }
+    }
+}
+
+/// Read the first line the client sends and check that it is the `token`.
+fn authenticate(tcp: &TcpStream, token: &Token) -> io::Result<bool> {
+    tcp.set_read_timeout(Some(TOKEN_TIMEOUT))?;
+    let mut line = Vec::with_capacity(Token::LEN);
+    // byte by byte, everything after the line is for the daemon
+    for byte in Read::bytes(tcp).take(Token::LEN + 1) {
+        match byte? {
+            b'\n' => {
+                tcp.set_read_timeout(None)?;
+                return Ok(token.matches(&line));
+            }
+            b => line.push(b),
+        }
+    }
+    Ok(false)
+}
+
+/// Connect to a daemon’s TCP port (see `forward`) at `addr`, a `host:port`,
+/// and authenticate with `token`.
+///
+/// The socket protocol is spoken over Unix streams, so we relay the TCP connection
+/// to one end of a socket pair, and return the other end.
+pub fn connect(addr: &str, token: &Token) -> io::Result<UnixStream> {
+    let mut tcp = TcpStream::connect(addr)?;
+    writeln!(tcp, "{}", token.0)?;
+    let (client, relayed) = UnixStream::pair()?;
+    // the client notices errors as a closed connection
+    std::thread::spawn(move || relay(tcp, relayed));
+    Ok(client)
+}
+
+/// Copy bytes in both directions until both sides are done.
+fn relay(tcp: TcpStream, unix: UnixStream) -> io::Result<()> {
+    let (mut tcp_read, mut unix_write) = (tcp.try_clone()?, unix.try_clone()?);
+    let (mut unix_read, mut tcp_write) = (unix, tcp);
+    std::thread::scope(|s| {
+        let to_daemon = s.spawn(move || {
+            let res = io::copy(&mut tcp_read, &mut unix_write);
+            // tell the daemon the client is done sending
+            let _ = unix_write.shutdown(Shutdown::Write);
+            res
+        });
+        let res = io::copy(&mut unix_read, &mut tcp_write);
+        let _ = tcp_write.shutdown(Shutdown::Write);
+        to_daemon.join().expect("forwarding thread panicked")?;
+        res.map(|_| ())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{forward, parse_loopback_address, Token};
+    use crate::socket::path::SocketPath;
+    use crate::AbsPathBuf;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn only_loopback_addresses() {
+        assert_eq!(
+            parse_loopback_address("4242"),
+            Ok(SocketAddr::from(([127, 0, 0, 1], 4242)))
+        );
+        assert!(parse_loopback_address("[::1]:4242").is_ok());
+        assert!(parse_loopback_address("0.0.0.0:4242").is_err());
+        assert!(parse_loopback_address("192.168.1.1:4242").is_err());
+        assert!(parse_loopback_address("localhost").is_err());
+    }
+
+    /// A socket to forward to, and the port forwarding to it.
+    fn forwarded(dir: &std::path::Path, name: &str) -> (UnixListener, SocketAddr, Token) {
+        let path = dir.join("daemon.socket");
+        let unix = UnixListener::bind(&path).unwrap();
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let socket_path = SocketPath::from(AbsPathBuf::new(path).unwrap());
+        let token = Token::create(&AbsPathBuf::new(dir.join("daemon.token")).unwrap()).unwrap();
+        let logger = crate::logging::test_logger(name);
+        let forward_token = token.clone();
+        std::thread::spawn(move || forward(tcp, &socket_path, &forward_token, &logger));
+        (unix, addr, token)
+    }
+
+    #[test]
+    fn tcp_clients_are_forwarded_to_the_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let (unix, addr, token) = forwarded(dir.path(), "tcp_clients_are_forwarded_to_the_socket");
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        writeln!(client, "{}", token.0).unwrap();
+        let (mut daemon, _) = unix.accept().unwrap();
+        client.write_all(b"ping").unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut received = String::new();
+        daemon.read_to_string(&mut received).unwrap();
+        assert_eq!(received, "ping");
+        daemon.write_all(b"pong").unwrap();
+        drop(daemon);
+        let mut answer = String::new();
+        client.read_to_string(&mut answer).unwrap();
+        assert_eq!(answer, "pong");
+    }
+
+    #[test]
+    fn tcp_clients_need_the_token() {
+        let dir = tempfile::tempdir().unwrap();
+        let (unix, addr, token) = forwarded(dir.path(), "tcp_clients_need_the_token");
+        let token_file = dir.path().join("daemon.token");
+        assert_eq!(
+            std::fs::metadata(&token_file).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        assert_eq!(
+            Token::read(&AbsPathBuf::new(token_file).unwrap())
+                .unwrap()
+                .0,
+            token.0
+        );
+
+        let wrong = "0".repeat(Token::LEN);
+        for first_line in [wrong.as_str(), "", &token.0[1..]] {
+            let mut client = TcpStream::connect(addr).unwrap();
+            writeln!(client, "{}", first_line).unwrap();
+            let mut answer = String::new();
+            // the forwarder hangs up
+            client.read_to_string(&mut answer).unwrap();
+            assert_eq!(answer, "");
+        }
+        // and never connected to the daemon
+        unix.set_nonblocking(true).unwrap();
+        assert_eq!(
+            unix.accept().unwrap_err().kind(),
+            std::io::ErrorKind::WouldBlock
+        );
+    }
+}
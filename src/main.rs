@@ -1,12 +1,16 @@
-use lorri::cli::{Arguments, Command, Internal_, Verbosity};
+use lorri::cli::{Arguments, Command, EventSelection, Internal_, Verbosity};
 use lorri::logging;
 use lorri::ops;
 use lorri::ops::error::ExitError;
+use lorri::ops::stream_events::Output;
 use lorri::project::Project;
+use lorri::socket::communicate::EventFilter;
+use lorri::socket::path::DaemonAddress;
 use lorri::NixFile;
 use lorri::{constants, AbsPathBuf};
 use slog::{debug, error, o};
 use std::env;
+use std::io::IsTerminal;
 use std::path::{Path, PathBuf};
 use structopt::StructOpt;
 
@@ -86,19 +90,31 @@ fn find_nix_file(shellfile: &Path) -> Result<NixFile, ExitError> {
     }
 }
 
-fn create_project(paths: &constants::Paths, shell_nix: NixFile) -> Result<Project, ExitError> {
-    Project::new(shell_nix, paths.gc_root_dir(), paths.cas_store().clone()).map_err(|err| {
-        ExitError::temporary(anyhow::anyhow!(err).context("Could not set up project paths"))
-    })
+fn create_project(paths: &constants::Paths, shell_nix: NixFile) -> Project {
+    Project::new(shell_nix, paths.gc_root_dir(), paths.cas_store().clone())
 }
 
 /// Run the main function of the relevant command.
 fn run_command(logger: &slog::Logger, opts: Arguments) -> Result<(), ExitError> {
+    if let Some(socket) = &opts.daemon_socket {
+        let is_tcp = socket
+            .to_str()
+            .is_some_and(|s| s.starts_with(DaemonAddress::TCP_PREFIX));
+        let socket = if is_tcp {
+            socket.clone()
+        } else {
+            env::current_dir()
+                .map_err(ExitError::temporary)?
+                .join(socket)
+        };
+        // `Paths` reads the variable, and lorri processes we start use the same socket
+        env::set_var(constants::DAEMON_SOCKET_ENV, socket);
+    }
     let paths = lorri::ops::get_paths()?;
 
     let with_project_resolved =
         |nix_file| -> std::result::Result<(Project, slog::Logger), ExitError> {
-            let project = create_project(&lorri::ops::get_paths()?, nix_file)?;
+            let project = create_project(&lorri::ops::get_paths()?, nix_file);
             let logger = logger.new(o!("nix_file" => project.nix_file.clone()));
             Ok((project, logger))
         };
@@ -124,6 +140,7 @@ fn run_command(logger: &slog::Logger, opts: Arguments) -> Result<(), ExitError>
             ops::op_direnv(
                 project,
                 &paths,
+                opts.wait,
                 /* shell_output */ std::io::stdout(),
                 &logger,
             )
@@ -133,6 +150,21 @@ fn run_command(logger: &slog::Logger, opts: Arguments) -> Result<(), ExitError>
             ops::op_shell(project, opts, &logger)
         }
 
+        Command::Wait(opts) => {
+            let nix_file = find_nix_file(&opts.nix_file)?;
+            ops::op_wait(&paths, nix_file, opts.timeout, logger)
+        }
+        Command::Events(opts) => {
+            let filter = event_filter(&opts.select)?;
+            let output = if opts.json {
+                Output::Json(opts.format_version)
+            } else {
+                Output::Human {
+                    colour: env::var_os("NO_COLOR").is_none() && std::io::stdout().is_terminal(),
+                }
+            };
+            ops::op_stream_events(&paths, opts.select.kind, filter, output, logger)
+        }
         Command::Watch(opts) => {
             let (project, logger) = with_project(&opts.nix_file)?;
             ops::op_watch(project, opts, &logger)
@@ -147,17 +179,43 @@ fn run_command(logger: &slog::Logger, opts: Arguments) -> Result<(), ExitError>
         Command::Internal { command } => match command {
             Internal_::Ping_(opts) => {
                 let nix_file = find_nix_file(&opts.nix_file)?;
-                ops::op_ping(&paths, nix_file, logger)
+                ops::op_ping(&paths, nix_file, opts.wait, logger)
             }
             Internal_::StartUserShell_(opts) => {
                 let (project, _logger) = with_project(&opts.nix_file)?;
                 ops::op_start_user_shell(project, opts)
             }
-            Internal_::StreamEvents_(se) => ops::op_stream_events(&paths, se.kind, logger),
+            Internal_::StreamEvents_(se) => {
+                let filter = event_filter(&se.select)?;
+                let output = Output::Json(se.format_version);
+                ops::op_stream_events(&paths, se.select.kind, filter, output, logger)
+            }
         },
     }
 }
 
+/// The filter the daemon applies to the events it streams.
+fn event_filter(select: &EventSelection) -> Result<EventFilter, ExitError> {
+    Ok(EventFilter {
+        nix_files: select
+            .nix_files
+            .iter()
+            .map(|f| find_nix_file(f))
+            .collect::<Result<_, _>>()?,
+        under: match &select.under {
+            Some(dir) => Some(std::fs::canonicalize(dir).map_err(|err| {
+                ExitError::user_error(anyhow::anyhow!(
+                    "cannot use `--under {}`: {}",
+                    dir.display(),
+                    err
+                ))
+            })?),
+            None => None,
+        },
+        types: select.types.clone(),
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -36,11 +36,15 @@ use crossbeam_channel as chan;
 use slog::debug;
 use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
+use std::os::unix::process::CommandExt;
 use std::path::{Path, PathBuf};
-use std::process::{ChildStderr, ChildStdout, Command, ExitStatus, Stdio};
+use std::process::{Child, ChildStderr, ChildStdout, Command, ExitStatus, Stdio};
 use std::thread;
+use std::time::{Duration, Instant};
 use vec1::Vec1;
 
+/// The client environment nix executables run with.
+pub mod env;
 /// Construct and combine nix options to pass to nix executables.
 pub mod options;
 
@@ -51,6 +55,8 @@ pub struct CallOpts<'a> {
     attribute: Option<String>,
     argstrs: HashMap<OsString, OsString>,
     extra_options: options::NixOptions,
+    env: Option<env::ClientEnv>,
+    timeout: Option<Duration>,
 }
 
 /// Which input to give nix.
@@ -86,6 +92,41 @@ impl From<std::ffi::OsString> for StorePath {
     }
 }
 
+/// Wait for `child` to exit, but kill it once `timeout` has passed.
+///
+/// `child` must lead its own process group (`CommandExt::process_group(0)`), which is
+/// killed as a whole: the processes it started could keep its stdout and stderr open,
+/// and reading them would block long after the timeout.
+///
+/// Returns `None` if the child was killed.
+pub(crate) fn wait_timeout(
+    child: &mut Child,
+    timeout: Option<Duration>,
+) -> std::io::Result<Option<ExitStatus>> {
+    let deadline = match timeout {
+        None => return child.wait().map(Some),
+        Some(timeout) => Instant::now() + timeout,
+    };
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            let group = ::nix::unistd::Pid::from_raw(child.id() as i32);
+            ::nix::sys::signal::killpg(group, ::nix::sys::signal::Signal::SIGKILL).map_err(
+                |e| {
+                    e.as_errno()
+                        .map_or_else(|| std::io::ErrorKind::Other.into(), std::io::Error::from)
+                },
+            )?;
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep((deadline - now).min(Duration::from_millis(50)));
+    }
+}
+
 /// Opaque type to keep a temporary GC root directory alive.
 /// Once it is dropped, the GC root is removed.
 #[derive(Debug)]
@@ -110,6 +151,8 @@ impl<'a> CallOpts<'a> {
             attribute: None,
             argstrs: HashMap::new(),
             extra_options: options::NixOptions::empty(),
+            env: None,
+            timeout: None,
         }
     }
 
@@ -120,6 +163,8 @@ impl<'a> CallOpts<'a> {
             attribute: None,
             argstrs: HashMap::new(),
             extra_options: options::NixOptions::empty(),
+            env: None,
+            timeout: None,
         }
     }
 
@@ -132,6 +177,20 @@ impl<'a> CallOpts<'a> {
         self.extra_options.append(opts)
     }
 
+    /// Run nix with the allowlisted environment variables of a client,
+    /// instead of the ones of this process. See `env::ClientEnv`.
+    pub fn env(&mut self, env: env::ClientEnv) -> &mut Self {
+        self.env = Some(env);
+        self
+    }
+
+    /// Kill nix if it runs longer than `timeout`; the call then fails
+    /// with `BuildError::Timeout`.
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
     /// Evaluate a sub attribute of the expression. Only supports one:
     /// calling attribute() multiple times is supported, but overwrites
     /// the previous attribute.
@@ -369,11 +428,16 @@ impl<'a> CallOpts<'a> {
     {
         cmd.stderr(Stdio::piped());
         cmd.stdout(Stdio::piped());
+        if let Some(env) = &self.env {
+            env.apply(&mut cmd);
+        }
+        // so that `wait_timeout` can kill everything nix starts
+        cmd.process_group(0);
 
         // 0. spawn the process
         let mut nix_proc = cmd.spawn().map_err(|e| match e.kind() {
             std::io::ErrorKind::NotFound => BuildError::spawn(&cmd, e),
-            _ => BuildError::io(e),
+            _ => BuildError::from(e),
         })?;
 
         // 1. spawn a stderr handling thread
@@ -394,7 +458,7 @@ impl<'a> CallOpts<'a> {
             thread::spawn(move || stdout_fn(std::io::BufReader::new(stdout_handle)));
 
         // 3. wait on the process
-        let nix_proc_result = nix_proc.wait()?;
+        let nix_proc_result = wait_timeout(&mut nix_proc, self.timeout)?;
 
         // 4. join the stderr handler
         stderr_thread
@@ -406,14 +470,18 @@ impl<'a> CallOpts<'a> {
             .join()
             .expect("stderr handling thread panicked");
 
-        if !nix_proc_result.success() {
-            Err(BuildError::exit(
+        match (nix_proc_result, self.timeout) {
+            (Some(status), _) if status.success() => Ok(data_result),
+            (Some(status), _) => Err(BuildError::exit(
                 &cmd,
-                nix_proc_result,
+                status,
                 stderr_rx.iter().collect::<Vec<_>>(),
-            ))
-        } else {
-            Ok(data_result)
+            )),
+            (None, timeout) => Err(BuildError::timeout(
+                &cmd,
+                timeout.expect("only a timeout kills the process"),
+                stderr_rx.iter().collect::<Vec<_>>(),
+            )),
         }
     }
 
@@ -519,9 +587,46 @@ impl From<BuildError> for OnePathError {
 
 #[cfg(test)]
 mod tests {
-    use super::CallOpts;
+    use super::{wait_timeout, CallOpts};
+    use crossbeam_channel as chan;
     use std::ffi::OsStr;
+    use std::io::Read;
+    use std::os::unix::process::CommandExt;
     use std::path::Path;
+    use std::process::{Command, Stdio};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn wait_timeout_kills_the_process() -> std::io::Result<()> {
+        let mut sleep = Command::new("sleep").arg("10").process_group(0).spawn()?;
+        let start = Instant::now();
+        assert_eq!(
+            wait_timeout(&mut sleep, Some(Duration::from_millis(100)))?,
+            None
+        );
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // and the processes it started, which keep its output open
+        let mut sh = Command::new("sh")
+            .args(["-c", "sleep 100 & sleep 100"])
+            .stdout(Stdio::piped())
+            .process_group(0)
+            .spawn()?;
+        let mut stdout = sh.stdout.take().expect("stdout is piped");
+        let (tx, rx) = chan::bounded(1);
+        std::thread::spawn(move || tx.send(stdout.read_to_end(&mut Vec::new())));
+        assert_eq!(
+            wait_timeout(&mut sh, Some(Duration::from_millis(100)))?,
+            None
+        );
+        assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok());
+
+        let mut finishes = Command::new("true").process_group(0).spawn()?;
+        assert!(wait_timeout(&mut finishes, Some(Duration::from_secs(10)))?
+            .expect("should not time out")
+            .success());
+        Ok(())
+    }
 
     #[test]
     fn cmd_arguments_expression() {
@@ -0,0 +1,90 @@
+//! The part of a client’s environment that influences nix evaluations.
+//!
+//! `lorri direnv` and `lorri internal ping` send these variables to the daemon,
+//! so that daemon builds see the same environment as `lorri shell` would.
+
+use std::collections::BTreeMap;
+use std::process::Command;
+
+/// Environment variables that are passed from the client to nix.
+///
+/// Everything else (`HOME`, `PATH`, …) is taken from the daemon’s environment.
+pub const ALLOWLIST: &[&str] = &[
+    "NIX_PATH",
+    "NIX_CONFIG",
+    "NIX_SSL_CERT_FILE",
+    "SSL_CERT_FILE",
+    "NIXPKGS_CONFIG",
+    "NIXPKGS_ALLOW_UNFREE",
+    "NIXPKGS_ALLOW_INSECURE",
+    "NIXPKGS_ALLOW_BROKEN",
+    "NIXPKGS_ALLOW_UNSUPPORTED_SYSTEM",
+];
+
+/// The values of the `ALLOWLIST`ed variables in a client’s environment.
+/// Variables that are not set in the client are missing.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientEnv(BTreeMap<String, String>);
+
+impl ClientEnv {
+    /// The allowlisted variables of the current process.
+    pub fn current() -> ClientEnv {
+        ClientEnv::from_vars(std::env::vars())
+    }
+
+    /// The allowlisted variables from `vars`; all others are dropped.
+    pub fn from_vars<I>(vars: I) -> ClientEnv
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        ClientEnv(
+            vars.into_iter()
+                .filter(|(name, _)| ALLOWLIST.contains(&name.as_str()))
+                .collect(),
+        )
+    }
+
+    /// Value of the variable `name`, if the client set it.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.get(name).map(String::as_str)
+    }
+
+    /// Set the allowlisted variables of `cmd` to the client’s values.
+    /// Variables the client did not set are removed, even if the daemon has them.
+    pub fn apply(&self, cmd: &mut Command) {
+        for name in ALLOWLIST {
+            match self.0.get(*name) {
+                Some(value) => cmd.env(name, value),
+                None => cmd.env_remove(name),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ClientEnv;
+    use std::ffi::OsStr;
+    use std::process::Command;
+
+    #[test]
+    fn only_allowlisted_vars_are_applied() {
+        let env = ClientEnv::from_vars(vec![
+            ("NIX_PATH".to_string(), "nixpkgs=/src/nixpkgs".to_string()),
+            ("HOME".to_string(), "/home/client".to_string()),
+        ]);
+        assert_eq!(env.get("NIX_PATH"), Some("nixpkgs=/src/nixpkgs"));
+        assert_eq!(env.get("HOME"), None);
+
+        let mut cmd = Command::new("true");
+        env.apply(&mut cmd);
+        let envs = cmd.get_envs().collect::<Vec<_>>();
+        assert!(envs.contains(&(
+            OsStr::new("NIX_PATH"),
+            Some(OsStr::new("nixpkgs=/src/nixpkgs"))
+        )));
+        // unset in the client, so it must not leak in from the daemon
+        assert!(envs.contains(&(OsStr::new("NIXPKGS_ALLOW_UNFREE"), None)));
+        assert!(!envs.iter().any(|(name, _)| *name == "HOME"));
+    }
+}
@@ -2,11 +2,12 @@
 
 mod direnv;
 pub mod error;
+pub mod stream_events;
 
 use crate::build_loop::BuildLoop;
-use crate::build_loop::{Event, EventI, ReasonI};
+use crate::build_loop::{BuildRequest, Event, RetryPolicy};
 use crate::builder;
-use crate::builder::OutputPath;
+use crate::builder::{OutputPath, Timeouts};
 use crate::cas::ContentAddressable;
 use crate::changelog;
 use crate::cli;
@@ -17,13 +18,16 @@ use crate::constants::Paths;
 use crate::daemon::client::{self, DaemonInfo};
 use crate::daemon::Daemon;
 use crate::nix;
+use crate::nix::env::ClientEnv;
 use crate::nix::options::NixOptions;
 use crate::nix::CallOpts;
 use crate::ops::direnv::{DirenvVersion, MIN_DIRENV_VERSION};
 use crate::ops::error::{ExitAs, ExitError, ExitErrorType};
-use crate::project::Project;
+use crate::project::pins::Pins;
+use crate::project::{Project, RootPath};
 use crate::run_async::Async;
-use crate::socket::path::SocketPath;
+use crate::socket::path::{DaemonAddress, SocketPath};
+use crate::watch::WatchConfig;
 use crate::NixFile;
 use crate::VERSION_BUILD_REV;
 
@@ -67,6 +71,14 @@ pub fn get_paths() -> Result<crate::constants::Paths, error::ExitError> {
 
 /// See the documentation for lorri::cli::Command::Daemon for details.
 pub fn op_daemon(opts: crate::cli::DaemonOptions, logger: &slog::Logger) -> Result<(), ExitError> {
+    let paths = crate::ops::get_paths()?;
+    if let DaemonAddress::Tcp { .. } = paths.daemon_address() {
+        return Err(ExitError::user_error(anyhow::anyhow!(
+            "the daemon listens on a Unix socket, not on {}; use --listen-tcp to accept TCP clients",
+            paths.daemon_address()
+        )));
+    }
+
     let extra_nix_options = match opts.extra_nix_options {
         None => NixOptions::empty(),
         Some(v) => NixOptions {
@@ -75,7 +87,48 @@ pub fn op_daemon(opts: crate::cli::DaemonOptions, logger: &slog::Logger) -> Resu
         },
     };
 
-    let (mut daemon, build_rx) = Daemon::new(extra_nix_options);
+    let defaults = WatchConfig::default();
+    let watch_config = WatchConfig {
+        debounce: opts
+            .debounce_ms
+            .map(Duration::from_millis)
+            .unwrap_or(defaults.debounce),
+        quiet_period: opts.quiet_period_ms.map(Duration::from_millis),
+        ..defaults
+    };
+
+    let timeouts = Timeouts {
+        instantiate: opts.instantiate_timeout_secs.map(Duration::from_secs),
+        build: opts.build_timeout_secs.map(Duration::from_secs),
+    };
+
+    let defaults = RetryPolicy::default();
+    let retry = RetryPolicy {
+        max_retries: opts.max_retries.unwrap_or(defaults.max_retries),
+        ..defaults
+    };
+
+    if opts.multi_user {
+        if !cfg!(target_os = "linux") {
+            return Err(ExitError::user_error(anyhow::anyhow!(
+                "--multi-user is only supported on Linux"
+            )));
+        }
+        if opts.listen_tcp.is_some() {
+            // forwarded connections come from the daemon itself, so we can’t tell the user
+            return Err(ExitError::user_error(anyhow::anyhow!(
+                "--multi-user and --listen-tcp can’t be combined"
+            )));
+        }
+    }
+
+    let (mut daemon, build_rx) = Daemon::new(
+        extra_nix_options,
+        watch_config,
+        timeouts,
+        retry,
+        opts.multi_user,
+    );
     let logger2 = logger.clone();
     let build_handle = std::thread::spawn(move || {
         for msg in build_rx {
@@ -84,9 +137,29 @@ pub fn op_daemon(opts: crate::cli::DaemonOptions, logger: &slog::Logger) -> Resu
     });
     info!(logger, "ready");
 
-    let paths = crate::ops::get_paths()?;
+    let tcp_listener = match opts.listen_tcp {
+        Some(addr) => {
+            let listener = std::net::TcpListener::bind(addr).map_err(|err| {
+                ExitError::user_error(
+                    anyhow::Error::new(err).context(format!("Could not listen on {}", addr)),
+                )
+            })?;
+            let token =
+                crate::daemon::tcp::Token::create(paths.daemon_token_file()).map_err(|err| {
+                    ExitError::environment_problem(anyhow::Error::new(err).context(format!(
+                        "Could not write the TCP token to {}",
+                        paths.daemon_token_file().display()
+                    )))
+                })?;
+            info!(logger, "listening on TCP"; "address" => %addr, "token_file" => %paths.daemon_token_file().display());
+            Some((listener, token))
+        }
+        None => None,
+    };
+
     daemon.serve(
         &SocketPath::from(paths.daemon_socket_file().clone()),
+        tcp_listener,
         paths.gc_root_dir(),
         paths.cas_store().clone(),
         logger,
@@ -104,27 +177,59 @@ pub fn op_daemon(opts: crate::cli::DaemonOptions, logger: &slog::Logger) -> Resu
 pub fn op_direnv<W: std::io::Write>(
     project: Project,
     paths: &Paths,
+    wait: Option<Duration>,
     mut shell_output: W,
     logger: &slog::Logger,
 ) -> Result<(), ExitError> {
     check_direnv_version()?;
 
     let root_paths = project.root_paths();
+    let address = paths.daemon_address();
+
+    // give the first build a chance to finish, so that direnv loads it right away
+    let built = match wait {
+        Some(timeout) if !root_paths.all_exist() => match ping_and_wait(
+            address,
+            project.nix_file.clone(),
+            client::Rebuild::OnlyIfNotYetWatching,
+            Some(timeout),
+            logger,
+        ) {
+            Ok(_) => true,
+            Err(err) => {
+                info!(logger, "not waiting for the first build"; "reason" => err.message());
+                false
+            }
+        },
+        _ => false,
+    };
     let paths_are_cached: bool = root_paths.all_exist();
 
-    let ping_sent = {
-        let address = crate::ops::get_paths()?.daemon_socket_file().clone();
-        debug!(logger, "connecting to socket"; "socket" => address.as_path().display());
-        client::create::<client::Ping>(paths, client::Timeout::from_millis(500), logger)
-            .map_err(ExitError::from)
+    let ping_sent = built || {
+        client::create_ping(address, client::Timeout::from_millis(500), logger)
+            .map_err(|err| {
+                if err.is_version_mismatch() {
+                    warn!(logger, "{}", err);
+                }
+                ExitError::from(err)
+            })
             .and_then(|c| {
-                c.write(&client::Ping {
-                    nix_file: project.nix_file,
+                let ack = c.ping(client::Ping {
+                    nix_file: project.nix_file.clone(),
                     rebuild: client::Rebuild::OnlyIfNotYetWatching,
+                    env: Some(ClientEnv::current()),
                 })?;
-                Ok(())
+                debug!(logger, "ping sent"; "ack" => ?ack);
+                let err = match ack {
+                    Some(client::PingResponse::Ack(client::PingAck::PermissionDenied)) => {
+                        permission_denied(&project.nix_file)
+                    }
+                    Some(client::PingResponse::Error(message)) => ping_failed(&message),
+                    _ => return Ok(()),
+                };
+                warn!(logger, "{}", err.message());
+                Err(err)
             })
-            // TODO: maybe ping should indeed return something so we can at least check whether it parses the message and the version is right. Right now this collapses all of that into a bool …
             .is_ok()
     };
 
@@ -235,15 +340,73 @@ where
 pub fn op_info(paths: &Paths, project: Project, logger: &slog::Logger) -> Result<(), ExitError> {
     let root_paths = project.root_paths();
     let OutputPath { shell_gc_root } = &root_paths;
-    let daemon_status =
-        match client::create::<client::DaemonInfo>(paths, client::Timeout::from_millis(50), logger)
-        {
-            Err(init_error) => format!("`lorri daemon` is not up: {}", init_error),
-            Ok(client) => match client.comunicate(&DaemonInfo {}) {
-                Ok(()) => "`lorri daemon` is running".to_string(),
-                Err(err) => format!("Problem connecting to the `lorri daemon`: {}", err),
-            },
-        };
+    let daemon_status = match client::create::<client::DaemonInfo>(
+        paths,
+        client::Timeout::from_millis(500),
+        logger,
+    ) {
+        Err(init_error) => format!("`lorri daemon` is not up: {}", init_error),
+        Ok(client) => match client.comunicate(&DaemonInfo {}) {
+            Ok(status) => {
+                let mut s = format!(
+                    "`lorri daemon` is running, watching {} paths in {} projects",
+                    status.watched_paths(),
+                    status.projects.len()
+                );
+                if let Some(project_status) = status.project(&project.nix_file) {
+                    s.push_str(&format!(
+                        "\nThis project: {} paths watched",
+                        project_status.watch.watched
+                    ));
+                    if project_status.watch.polled > 0 {
+                        s.push_str(&format!(
+                            " ({} of them polled)",
+                            project_status.watch.polled
+                        ));
+                    }
+                    if let Some(reason) = &project_status.crashed {
+                        s.push_str(&format!(
+                            "\nWarning: the build loop of this project crashed and is being restarted: {}",
+                            reason
+                        ));
+                    }
+                    if let Some(err) = &project_status.watch.limit_exceeded {
+                        s.push_str(&format!("\nWarning: {}", err));
+                    }
+                    if !project_status.watch.unreadable.is_empty() {
+                        s.push_str("\nWarning: these paths could not be read and are not watched:");
+                        for path in &project_status.watch.unreadable {
+                            s.push_str(&format!("\n  {}", path.display()));
+                        }
+                    }
+                }
+                s
+            }
+            Err(err) => format!("Problem connecting to the `lorri daemon`: {}", err),
+        },
+    };
+
+    let pins = Pins::load(&project.nix_file, logger);
+    let pinned_sources = if pins.files.is_empty() {
+        String::new()
+    } else {
+        let mut s = String::from("\nPinned Sources:\n");
+        for file in &pins.files {
+            s.push_str(&format!("{}:\n", file.path.display()));
+            for pin in &file.pins {
+                s.push_str(&format!(
+                    "  {}: {}",
+                    pin.name,
+                    pin.revision.as_deref().unwrap_or("(no revision)")
+                ));
+                if let Some(url) = &pin.url {
+                    s.push_str(&format!(" ({})", url));
+                }
+                s.push('\n');
+            }
+        }
+        s
+    };
 
     let gc_root = if root_paths.all_exist() {
         format!("{}", shell_gc_root.0.display())
@@ -255,7 +418,8 @@ pub fn op_info(paths: &Paths, project: Project, logger: &slog::Logger) -> Result
         "\
 Project Shell File: {}
 Project Garbage Collector Root: {}
-
+Project NIX_PATH (last build): {}
+{}
 General:
 Lorri User GC Root Dir: {}
 Lorri Daemon Socket: {}
@@ -263,8 +427,12 @@ Lorri Daemon Status: {}
 ",
         project.nix_file.display(),
         gc_root,
+        project
+            .recorded_nix_path()
+            .unwrap_or_else(|| "(unknown)".to_string()),
+        pinned_sources,
         paths.gc_root_dir().display(),
-        paths.daemon_socket_file().display(),
+        paths.daemon_address(),
         daemon_status
     );
 
@@ -317,14 +485,135 @@ fn create_if_missing(
 ///
 /// Can be used together with `direnv`.
 /// See the documentation for lorri::cli::Command::Ping_ for details.
-pub fn op_ping(paths: &Paths, nix_file: NixFile, logger: &slog::Logger) -> Result<(), ExitError> {
-    client::create(paths, client::Timeout::from_millis(500), logger)?.write(&client::Ping {
+pub fn op_ping(
+    paths: &Paths,
+    nix_file: NixFile,
+    wait: bool,
+    logger: &slog::Logger,
+) -> Result<(), ExitError> {
+    let address = paths.daemon_address();
+    if wait {
+        let root = ping_and_wait(address, nix_file, client::Rebuild::Always, None, logger)?;
+        println!("{}", root.display());
+        return Ok(());
+    }
+    let client = client::create_ping(address, client::Timeout::from_millis(500), logger)?;
+    let ack = client.ping(client::Ping {
         nix_file,
         rebuild: client::Rebuild::Always,
+        env: Some(ClientEnv::current()),
     })?;
+    debug!(logger, "ping sent"; "ack" => ?ack);
+    match ack {
+        Some(client::PingResponse::Error(message)) => Err(ping_failed(&message)),
+        _ => Ok(()),
+    }
+}
+
+/// Wait until the daemon built `nix_file`, pinging it if it is not watched yet.
+///
+/// See the documentation for lorri::cli::Command::Wait for details.
+pub fn op_wait(
+    paths: &Paths,
+    nix_file: NixFile,
+    timeout: Duration,
+    logger: &slog::Logger,
+) -> Result<(), ExitError> {
+    let root = ping_and_wait(
+        paths.daemon_address(),
+        nix_file,
+        client::Rebuild::OnlyIfNotYetWatching,
+        Some(timeout),
+        logger,
+    )?;
+    info!(logger, "project is built"; "gc_root" => root.display());
     Ok(())
 }
 
+/// Ping the daemon and wait for the result of the build (see `communicate::AcknowledgedPing`).
+/// Returns the GC root of the build, or an error if it failed or did not finish within `timeout`.
+fn ping_and_wait(
+    address: &DaemonAddress,
+    nix_file: NixFile,
+    rebuild: client::Rebuild,
+    timeout: Option<Duration>,
+    logger: &slog::Logger,
+) -> Result<RootPath, ExitError> {
+    let mut client = match client::create_ping(address, client::Timeout::from_millis(500), logger)?
+    {
+        client::PingClient::Acknowledged(client) => client,
+        client::PingClient::Plain(client) => {
+            return Err(ExitError::user_error(anyhow::anyhow!(
+                "the daemon (lorri {}) can’t report build results, please restart it",
+                client
+                    .daemon_version()
+                    .map_or("unknown", |v| v.lorri_version.as_str())
+            )))
+        }
+    };
+    let ack = client.comunicate(&client::AcknowledgedPing {
+        ping: client::Ping {
+            nix_file: nix_file.clone(),
+            rebuild,
+            env: Some(ClientEnv::current()),
+        },
+        wait: true,
+    })?;
+    debug!(logger, "ping acknowledged"; "ack" => ?ack);
+    match ack {
+        client::PingResponse::Ack(client::PingAck::PermissionDenied) => {
+            return Err(permission_denied(&nix_file))
+        }
+        client::PingResponse::Error(message) => return Err(ping_failed(&message)),
+        _ => {}
+    }
+    // builds can take arbitrarily long, we time out ourselves
+    client.set_timeout(client::Timeout::Infinite);
+    // lingers, because we don’t want to block exiting on a timeout
+    let response = Async::run_and_linger(logger, move || client.read());
+    let timeout = timeout.map_or_else(chan::never, chan::after);
+    let response = chan::select! {
+        recv(response.chan()) -> res => res.expect("the ping thread hung up")?,
+        recv(timeout) -> _ => return Err(ExitError::temporary(anyhow::anyhow!(
+            "timed out waiting for the build of {}",
+            nix_file.display()
+        ))),
+    };
+    match response {
+        client::PingResponse::Finished(Event::Completed {
+            rooted_output_paths,
+            ..
+        }) => Ok(rooted_output_paths.shell_gc_root),
+        client::PingResponse::Finished(Event::Failure { failure, .. }) => Err(
+            ExitError::user_error(anyhow::anyhow!("build failed: {}", failure)),
+        ),
+        client::PingResponse::Finished(Event::Crashed { reason, .. }) => Err(ExitError::temporary(
+            anyhow::anyhow!("the build loop crashed: {}", reason),
+        )),
+        client::PingResponse::Error(message) => Err(ping_failed(&message)),
+        other => Err(ExitError::panic(anyhow::anyhow!(
+            "the daemon sent an unexpected response: {:?}",
+            other
+        ))),
+    }
+}
+
+/// The shared daemon refused to build `nix_file` for us.
+fn permission_denied(nix_file: &NixFile) -> ExitError {
+    ExitError::user_error(anyhow::anyhow!(
+        "the lorri daemon serves multiple users and refused to build {}, because you can’t read it",
+        nix_file.display()
+    ))
+}
+
+/// The daemon reported that it could not handle our ping.
+fn ping_failed(message: &str) -> ExitError {
+    ExitError::temporary(anyhow::anyhow!(
+        "the daemon failed to handle the ping: {}",
+        message
+    ))
+}
+
 /// Open up a project shell
 ///
 /// This is the entry point for the `lorri shell` command.
@@ -435,6 +724,8 @@ fn build_root(
         &project.nix_file,
         &project.cas,
         &crate::nix::options::NixOptions::empty(),
+        None,
+        Timeouts::default(),
         &logger2,
     );
     building.store(false, Ordering::SeqCst);
@@ -626,36 +917,6 @@ impl FromStr for EventKind {
     }
 }
 
-// These types are just transparent newtype wrappers to implement a different serde class and JsonEncode
-
-/// For now use the EventI structure, in the future we might want to split it off.
-/// At least it will show us that we need to change something here if we change it
-/// and it relates to this interface.
-#[derive(Serialize)]
-#[serde(transparent)]
-struct StreamEvent(EventI<StreamNixFile, StreamReason, StreamOutputPath, StreamBuildError>);
-
-/// Nix files are encoded as strings
-#[derive(Serialize)]
-#[serde(transparent)]
-struct StreamNixFile(String);
-
-/// Same here, the reason contains a nix file which has to be converted to a string.
-#[derive(Serialize)]
-#[serde(transparent)]
-struct StreamReason(ReasonI<String>);
-
-/// And same here, OutputPaths are GcRoots and have to be converted as well.
-#[derive(Serialize)]
-#[serde(transparent)]
-struct StreamOutputPath(OutputPath<String>);
-
-/// Just expose the error message for now.
-#[derive(Serialize)]
-struct StreamBuildError {
-    message: String,
-}
-
 /// Run to output a stream of build events in a machine-parseable form.
 ///
 /// See the documentation for lorri::cli::Command::StreamEvents_ for more
@@ -663,34 +924,60 @@ struct StreamBuildError {
 pub fn op_stream_events(
     paths: &Paths,
     kind: EventKind,
+    filter: client::EventFilter,
+    output: stream_events::Output,
     logger: &slog::Logger,
 ) -> Result<(), ExitError> {
-    let (tx_event, rx_event) = chan::unbounded::<Event>();
+    let (tx_event, rx_event) = chan::unbounded::<client::StampedEvent>();
 
     let thread = {
-        let address = crate::ops::get_paths()?.daemon_socket_file().clone();
-        debug!(logger, "connecting to socket"; "socket" => address.as_path().display());
         let logger2 = logger.clone();
         let paths2 = (*paths).clone();
         // This async will not block when it is dropped,
         // since it only reads messages and don’t want to block exit in the Snapshot case.
         Async::<Result<(), ExitError>>::run_and_linger(logger, move || {
-            let client = client::create::<client::StreamEvents>(
+            // infinite timeout because we are listening indefinitely
+            match client::create::<client::FilteredStreamEvents>(
                 &paths2,
-                // infinite timeout because we are listening indefinitely
                 client::Timeout::Infinite,
                 &logger2,
-            )?;
-
-            client.write(&client::StreamEvents {})?;
-            loop {
-                let res = client.read();
-                tx_event
-                    .send(
-                        // TODO: error
-                        res.map_err(|err| ExitError::temporary(anyhow::Error::new(err)))?,
+            ) {
+                Ok(client) => {
+                    client.write(&client::FilteredStreamEvents {
+                        filter: filter.clone(),
+                    })?;
+                    let stamped = client.daemon_version().is_some_and(|daemon| {
+                        daemon.has_capability(client::capability::STAMPED_EVENTS)
+                    });
+                    if stamped {
+                        forward_events(client, |stamped| stamped, &filter, tx_event)
+                    } else {
+                        // the daemon predates `StampedEvent`, so we use the time we receive the events
+                        forward_events(
+                            client.unstamped(),
+                            |event| client::StampedEvent::now(event, None),
+                            &filter,
+                            tx_event,
+                        )
+                    }
+                }
+                // the daemon predates `FilteredStreamEvents`, so we filter ourselves
+                // and use the time we receive the events
+                Err(client::InitError::OldDaemon(_)) => {
+                    let client = client::create::<client::StreamEvents>(
+                        &paths2,
+                        client::Timeout::Infinite,
+                        &logger2,
+                    )?;
+                    client.write(&client::StreamEvents {})?;
+                    forward_events(
+                        client,
+                        |event| client::StampedEvent::now(event, None),
+                        &filter,
+                        tx_event,
                     )
-                    .expect("tx_event hung up!");
+                }
+                Err(err) => Err(err.into()),
             }
         })
     };
@@ -699,8 +986,11 @@ pub fn op_stream_events(
     loop {
         chan::select! {
             recv(rx_event) -> event => match event.expect("rx_event hung up!") {
-                Event::SectionEnd => {
+                stamped @ client::StampedEvent { event: Event::SectionEnd, .. } => {
                     debug!(logger, "SectionEnd");
+                    if let EventKind::All = kind {
+                        print_event(output, stamped);
+                    }
                     match kind {
                         // If we only want the snapshot, quit the program
                         EventKind::Snapshot => break Ok(()),
@@ -710,25 +1000,7 @@ pub fn op_stream_events(
                 }
                 ev => match (snapshot_done, &kind) {
                     (_, EventKind::All) | (false, EventKind::Snapshot) | (true, EventKind::Live) => {
-                        fn nix_file_string(nix_file: NixFile) -> String {
-                            nix_file.display().to_string()
-                        }
-                        serde_json::to_writer(
-                            std::io::stdout(),
-                            &StreamEvent(ev.map(
-                                |nix_file| StreamNixFile(nix_file_string(nix_file)),
-                                |reason| StreamReason(reason.map(nix_file_string)),
-                                |output_path| {
-                                    StreamOutputPath(output_path.map(|o| o.display().to_string()))
-                                },
-                                |build_error| StreamBuildError {
-                                    message: format!("{}", build_error),
-                                },
-                            )),
-                        )
-                            .expect("couldn't serialize event");
-                        writeln!(std::io::stdout()).expect("couldn't serialize event");
-                        std::io::stdout().flush().expect("couldn't flush serialized event");
+                        print_event(output, ev);
                     }
                     _ => (),
                 },
@@ -742,6 +1014,40 @@ pub fn op_stream_events(
     }
 }
 
+/// Send the events from `client` that match `filter` to `tx_event`, until reading fails.
+/// `stamp` turns the messages of `client` into `StampedEvent`s.
+fn forward_events<R, W>(
+    client: client::Client<R, W>,
+    stamp: impl Fn(R) -> client::StampedEvent,
+    filter: &client::EventFilter,
+    tx_event: chan::Sender<client::StampedEvent>,
+) -> Result<(), ExitError>
+where
+    R: serde::de::DeserializeOwned,
+{
+    loop {
+        // TODO: error
+        let stamped = stamp(
+            client
+                .read()
+                .map_err(|err| ExitError::temporary(anyhow::Error::new(err)))?,
+        );
+        if filter.matches(&stamped.event) {
+            tx_event.send(stamped).expect("tx_event hung up!");
+        }
+    }
+}
+
+/// Print `stamped` to stdout, as a single line.
+fn print_event(output: stream_events::Output, stamped: client::StampedEvent) {
+    let time = stamped.time();
+    if let Some(line) = stream_events::render(output, stamped.event, time) {
+        let mut stdout = std::io::stdout().lock();
+        writeln!(stdout, "{}", line).expect("couldn't print event");
+        stdout.flush().expect("couldn't flush printed event");
+    }
+}
+
 /// The source to upgrade to.
 enum UpgradeSource {
     /// A branch in the upstream git repo
@@ -920,8 +1226,15 @@ pub fn op_watch(
 
 fn main_run_once(project: Project, logger: &slog::Logger) -> Result<(), ExitError> {
     // TODO: add the ability to pass extra_nix_options to watch
-    let mut build_loop = BuildLoop::new(&project, NixOptions::empty(), logger.clone())
-        .map_err(ExitError::temporary)?;
+    let mut build_loop = BuildLoop::new(
+        &project,
+        NixOptions::empty(),
+        WatchConfig::default(),
+        Timeouts::default(),
+        RetryPolicy::default(),
+        logger.clone(),
+    )
+    .map_err(ExitError::temporary)?;
     match build_loop.once() {
         Ok(msg) => {
             info!(logger, "build message"; "message" => ?msg);
@@ -957,7 +1270,12 @@ fn list_roots(logger: &slog::Logger) -> Result<Vec<GcRootInfo>, ExitError> {
     let paths = crate::ops::get_paths()?;
     let mut res = Vec::new();
     let gc_root_dir = paths.gc_root_dir();
-    for entry in std::fs::read_dir(gc_root_dir)? {
+    let entries = match std::fs::read_dir(gc_root_dir) {
+        // nothing was built yet
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(res),
+        entries => entries?,
+    };
+    for entry in entries {
         let entry = entry?;
         if !entry.file_type()?.is_dir() {
             debug!(
@@ -1114,15 +1432,27 @@ fn main_run_forever(project: Project, logger: &slog::Logger) -> Result<(), ExitE
     // TODO: add the ability to pass extra_nix_options to watch
     let build_thread = {
         Async::run(logger, move || {
-            match BuildLoop::new(&project, NixOptions::empty(), logger2) {
-                Ok(mut bl) => bl.forever(tx_build_results, rx_ping).never(),
+            match BuildLoop::new(
+                &project,
+                NixOptions::empty(),
+                WatchConfig::default(),
+                Timeouts::default(),
+                RetryPolicy::default(),
+                logger2,
+            ) {
+                Ok(mut bl) => match bl.forever(None, tx_build_results, rx_ping) {
+                    Ok(never) => never.never(),
+                    Err(e) => Err(ExitError::temporary(anyhow::anyhow!("{}", e))),
+                },
                 Err(e) => Err(ExitError::temporary(e)),
             }
         })
     };
 
     // We ping the build loop once, to make it run the first build immediately
-    tx_ping.send(()).expect("could not send ping to build_loop");
+    tx_ping
+        .send(BuildRequest::default())
+        .expect("could not send ping to build_loop");
 
     for msg in rx_build_results {
         info!(logger, "build message"; "message" => ?msg);
@@ -1130,3 +1460,174 @@ fn main_run_forever(project: Project, logger: &slog::Logger) -> Result<(), ExitE
 
     build_thread.block()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::daemon::server::Server;
+    use crate::daemon::{IndicateActivity, LoopHandlerEvent};
+    use crate::AbsPathBuf;
+
+    /// Answer the first `AcknowledgedPing` like a daemon whose build of the project
+    /// sends `events`, and return the socket of the daemon.
+    fn fake_daemon(dir: &Path, events: Vec<Event>) -> DaemonAddress {
+        let logger = crate::logging::test_logger("ping_and_wait");
+        let socket = SocketPath::from(AbsPathBuf::new(dir.join("daemon.socket")).unwrap());
+        let (tx_activity, rx_activity) = chan::unbounded::<IndicateActivity>();
+        let (tx_build, rx_build) = chan::unbounded();
+        let server = Server::new(tx_activity, tx_build, false);
+        let listen_socket = socket.clone();
+        thread::spawn(move || server.listen(&listen_socket, &logger));
+        thread::spawn(move || {
+            let tx_event = match rx_build.recv() {
+                Ok(LoopHandlerEvent::NewListener { tx, .. }) => tx,
+                _ => panic!("expected the server to add a listener"),
+            };
+            let activity = rx_activity.recv().unwrap();
+            activity
+                .ack
+                .unwrap()
+                .send(client::PingAck::Accepted)
+                .unwrap();
+            tx_event
+                .send(client::StampedEvent::now(Event::SectionEnd, None))
+                .unwrap();
+            for event in events {
+                // like the build loop, which reports the build after the ping to the server
+                if let (Event::Completed { .. } | Event::Failure { .. }, Some(done)) =
+                    (&event, &activity.done)
+                {
+                    done.send(event.clone()).unwrap();
+                }
+                tx_event
+                    .send(client::StampedEvent::now(event, None))
+                    .unwrap();
+            }
+            // keep the listener alive until the client is done
+            let _ = rx_build.recv();
+        });
+        for _ in 0..100 {
+            if socket.connect().is_ok() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        DaemonAddress::Unix(socket)
+    }
+
+    fn shell_nix(dir: &Path) -> NixFile {
+        NixFile::from(AbsPathBuf::new(dir.join("shell.nix")).unwrap())
+    }
+
+    #[test]
+    fn ping_and_wait_returns_the_gc_root() {
+        let logger = crate::logging::test_logger("ping_and_wait");
+        let dir = tempfile::tempdir().unwrap();
+        let nix_file = shell_nix(dir.path());
+        let gc_root = AbsPathBuf::new(dir.path().join("gc_root")).unwrap();
+        let address = fake_daemon(
+            dir.path(),
+            vec![
+                Event::Started {
+                    nix_file: nix_file.clone(),
+                    reason: crate::build_loop::ReasonI::PingReceived,
+                },
+                Event::Completed {
+                    nix_file: nix_file.clone(),
+                    rooted_output_paths: OutputPath {
+                        shell_gc_root: RootPath(gc_root.clone()),
+                    },
+                },
+            ],
+        );
+        let root = ping_and_wait(
+            &address,
+            nix_file,
+            client::Rebuild::OnlyIfNotYetWatching,
+            Some(Duration::from_secs(10)),
+            &logger,
+        )
+        .unwrap();
+        assert_eq!(root.0, gc_root);
+    }
+
+    #[test]
+    fn ping_and_wait_times_out() {
+        let logger = crate::logging::test_logger("ping_and_wait");
+        let dir = tempfile::tempdir().unwrap();
+        let nix_file = shell_nix(dir.path());
+        // the build starts, but never finishes
+        let address = fake_daemon(
+            dir.path(),
+            vec![Event::Started {
+                nix_file: nix_file.clone(),
+                reason: crate::build_loop::ReasonI::PingReceived,
+            }],
+        );
+        let start = Instant::now();
+        let err = ping_and_wait(
+            &address,
+            nix_file,
+            client::Rebuild::OnlyIfNotYetWatching,
+            Some(Duration::from_millis(200)),
+            &logger,
+        )
+        .unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(
+            err.message().contains("timed out"),
+            "unexpected error: {}",
+            err.message()
+        );
+    }
+
+    #[test]
+    fn ping_and_wait_over_tcp() {
+        let logger = crate::logging::test_logger("ping_and_wait");
+        let dir = tempfile::tempdir().unwrap();
+        let nix_file = shell_nix(dir.path());
+        let gc_root = AbsPathBuf::new(dir.path().join("gc_root")).unwrap();
+        let socket = match fake_daemon(
+            dir.path(),
+            vec![
+                Event::Started {
+                    nix_file: nix_file.clone(),
+                    reason: crate::build_loop::ReasonI::PingReceived,
+                },
+                Event::Completed {
+                    nix_file: nix_file.clone(),
+                    rooted_output_paths: OutputPath {
+                        shell_gc_root: RootPath(gc_root.clone()),
+                    },
+                },
+            ],
+        ) {
+            DaemonAddress::Unix(socket) => socket,
+            DaemonAddress::Tcp { .. } => unreachable!(),
+        };
+        // like `lorri daemon --listen-tcp`
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let token_file = AbsPathBuf::new(dir.path().join("daemon.token")).unwrap();
+        let token = crate::daemon::tcp::Token::create(&token_file).unwrap();
+        let forward_logger = logger.clone();
+        thread::spawn(move || {
+            crate::daemon::tcp::forward(listener, &socket, &token, &forward_logger)
+        });
+
+        let address =
+            match DaemonAddress::parse_tcp(&format!("tcp://127.0.0.1:{}", port), &token_file) {
+                Some(Ok(address)) => address,
+                other => panic!("expected a TCP address, got {:?}", other),
+            };
+        let root = ping_and_wait(
+            &address,
+            nix_file,
+            client::Rebuild::OnlyIfNotYetWatching,
+            Some(Duration::from_secs(10)),
+            &logger,
+        )
+        .unwrap();
+        assert_eq!(root.0, gc_root);
+    }
+}
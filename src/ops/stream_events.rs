@@ -0,0 +1,416 @@
+//! The output of `lorri events` and `lorri internal stream-events`.
+//!
+//! `lorri events` prints one line per event for humans by default.
+//! As JSON, every event is printed as one JSON object per line. The format is versioned:
+//! version 1 is documented in `contrib/stream-events.md` and only changes in
+//! backwards-compatible ways (new fields, new `type`s), version 0 is the
+//! unstable format of earlier lorri releases, which mirrors lorri’s internal types.
+//! Version 1 events are the `socket::wire` events, which JSON clients of the socket get as well.
+
+use crate::build_loop::{Event, EventI, ReasonI};
+use crate::builder::{BuildError, OutputPath};
+use crate::socket::wire;
+use crate::NixFile;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::SystemTime;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+/// The versions of the output format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormatVersion {
+    /// The unstable format of lorri 1.6 and earlier
+    V0,
+    /// The documented format, see `contrib/stream-events.md`
+    V1,
+}
+
+impl FromStr for FormatVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "0" => Ok(FormatVersion::V0),
+            "1" => Ok(FormatVersion::V1),
+            _ => Err(format!("{} not in 0,1", s)),
+        }
+    }
+}
+
+/// How to print the events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Output {
+    /// One JSON object per line, in this format version
+    Json(FormatVersion),
+    /// One line per event for humans, with ANSI colours if `colour` is set
+    Human {
+        /// Whether to colour the output
+        colour: bool,
+    },
+}
+
+/// Render `event` as a single line (without the newline), `None` if it is not printed.
+/// `time` is the time the daemon recorded the event.
+pub fn render(output: Output, event: Event, time: SystemTime) -> Option<String> {
+    match (output, event) {
+        // version 0 never printed the end of the snapshot
+        (Output::Json(FormatVersion::V0), EventI::SectionEnd) => None,
+        (Output::Json(version), event) => Some(encode(version, event, time)),
+        (Output::Human { colour }, event) => Some(human(&event, time, colour)),
+    }
+}
+
+/// Encode `event` as a single line of JSON (without the newline).
+/// `time` is the time the daemon recorded the event.
+/// Paths that are not valid UTF-8 are encoded lossily.
+pub fn encode(version: FormatVersion, event: Event, time: SystemTime) -> String {
+    match version {
+        FormatVersion::V0 => serde_json::to_string(&v0(event)),
+        FormatVersion::V1 => serde_json::to_string(&wire::Event::new(event, time)),
+    }
+    // both formats only contain strings, numbers and string-keyed objects
+    .expect("couldn't serialize event")
+}
+
+/// ANSI colour codes
+const GREEN: &str = "32";
+const YELLOW: &str = "33";
+const RED: &str = "31";
+const DIM: &str = "2";
+
+/// A line like `2026-10-18T17:43:11Z /p/shell.nix built`.
+fn human(event: &Event, time: SystemTime, colour: bool) -> String {
+    let paint = |code: &str, s: &str| {
+        if colour {
+            format!("\x1b[{}m{}\x1b[0m", code, s)
+        } else {
+            s.to_string()
+        }
+    };
+    let (nix_file, status) = match event {
+        EventI::SectionEnd => {
+            return paint(DIM, &format!("{} (live events follow)", utc_time(time)));
+        }
+        EventI::Started { nix_file, reason } => {
+            let reason = match reason {
+                ReasonI::ProjectAdded(_) => "project added".to_string(),
+                ReasonI::PingReceived => "ping received".to_string(),
+                ReasonI::FilesChanged(files) => changed("changed", files),
+                ReasonI::PinsChanged { pins, files } => match pins.as_slice() {
+                    [] => changed("pins changed", files),
+                    pins => format!(
+                        "pins changed: {}",
+                        pins.iter()
+                            .map(|pin| pin.to_string())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ),
+                },
+            };
+            (
+                nix_file,
+                format!("{} ({})", paint(YELLOW, "building"), reason),
+            )
+        }
+        EventI::Completed { nix_file, .. } => (nix_file, paint(GREEN, "built")),
+        EventI::Failure { nix_file, failure } => (
+            nix_file,
+            format!("{}: {}", paint(RED, "failed"), error_summary(failure)),
+        ),
+        EventI::Retrying {
+            nix_file,
+            attempt,
+            failure,
+        } => (
+            nix_file,
+            format!(
+                "{}: {}",
+                paint(YELLOW, &format!("retrying (attempt {})", attempt)),
+                error_summary(failure)
+            ),
+        ),
+        EventI::Crashed {
+            nix_file,
+            reason,
+            restart_in_secs,
+        } => (
+            nix_file,
+            format!(
+                "{}: {}",
+                paint(RED, &format!("crashed, restarting in {}s", restart_in_secs)),
+                reason.lines().next().unwrap_or_default()
+            ),
+        ),
+    };
+    format!(
+        "{} {} {}",
+        paint(DIM, &utc_time(time)),
+        nix_file.display(),
+        status
+    )
+}
+
+/// `what: first/file and 2 more`
+fn changed(what: &str, files: &[PathBuf]) -> String {
+    match files {
+        [] => what.to_string(),
+        [file] => format!("{}: {}", what, file.display()),
+        [file, rest @ ..] => format!("{}: {} and {} more", what, file.display(), rest.len()),
+    }
+}
+
+/// The gist of `error` in one line: the last error nix printed, if any.
+fn error_summary(error: &BuildError) -> String {
+    let logs = match error {
+        BuildError::Exit { logs, .. } | BuildError::Timeout { logs, .. } => logs.as_slice(),
+        _ => &[],
+    };
+    let nix_error = logs
+        .iter()
+        .rev()
+        .map(|l| String::from_utf8_lossy(l.0.as_bytes()))
+        .find(|l| l.trim_start().starts_with("error:"));
+    match nix_error {
+        Some(line) => line.trim().to_string(),
+        None => error
+            .to_string()
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .to_string(),
+    }
+}
+
+/// `time` in UTC as RFC 3339, to the second.
+fn utc_time(time: SystemTime) -> String {
+    let time = OffsetDateTime::from(time);
+    time.replace_nanosecond(0)
+        .unwrap_or(time)
+        .format(&Rfc3339)
+        // only years after 9999 can’t be formatted
+        .unwrap_or_else(|_| format!("@{}", time.unix_timestamp()))
+}
+
+fn nix_file_string(nix_file: NixFile) -> String {
+    nix_file.display().to_string()
+}
+
+// These types are just transparent newtype wrappers to implement a different serde class and JsonEncode
+
+/// For now use the EventI structure, in the future we might want to split it off.
+/// At least it will show us that we need to change something here if we change it
+/// and it relates to this interface.
+#[derive(Serialize)]
+#[serde(transparent)]
+struct StreamEvent(EventI<StreamNixFile, StreamReason, StreamOutputPath, StreamBuildError>);
+
+/// Nix files are encoded as strings
+#[derive(Serialize)]
+#[serde(transparent)]
+struct StreamNixFile(String);
+
+/// Same here, the reason contains a nix file which has to be converted to a string.
+#[derive(Serialize)]
+#[serde(transparent)]
+struct StreamReason(ReasonI<String>);
+
+/// And same here, OutputPaths are GcRoots and have to be converted as well.
+#[derive(Serialize)]
+#[serde(transparent)]
+struct StreamOutputPath(OutputPath<String>);
+
+/// Just expose the error message for now.
+#[derive(Serialize)]
+struct StreamBuildError {
+    message: String,
+}
+
+fn v0(event: Event) -> StreamEvent {
+    // JSON strings must be UTF-8, paths need not be
+    let lossy = |files: Vec<PathBuf>| -> Vec<PathBuf> {
+        files
+            .iter()
+            .map(|file| PathBuf::from(file.to_string_lossy().into_owned()))
+            .collect()
+    };
+    StreamEvent(event.map(
+        |nix_file| StreamNixFile(nix_file_string(nix_file)),
+        |reason| {
+            StreamReason(match reason.map(nix_file_string) {
+                ReasonI::FilesChanged(files) => ReasonI::FilesChanged(lossy(files)),
+                ReasonI::PinsChanged { files, pins } => ReasonI::PinsChanged {
+                    files: lossy(files),
+                    pins,
+                },
+                reason => reason,
+            })
+        },
+        |output_path| StreamOutputPath(output_path.map(|o| o.display().to_string())),
+        |build_error| StreamBuildError {
+            message: format!("{}", build_error),
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode, render, utc_time, FormatVersion, Output};
+    use crate::build_loop::{Event, ReasonI};
+    use crate::builder::{BuildError, LogLine, OutputPath};
+    use crate::project::pins::{ChangedPin, PinTool};
+    use crate::project::RootPath;
+    use crate::{AbsPathBuf, NixFile};
+    use std::path::PathBuf;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    /// One event of every kind, to compare against the golden files.
+    fn events() -> Vec<Event> {
+        let nix_file = NixFile::from(AbsPathBuf::new(PathBuf::from("/p/shell.nix")).unwrap());
+        let exit = BuildError::Exit {
+            cmd: "\"nix-instantiate\" \"/p/shell.nix\"".into(),
+            status: Some(1),
+            logs: vec![LogLine::from("error: undefined variable 'foo'".to_string())],
+            referenced_paths: vec![],
+        };
+        vec![
+            Event::Started {
+                nix_file: nix_file.clone(),
+                reason: ReasonI::ProjectAdded(nix_file.clone()),
+            },
+            Event::Started {
+                nix_file: nix_file.clone(),
+                reason: ReasonI::PingReceived,
+            },
+            Event::Started {
+                nix_file: nix_file.clone(),
+                reason: ReasonI::FilesChanged(vec![PathBuf::from("/p/default.nix")]),
+            },
+            Event::Started {
+                nix_file: nix_file.clone(),
+                reason: ReasonI::PinsChanged {
+                    files: vec![PathBuf::from("/p/npins/sources.json")],
+                    pins: vec![ChangedPin {
+                        tool: PinTool::Npins,
+                        name: "nixpkgs".into(),
+                        old_revision: Some("aaaa".into()),
+                        new_revision: Some("bbbb".into()),
+                    }],
+                },
+            },
+            Event::Completed {
+                nix_file: nix_file.clone(),
+                rooted_output_paths: OutputPath {
+                    shell_gc_root: RootPath(
+                        AbsPathBuf::new(PathBuf::from("/gc_root/shell_gc_root")).unwrap(),
+                    ),
+                },
+            },
+            Event::Failure {
+                nix_file: nix_file.clone(),
+                failure: exit,
+            },
+            Event::Retrying {
+                nix_file: nix_file.clone(),
+                attempt: 1,
+                failure: BuildError::Timeout {
+                    cmd: "\"nix-build\"".into(),
+                    timeout: Duration::from_secs(600),
+                    logs: vec![],
+                    referenced_paths: vec![],
+                },
+            },
+            Event::Crashed {
+                nix_file,
+                reason: "the build loop panicked".into(),
+                restart_in_secs: 5,
+            },
+            Event::SectionEnd,
+        ]
+    }
+
+    /// The output of `lorri events --json --format-version <version> --kind all`.
+    fn render_all(version: FormatVersion) -> String {
+        let time = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        events()
+            .into_iter()
+            .filter_map(|ev| render(Output::Json(version), ev, time))
+            .map(|line| line + "\n")
+            .collect()
+    }
+
+    #[test]
+    fn format_version_1_matches_golden_file() {
+        assert_eq!(
+            render_all(FormatVersion::V1),
+            include_str!("./stream_events/v1.jsonl")
+        );
+    }
+
+    #[test]
+    fn format_version_0_matches_golden_file() {
+        assert_eq!(
+            render_all(FormatVersion::V0),
+            include_str!("./stream_events/v0.jsonl")
+        );
+    }
+
+    #[test]
+    fn non_utf8_paths_are_encoded_lossily() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        let event = Event::Started {
+            nix_file: NixFile::from(AbsPathBuf::new(PathBuf::from("/p/shell.nix")).unwrap()),
+            reason: ReasonI::FilesChanged(vec![PathBuf::from(OsStr::from_bytes(b"/p/\xff.nix"))]),
+        };
+        assert!(encode(FormatVersion::V0, event.clone(), UNIX_EPOCH)
+            .contains("{\"FilesChanged\":[\"/p/\u{fffd}.nix\"]}"));
+        assert!(encode(FormatVersion::V1, event, UNIX_EPOCH)
+            .contains("\"files\":[\"/p/\u{fffd}.nix\"]"));
+    }
+
+    #[test]
+    fn times_are_printed_in_utc() {
+        assert_eq!(
+            utc_time(UNIX_EPOCH + Duration::from_millis(1_700_000_000_123)),
+            "2023-11-14T22:13:20Z"
+        );
+    }
+
+    #[test]
+    fn human_output_is_one_line_per_event() {
+        let lines: Vec<String> = events()
+            .into_iter()
+            .map(|ev| {
+                render(Output::Human { colour: false }, ev, UNIX_EPOCH)
+                    .unwrap()
+                    .strip_prefix("1970-01-01T00:00:00Z ")
+                    .unwrap()
+                    .to_string()
+            })
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                "/p/shell.nix building (project added)",
+                "/p/shell.nix building (ping received)",
+                "/p/shell.nix building (changed: /p/default.nix)",
+                "/p/shell.nix building (pins changed: nixpkgs (npins): aaaa -> bbbb)",
+                "/p/shell.nix built",
+                "/p/shell.nix failed: error: undefined variable 'foo'",
+                "/p/shell.nix retrying (attempt 1): Nix process did not finish within 600s and was killed.",
+                "/p/shell.nix crashed, restarting in 5s: the build loop panicked",
+                "(live events follow)",
+            ]
+        );
+        assert_eq!(
+            render(
+                Output::Json(FormatVersion::V0),
+                Event::SectionEnd,
+                UNIX_EPOCH
+            ),
+            None
+        );
+    }
+}
@@ -1,68 +1,134 @@
 //! Given a list of paths, reduce them to a minimum set of paths
 //! which should be watched for changes.
+//!
+//! Every rule is a `PathReducer`; `PathReducers` is the ordered list
+//! of rules applied to a project. The first rule with an opinion wins.
 
 use crate::watch::WatchPathBuf;
 use std::collections::HashSet;
 use std::path::{Path, PathBuf};
 
+/// What to do with a path.
 #[derive(PartialEq, Debug)]
-enum PathReduction {
+pub enum PathReduction {
+    /// Watch this path instead.
     Reduced(WatchPathBuf),
+    /// Keep watching the path, and also watch these paths.
+    KeepWith(Vec<WatchPathBuf>),
+    /// Don’t watch the path at all.
     Remove,
 }
 
-#[derive(Debug)]
-enum ReductionOp {
+/// The result of a single `PathReducer`.
+#[derive(Debug, PartialEq)]
+pub enum ReductionOp {
+    /// The rule decided what to do with the path.
     Reduction(PathReduction),
+    /// The rule does not apply to the path, ask the next one.
     NoOpinion,
 }
 
-impl PathReduction {
-    fn unwrap(self, msg: &'static str) -> WatchPathBuf {
-        match self {
-            PathReduction::Reduced(p) => p,
-            _ => panic!("{:?}", msg),
+/// A rule for reducing a path.
+pub trait PathReducer: Send + Sync {
+    /// Short name of the rule, for logging.
+    fn name(&self) -> &'static str;
+
+    /// Decide what to do with `path`.
+    fn reduce(&self, path: &WatchPathBuf) -> ReductionOp;
+}
+
+/// Ordered registry of `PathReducer` rules.
+pub struct PathReducers {
+    reducers: Vec<Box<dyn PathReducer>>,
+}
+
+impl PathReducers {
+    /// A registry without any rules. Every path is kept as-is.
+    pub fn empty() -> PathReducers {
+        PathReducers { reducers: vec![] }
+    }
+
+    /// The rules lorri applies to every project.
+    ///
+    /// `nix_path` is the `NIX_PATH` the evaluation runs with.
+    pub fn builtin(nix_path: Option<&str>) -> PathReducers {
+        let mut reducers = PathReducers::empty();
+        reducers.register(ChannelPath);
+        if let Some(defexpr) = NixDefexpr::from_env() {
+            reducers.register(defexpr);
+        }
+        reducers.register(NixStorePath);
+        if let Some(flake_registry) = FlakeRegistry::from_env() {
+            reducers.register(flake_registry);
         }
+        if let Some(nix_path) = nix_path {
+            reducers.register(NixPathGitCheckouts::from_nix_path(nix_path));
+        }
+        reducers.register(Niv);
+        reducers.register(Npins);
+        reducers
     }
-}
 
-/// Reduce one list of paths to another list of paths.
-pub fn reduce_paths(paths: &[WatchPathBuf]) -> HashSet<WatchPathBuf> {
-    let mut reduced = paths
-        .iter()
-        .map(|path| {
-            let reducers = &[reduce_channel_path, reduce_nix_store_path];
-
-            for reducer in reducers {
-                match reducer(path) {
-                    ReductionOp::Reduction(r) => {
-                        return r;
-                    }
-                    ReductionOp::NoOpinion => {
-                        // next
-                    }
+    /// Add a rule. It is consulted after all rules registered before it.
+    pub fn register<R: PathReducer + 'static>(&mut self, reducer: R) -> &mut PathReducers {
+        self.reducers.push(Box::new(reducer));
+        self
+    }
+
+    /// Add a rule that is consulted before all rules registered so far.
+    pub fn register_first<R: PathReducer + 'static>(&mut self, reducer: R) -> &mut PathReducers {
+        self.reducers.insert(0, Box::new(reducer));
+        self
+    }
+
+    /// Names of the registered rules, in order.
+    pub fn names(&self) -> Vec<&'static str> {
+        self.reducers.iter().map(|r| r.name()).collect()
+    }
+
+    fn reduce(&self, path: &WatchPathBuf) -> PathReduction {
+        for reducer in &self.reducers {
+            match reducer.reduce(path) {
+                ReductionOp::Reduction(r) => {
+                    return r;
+                }
+                ReductionOp::NoOpinion => {
+                    // next
                 }
             }
+        }
 
-            // Default: return a noop reduction
-            PathReduction::Reduced(path.clone())
-        })
-        .filter(|reduction| reduction != &PathReduction::Remove)
-        .map(|reduction| reduction.unwrap("previous filter got them"))
-        .collect::<Vec<WatchPathBuf>>();
-
-    // Sort by length so we automatically select project roots when
-    // possible, in the next fold.
-    reduced.sort();
-    reduced.dedup();
-    reduced
-        .into_iter()
-        .fold::<HashSet<WatchPathBuf>, _>(HashSet::new(), |mut set, new_path| {
-            if !set.iter().any(|path| new_path.as_ref().starts_with(path)) {
-                set.insert(new_path);
-            }
-            set
-        })
+        // Default: return a noop reduction
+        PathReduction::Reduced(path.clone())
+    }
+
+    /// Reduce one list of paths to another list of paths.
+    pub fn reduce_paths(&self, paths: &[WatchPathBuf]) -> HashSet<WatchPathBuf> {
+        let mut reduced = paths
+            .iter()
+            .flat_map(|path| match self.reduce(path) {
+                PathReduction::Reduced(p) => vec![p],
+                PathReduction::KeepWith(mut also) => {
+                    also.insert(0, path.clone());
+                    also
+                }
+                PathReduction::Remove => vec![],
+            })
+            .collect::<Vec<WatchPathBuf>>();
+
+        // Sort by length so we automatically select project roots when
+        // possible, in the next fold.
+        reduced.sort();
+        reduced.dedup();
+        reduced
+            .into_iter()
+            .fold::<HashSet<WatchPathBuf>, _>(HashSet::new(), |mut set, new_path| {
+                if !set.iter().any(|path| new_path.as_ref().starts_with(path)) {
+                    set.insert(new_path);
+                }
+                set
+            })
+    }
 }
 
 /// Reduce a path coming from a user's channel to the location where
@@ -106,6 +172,18 @@ pub fn reduce_paths(paths: &[WatchPathBuf]) -> HashSet<WatchPathBuf> {
 ///    (C) it never changes.
 ///
 /// (E) Sub-path to exactly what file was looked at.
+pub struct ChannelPath;
+
+impl PathReducer for ChannelPath {
+    fn name(&self) -> &'static str {
+        "channel"
+    }
+
+    fn reduce(&self, path: &WatchPathBuf) -> ReductionOp {
+        reduce_channel_path(path)
+    }
+}
+
 fn reduce_channel_path(path: &WatchPathBuf) -> ReductionOp {
     let nix_profile = Path::new("/nix/var/nix/profiles/per-user");
 
@@ -151,6 +229,18 @@ fn reduce_channel_path(path: &WatchPathBuf) -> ReductionOp {
 ///
 /// Note that because store paths are immutable, these paths can
 /// be discarded.
+pub struct NixStorePath;
+
+impl PathReducer for NixStorePath {
+    fn name(&self) -> &'static str {
+        "nix-store"
+    }
+
+    fn reduce(&self, path: &WatchPathBuf) -> ReductionOp {
+        reduce_nix_store_path(path)
+    }
+}
+
 fn reduce_nix_store_path(path: &WatchPathBuf) -> ReductionOp {
     let nix_store = Path::new("/nix/store");
 
@@ -172,3 +262,523 @@ fn reduce_nix_store_path(path: &WatchPathBuf) -> ReductionOp {
 
     ReductionOp::NoOpinion
 }
+
+/// Reduce paths reached through `~/.nix-defexpr` (the default `NIX_PATH`
+/// entry of `nix-env` and `nix-channel`) to the profile directory the
+/// channel is selected in.
+///
+/// `~/.nix-defexpr/channels` and `~/.nix-defexpr/channels_root` are
+/// symlinks to the `channels` profiles of the user and of root,
+/// e.g. `/nix/var/nix/profiles/per-user/root/channels`. That profile is a
+/// symlink which is swapped on `nix-channel --update`, so we watch the
+/// directory containing it (see `ChannelPath`).
+pub struct NixDefexpr {
+    defexpr: PathBuf,
+}
+
+impl NixDefexpr {
+    /// Rule for the given `.nix-defexpr` directory.
+    pub fn new(defexpr: PathBuf) -> NixDefexpr {
+        NixDefexpr { defexpr }
+    }
+
+    /// Rule for the `~/.nix-defexpr` of the current user.
+    pub fn from_env() -> Option<NixDefexpr> {
+        let dirs = directories::BaseDirs::new()?;
+        Some(NixDefexpr::new(dirs.home_dir().join(".nix-defexpr")))
+    }
+}
+
+impl PathReducer for NixDefexpr {
+    fn name(&self) -> &'static str {
+        "nix-defexpr"
+    }
+
+    fn reduce(&self, path: &WatchPathBuf) -> ReductionOp {
+        let entry = match path.as_ref().strip_prefix(&self.defexpr) {
+            Ok(rest) => match rest.iter().next() {
+                Some(entry) => self.defexpr.join(entry),
+                None => return ReductionOp::NoOpinion,
+            },
+            Err(_) => return ReductionOp::NoOpinion,
+        };
+        let profile = match std::fs::read_link(&entry) {
+            Ok(profile) => entry.parent().unwrap_or(&self.defexpr).join(profile),
+            Err(_) => return ReductionOp::NoOpinion,
+        };
+        // only a profile (itself a symlink to the current generation) is swapped on update
+        let is_profile = std::fs::symlink_metadata(&profile)
+            .map(|m| m.file_type().is_symlink())
+            .unwrap_or(false);
+        match profile.parent() {
+            Some(profile_dir) if is_profile => ReductionOp::Reduction(PathReduction::Reduced(
+                WatchPathBuf::Normal(profile_dir.to_owned()),
+            )),
+            _ => ReductionOp::NoOpinion,
+        }
+    }
+}
+
+/// Reduce paths in nix’s flake cache (`$XDG_CACHE_HOME/nix`) to the
+/// user’s flake registry (`$XDG_CONFIG_HOME/nix/registry.json`).
+///
+/// The cache contains fetched trees and the downloaded global
+/// registry; it changes on every evaluation, but the result of the
+/// evaluation only changes when the user points a registry entry
+/// somewhere else.
+pub struct FlakeRegistry {
+    cache_dir: PathBuf,
+    registry: PathBuf,
+}
+
+impl FlakeRegistry {
+    /// Rule for nix’s cache directory and user registry file.
+    pub fn new(cache_dir: PathBuf, registry: PathBuf) -> FlakeRegistry {
+        FlakeRegistry {
+            cache_dir,
+            registry,
+        }
+    }
+
+    /// Rule for the directories of the current user.
+    pub fn from_env() -> Option<FlakeRegistry> {
+        let dirs = directories::BaseDirs::new()?;
+        Some(FlakeRegistry::new(
+            dirs.cache_dir().join("nix"),
+            dirs.config_dir().join("nix").join("registry.json"),
+        ))
+    }
+}
+
+impl PathReducer for FlakeRegistry {
+    fn name(&self) -> &'static str {
+        "flake-registry"
+    }
+
+    fn reduce(&self, path: &WatchPathBuf) -> ReductionOp {
+        if !path.as_ref().starts_with(&self.cache_dir) {
+            return ReductionOp::NoOpinion;
+        }
+        if self.registry.is_file() {
+            ReductionOp::Reduction(PathReduction::Reduced(WatchPathBuf::Normal(
+                self.registry.clone(),
+            )))
+        } else {
+            ReductionOp::Reduction(PathReduction::Remove)
+        }
+    }
+}
+
+/// Watch the checked-out revision of git checkouts listed in `NIX_PATH`
+/// (e.g. `nixpkgs=/home/user/src/nixpkgs`).
+///
+/// The files nix read from the checkout stay watched, so that edits to the
+/// working tree are picked up. In addition, `.git/HEAD` and the file of the
+/// currently checked-out ref are watched, because switching branches or
+/// committing does not necessarily touch the files that were read.
+/// The rest of `.git` (index, lock files, `FETCH_HEAD`, …) changes all the
+/// time without affecting the evaluation, so it is not watched.
+pub struct NixPathGitCheckouts {
+    checkouts: Vec<PathBuf>,
+}
+
+impl NixPathGitCheckouts {
+    /// Rule for the given checkout roots.
+    pub fn new(checkouts: Vec<PathBuf>) -> NixPathGitCheckouts {
+        NixPathGitCheckouts { checkouts }
+    }
+
+    /// Rule for all entries of a `NIX_PATH` value which are git checkouts.
+    ///
+    /// Entries are either `prefix=path` or `path`; URLs are ignored.
+    pub fn from_nix_path(nix_path: &str) -> NixPathGitCheckouts {
+        let checkouts = nix_path
+            .split(':')
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| match entry.find('=') {
+                Some(i) => &entry[i + 1..],
+                None => entry,
+            })
+            // `https://…` is split at the `:`, so URLs end up as `https` and `//…`
+            .filter(|path| path.starts_with('/') && !path.starts_with("//"))
+            .map(PathBuf::from)
+            .filter(|path| path.join(".git").exists())
+            .collect();
+        NixPathGitCheckouts::new(checkouts)
+    }
+}
+
+impl PathReducer for NixPathGitCheckouts {
+    fn name(&self) -> &'static str {
+        "nix-path-git-checkout"
+    }
+
+    fn reduce(&self, path: &WatchPathBuf) -> ReductionOp {
+        match self
+            .checkouts
+            .iter()
+            .find(|checkout| path.as_ref().starts_with(checkout))
+        {
+            Some(checkout) => ReductionOp::Reduction(PathReduction::KeepWith(
+                git_head_files(checkout)
+                    .into_iter()
+                    .map(WatchPathBuf::Normal)
+                    .collect(),
+            )),
+            None => ReductionOp::NoOpinion,
+        }
+    }
+}
+
+/// `HEAD` of the checkout’s git directory, plus the file of the ref it points to.
+///
+/// Refs that only exist in `packed-refs` are watched through that file.
+fn git_head_files(checkout: &Path) -> Vec<PathBuf> {
+    let dot_git = checkout.join(".git");
+    // in worktrees and submodules, `.git` is a file pointing to the git directory
+    let git_dir = match std::fs::read_to_string(&dot_git) {
+        Ok(content) => match content.trim().strip_prefix("gitdir:") {
+            Some(dir) => checkout.join(dir.trim()),
+            None => return vec![],
+        },
+        Err(_) => dot_git,
+    };
+    let head = git_dir.join("HEAD");
+    let mut files = vec![];
+    if let Ok(content) = std::fs::read_to_string(&head) {
+        if let Some(reference) = content.trim().strip_prefix("ref:") {
+            let ref_file = git_dir.join(reference.trim());
+            if ref_file.is_file() {
+                files.push(ref_file);
+            } else {
+                files.push(git_dir.join("packed-refs"));
+            }
+        }
+        files.insert(0, head);
+    }
+    files
+}
+
+/// Watch niv’s `nix/sources.json` together with `nix/sources.nix`.
+///
+/// `sources.nix` is generated by niv and interprets the pins in
+/// `sources.json`, which is what changes on `niv update`. Changes to the
+/// fetcher code in `sources.nix` itself must trigger a rebuild as well.
+pub struct Niv;
+
+impl PathReducer for Niv {
+    fn name(&self) -> &'static str {
+        "niv"
+    }
+
+    fn reduce(&self, path: &WatchPathBuf) -> ReductionOp {
+        reduce_to_sibling_sources_json(path, "nix", "sources.nix")
+    }
+}
+
+/// Watch npins’ `npins/sources.json` together with `npins/default.nix`.
+pub struct Npins;
+
+impl PathReducer for Npins {
+    fn name(&self) -> &'static str {
+        "npins"
+    }
+
+    fn reduce(&self, path: &WatchPathBuf) -> ReductionOp {
+        reduce_to_sibling_sources_json(path, "npins", "default.nix")
+    }
+}
+
+/// `<dir_name>/<file_name>` → also `<dir_name>/sources.json`, if the latter exists.
+fn reduce_to_sibling_sources_json(
+    path: &WatchPathBuf,
+    dir_name: &str,
+    file_name: &str,
+) -> ReductionOp {
+    let path_ref = path.as_ref();
+    let dir = match path_ref.parent() {
+        Some(dir) => dir,
+        None => return ReductionOp::NoOpinion,
+    };
+    if path_ref.file_name() != Some(file_name.as_ref())
+        || dir.file_name() != Some(dir_name.as_ref())
+    {
+        return ReductionOp::NoOpinion;
+    }
+    let sources_json = dir.join("sources.json");
+    if sources_json.is_file() {
+        ReductionOp::Reduction(PathReduction::KeepWith(vec![WatchPathBuf::Normal(
+            sources_json,
+        )]))
+    } else {
+        ReductionOp::NoOpinion
+    }
+}
+
+/// A project-defined rule: reduce everything under `under` to `to`,
+/// or stop watching it if `to` is `None`.
+pub struct UserRule {
+    under: PathBuf,
+    to: Option<PathBuf>,
+}
+
+impl UserRule {
+    /// Rule for absolute paths.
+    pub fn new(under: PathBuf, to: Option<PathBuf>) -> UserRule {
+        UserRule { under, to }
+    }
+}
+
+impl PathReducer for UserRule {
+    fn name(&self) -> &'static str {
+        "user"
+    }
+
+    fn reduce(&self, path: &WatchPathBuf) -> ReductionOp {
+        if !path.as_ref().starts_with(&self.under) {
+            return ReductionOp::NoOpinion;
+        }
+        match &self.to {
+            Some(to) => {
+                ReductionOp::Reduction(PathReduction::Reduced(WatchPathBuf::Normal(to.clone())))
+            }
+            None => ReductionOp::Reduction(PathReduction::Remove),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn normal(path: &Path) -> WatchPathBuf {
+        WatchPathBuf::Normal(path.to_path_buf())
+    }
+
+    fn reduced(path: &Path) -> ReductionOp {
+        ReductionOp::Reduction(PathReduction::Reduced(normal(path)))
+    }
+
+    fn keep_with(paths: &[PathBuf]) -> ReductionOp {
+        ReductionOp::Reduction(PathReduction::KeepWith(
+            paths.iter().map(|p| normal(p)).collect(),
+        ))
+    }
+
+    #[test]
+    fn nix_store_rule_ignores_other_paths() {
+        assert_eq!(
+            NixStorePath.reduce(&normal(Path::new("/home/user/shell.nix"))),
+            ReductionOp::NoOpinion
+        );
+    }
+
+    #[test]
+    fn nix_defexpr_reduces_to_profile_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let defexpr = dir.path().join(".nix-defexpr");
+        let profiles = dir.path().join("profiles/per-user/root");
+        let generation = dir.path().join("store/abc-user-environment");
+        fs::create_dir_all(&defexpr).unwrap();
+        fs::create_dir_all(&profiles).unwrap();
+        fs::create_dir_all(generation.join("nixpkgs")).unwrap();
+        std::os::unix::fs::symlink(&generation, profiles.join("channels-1-link")).unwrap();
+        std::os::unix::fs::symlink("channels-1-link", profiles.join("channels")).unwrap();
+        std::os::unix::fs::symlink(profiles.join("channels"), defexpr.join("channels_root"))
+            .unwrap();
+        std::os::unix::fs::symlink(&generation, defexpr.join("not_a_profile")).unwrap();
+        let rule = NixDefexpr::new(defexpr.clone());
+
+        assert_eq!(
+            rule.reduce(&normal(&defexpr.join("channels_root/nixpkgs/default.nix"))),
+            reduced(&profiles)
+        );
+        assert_eq!(
+            rule.reduce(&normal(&defexpr.join("not_a_profile/nixpkgs/default.nix"))),
+            ReductionOp::NoOpinion
+        );
+        assert_eq!(
+            rule.reduce(&normal(&dir.path().join("shell.nix"))),
+            ReductionOp::NoOpinion
+        );
+    }
+
+    #[test]
+    fn flake_cache_reduces_to_registry() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = dir.path().join("cache/nix");
+        let registry = dir.path().join("config/nix/registry.json");
+        let rule = FlakeRegistry::new(cache.clone(), registry.clone());
+        let cached = normal(&cache.join("flake-registry.json"));
+
+        assert_eq!(
+            rule.reduce(&cached),
+            ReductionOp::Reduction(PathReduction::Remove)
+        );
+        fs::create_dir_all(registry.parent().unwrap()).unwrap();
+        fs::write(&registry, "{}").unwrap();
+        assert_eq!(rule.reduce(&cached), reduced(&registry));
+        assert_eq!(
+            rule.reduce(&normal(&dir.path().join("shell.nix"))),
+            ReductionOp::NoOpinion
+        );
+    }
+
+    #[test]
+    fn nix_path_git_checkouts() {
+        let dir = tempfile::tempdir().unwrap();
+        let checkout = dir.path().join("nixpkgs");
+        let git_dir = checkout.join(".git");
+        let plain = dir.path().join("plain");
+        fs::create_dir_all(git_dir.join("refs/heads")).unwrap();
+        fs::create_dir_all(checkout.join("lib")).unwrap();
+        fs::create_dir_all(&plain).unwrap();
+        fs::write(git_dir.join("HEAD"), "ref: refs/heads/master\n").unwrap();
+        fs::write(git_dir.join("refs/heads/master"), "abcd\n").unwrap();
+        fs::write(git_dir.join("index"), "").unwrap();
+        let nix_path = format!(
+            "nixpkgs={}:{}:foo=https://example.com/foo.tar.gz:",
+            checkout.display(),
+            plain.display()
+        );
+
+        let rule = NixPathGitCheckouts::from_nix_path(&nix_path);
+        assert_eq!(rule.checkouts, vec![checkout.clone()]);
+        let read = checkout.join("lib/default.nix");
+        let head_files = [git_dir.join("HEAD"), git_dir.join("refs/heads/master")];
+        assert_eq!(rule.reduce(&normal(&read)), keep_with(&head_files));
+        assert_eq!(
+            rule.reduce(&normal(&plain.join("default.nix"))),
+            ReductionOp::NoOpinion
+        );
+
+        // the working tree file stays watched, git’s internal files are not watched
+        let mut reducers = PathReducers::empty();
+        reducers.register(rule);
+        assert_eq!(
+            reducers.reduce_paths(&[normal(&read)]),
+            vec![
+                normal(&read),
+                normal(&head_files[0]),
+                normal(&head_files[1])
+            ]
+            .into_iter()
+            .collect()
+        );
+
+        // a ref that was packed by `git gc`
+        fs::remove_file(git_dir.join("refs/heads/master")).unwrap();
+        assert_eq!(
+            git_head_files(&checkout),
+            vec![git_dir.join("HEAD"), git_dir.join("packed-refs")]
+        );
+
+        // a detached HEAD
+        fs::write(git_dir.join("HEAD"), "abcd\n").unwrap();
+        assert_eq!(git_head_files(&checkout), vec![git_dir.join("HEAD")]);
+    }
+
+    #[test]
+    fn git_worktree_head_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let worktree = dir.path().join("worktree");
+        let git_dir = dir.path().join("repo/.git/worktrees/worktree");
+        fs::create_dir_all(git_dir.join("refs/heads")).unwrap();
+        fs::create_dir_all(&worktree).unwrap();
+        fs::write(
+            worktree.join(".git"),
+            format!("gitdir: {}\n", git_dir.display()),
+        )
+        .unwrap();
+        fs::write(git_dir.join("HEAD"), "ref: refs/heads/feature\n").unwrap();
+        fs::write(git_dir.join("refs/heads/feature"), "abcd\n").unwrap();
+
+        assert_eq!(
+            git_head_files(&worktree),
+            vec![git_dir.join("HEAD"), git_dir.join("refs/heads/feature")]
+        );
+    }
+
+    #[test]
+    fn niv_and_npins_also_watch_sources_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let niv = dir.path().join("nix");
+        let npins = dir.path().join("npins");
+        fs::create_dir_all(&niv).unwrap();
+        fs::create_dir_all(&npins).unwrap();
+
+        // without a sources.json, the rules don’t apply
+        assert_eq!(
+            Niv.reduce(&normal(&niv.join("sources.nix"))),
+            ReductionOp::NoOpinion
+        );
+
+        fs::write(niv.join("sources.json"), "{}").unwrap();
+        fs::write(npins.join("sources.json"), "{}").unwrap();
+        assert_eq!(
+            Niv.reduce(&normal(&niv.join("sources.nix"))),
+            keep_with(&[niv.join("sources.json")])
+        );
+        assert_eq!(
+            Npins.reduce(&normal(&npins.join("default.nix"))),
+            keep_with(&[npins.join("sources.json")])
+        );
+        assert_eq!(
+            Npins.reduce(&normal(&niv.join("sources.nix"))),
+            ReductionOp::NoOpinion
+        );
+        assert_eq!(
+            Niv.reduce(&normal(&niv.join("default.nix"))),
+            ReductionOp::NoOpinion
+        );
+    }
+
+    #[test]
+    fn user_rules() {
+        let vendor = Path::new("/project/vendor");
+        let lockfile = vendor.join("lockfile");
+        let reduce = UserRule::new(vendor.to_path_buf(), Some(lockfile.clone()));
+        let remove = UserRule::new(vendor.to_path_buf(), None);
+        let vendored = normal(&vendor.join("foo/default.nix"));
+
+        assert_eq!(reduce.reduce(&vendored), reduced(&lockfile));
+        assert_eq!(
+            remove.reduce(&vendored),
+            ReductionOp::Reduction(PathReduction::Remove)
+        );
+        assert_eq!(
+            reduce.reduce(&normal(Path::new("/project/shell.nix"))),
+            ReductionOp::NoOpinion
+        );
+    }
+
+    #[test]
+    fn first_rule_with_an_opinion_wins() {
+        let project = Path::new("/project");
+        let mut reducers = PathReducers::empty();
+        reducers
+            .register(UserRule::new(project.join("vendor"), None))
+            .register_first(UserRule::new(
+                project.join("vendor/keep"),
+                Some(project.join("vendor/keep/lock")),
+            ));
+        assert_eq!(reducers.names(), vec!["user", "user"]);
+
+        let paths = reducers.reduce_paths(&[
+            normal(&project.join("shell.nix")),
+            normal(&project.join("vendor/a.nix")),
+            normal(&project.join("vendor/keep/a.nix")),
+            normal(&project.join("vendor/keep/b.nix")),
+        ]);
+        assert_eq!(
+            paths,
+            vec![
+                normal(&project.join("shell.nix")),
+                normal(&project.join("vendor/keep/lock")),
+            ]
+            .into_iter()
+            .collect()
+        );
+    }
+}
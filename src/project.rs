@@ -1,5 +1,8 @@
 //! Wrap a nix file and manage corresponding state.
 
+pub mod config;
+pub mod pins;
+
 use thiserror::Error;
 
 use crate::builder::{OutputPath, RootedPath};
@@ -22,6 +25,10 @@ pub struct Project {
     /// garbage collection roots are stored.
     gc_root_path: AbsPathBuf,
 
+    /// Directory for the rest of this project’s state.
+    /// It contains `gc_root_path`, which must only contain symlinks.
+    state_path: AbsPathBuf,
+
     /// Hash of the nix file’s absolute path.
     hash: String,
 
@@ -32,26 +39,42 @@ pub struct Project {
 impl Project {
     /// The name for the build output that's sourced in direnv to produce environment variables
     pub const ENV_CONTEXT: &'static str = "shell_gc_root";
+    /// The file the `NIX_PATH` of the last build is recorded in
+    const NIX_PATH_FILE: &'static str = "nix_path";
     /// Construct a `Project` from nix file path
     /// and the base GC root directory
-    /// (as returned by `Paths.gc_root_dir()`),
-    pub fn new(
-        nix_file: NixFile,
-        gc_root_dir: &AbsPathBuf,
-        cas: ContentAddressable,
-    ) -> std::io::Result<Project> {
+    /// (as returned by `Paths.gc_root_dir()`).
+    ///
+    /// Nothing is written until the project is built, see `create_gc_root_dir`.
+    pub fn new(nix_file: NixFile, gc_root_dir: &AbsPathBuf, cas: ContentAddressable) -> Project {
         let hash = format!(
             "{:x}",
             md5::compute(nix_file.as_absolute_path().as_os_str().as_bytes())
         );
-        let project_gc_root = gc_root_dir.join(&hash).join("gc_root");
+        let state_path = gc_root_dir.join(&hash);
+        let project_gc_root = state_path.join("gc_root");
+
+        Project {
+            nix_file,
+            gc_root_path: project_gc_root,
+            state_path,
+            hash,
+            cas,
+        }
+    }
 
-        std::fs::create_dir_all(&project_gc_root)?;
+    /// Create the GC root directory of this project (and the base GC root directory),
+    /// and link the nix file from it, for `lorri gc`.
+    ///
+    /// Only whoever builds the project needs to, clients of a shared daemon
+    /// can’t write to its GC root directory.
+    pub fn create_gc_root_dir(&self) -> std::io::Result<()> {
+        std::fs::create_dir_all(&self.gc_root_path)?;
 
-        let nix_file_symlink = project_gc_root.clone().join("nix_file");
+        let nix_file_symlink = self.gc_root_path.join("nix_file");
         let (remove, create) = match std::fs::read_link(&nix_file_symlink) {
             Ok(path) => {
-                if path == nix_file.as_absolute_path() {
+                if path == self.nix_file.as_absolute_path() {
                     (false, false)
                 } else {
                     (true, true)
@@ -69,15 +92,9 @@ impl Project {
             std::fs::remove_file(&nix_file_symlink)?;
         }
         if create {
-            std::os::unix::fs::symlink(nix_file.as_absolute_path(), nix_file_symlink)?;
+            std::os::unix::fs::symlink(self.nix_file.as_absolute_path(), nix_file_symlink)?;
         }
-
-        Ok(Project {
-            nix_file,
-            gc_root_path: project_gc_root,
-            hash,
-            cas,
-        })
+        Ok(())
     }
 
     /// Generate a "unique" ID for this project based on its absolute path.
@@ -98,6 +115,29 @@ impl Project {
         }
     }
 
+    /// Remember the `NIX_PATH` the last build of this project was evaluated with.
+    ///
+    /// It is kept next to the GC roots, not among them, since `nix-store --gc`
+    /// and `lorri gc` expect only symlinks there.
+    pub fn record_nix_path(&self, nix_path: Option<&str>) -> std::io::Result<()> {
+        let file = self.state_path.join(Self::NIX_PATH_FILE);
+        match nix_path {
+            Some(nix_path) => {
+                self.create_gc_root_dir()?;
+                std::fs::write(file.as_path(), nix_path)
+            }
+            None => match std::fs::remove_file(file.as_path()) {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                res => res,
+            },
+        }
+    }
+
+    /// The `NIX_PATH` recorded by `record_nix_path`, if any.
+    pub fn recorded_nix_path(&self) -> Option<String> {
+        std::fs::read_to_string(self.state_path.join(Self::NIX_PATH_FILE).as_path()).ok()
+    }
+
     /// Create roots to store paths.
     pub fn create_roots(
         &self,
@@ -111,6 +151,9 @@ impl Project {
         base_name: PathBuf,
         store_path: StorePath,
     ) -> Result<OutputPath<RootPath>, AddRootError> {
+        self.create_gc_root_dir()
+            .map_err(|e| AddRootError::gc_root_dir_error(e, self.gc_root_path.as_path()))?;
+
         // nix-store --add-root /tmp/test-root --realise
         let mut cmd = Command::new("nix-store");
         cmd.args([
@@ -167,6 +210,13 @@ pub struct AddRootError {
 }
 
 impl AddRootError {
+    fn gc_root_dir_error(source: std::io::Error, path: &Path) -> AddRootError {
+        AddRootError {
+            source,
+            msg: format!("could not create the GC root directory {}", path.display()),
+        }
+    }
+
     fn nix_run_error(source: std::io::Error, path: &Path) -> AddRootError {
         AddRootError {
             source,
@@ -181,3 +231,41 @@ impl AddRootError {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Project;
+    use crate::cas::ContentAddressable;
+    use crate::{AbsPathBuf, NixFile};
+
+    #[test]
+    fn nix_path_is_recorded_outside_the_gc_roots() {
+        let dir = tempfile::tempdir().unwrap();
+        let shell_nix = dir.path().join("shell.nix");
+        std::fs::write(&shell_nix, "{}").unwrap();
+        let project = Project::new(
+            NixFile::from(AbsPathBuf::new(shell_nix).unwrap()),
+            &AbsPathBuf::new(dir.path().join("gc_roots")).unwrap(),
+            ContentAddressable::new(AbsPathBuf::new(dir.path().join("cas")).unwrap()).unwrap(),
+        );
+        assert!(
+            !dir.path().join("gc_roots").exists(),
+            "only the build creates the GC root directory"
+        );
+
+        project
+            .record_nix_path(Some("nixpkgs=/src/nixpkgs"))
+            .unwrap();
+        assert_eq!(
+            project.recorded_nix_path().as_deref(),
+            Some("nixpkgs=/src/nixpkgs")
+        );
+        for entry in std::fs::read_dir(project.gc_root_path.as_path()).unwrap() {
+            let file_type = entry.unwrap().file_type().unwrap();
+            assert!(file_type.is_symlink(), "only GC roots in the GC root dir");
+        }
+
+        project.record_nix_path(None).unwrap();
+        assert_eq!(project.recorded_nix_path(), None);
+    }
+}
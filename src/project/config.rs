@@ -0,0 +1,203 @@
+//! Per-project settings, read from a `.lorri.json` next to the nix file.
+//!
+//! Example:
+//!
+//! ```json
+//! {
+//!   "debounce_ms": 500,
+//!   "quiet_period_ms": 2000,
+//!   "watcher": "poll",
+//!   "reduce": [
+//!     { "under": "vendor", "to": "vendor/lockfile" },
+//!     { "under": "generated", "to": null }
+//!   ]
+//! }
+//! ```
+//!
+//! Every field is optional; missing fields keep the value the daemon was started with.
+//! Paths in `reduce` are relative to the directory of the nix file.
+
+use crate::pathreduction::{PathReducers, UserRule};
+use crate::watch::{WatchBackend, WatchConfig};
+use crate::NixFile;
+use slog::warn;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Settings a project can override.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProjectConfig {
+    /// Debounce window of the file watcher, in milliseconds.
+    pub debounce_ms: Option<u64>,
+    /// Only start a build after this many milliseconds without file changes.
+    pub quiet_period_ms: Option<u64>,
+    /// How to detect file changes: `"auto"`, `"native"` or `"poll"`.
+    pub watcher: Option<WatchBackend>,
+    /// Extra path reduction rules, tried before the builtin ones.
+    #[serde(default)]
+    pub reduce: Vec<ReduceRule>,
+}
+
+/// A path reduction rule defined by the project.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReduceRule {
+    /// Any input below this path …
+    pub under: PathBuf,
+    /// … is watched as this path instead. `null` stops watching it.
+    pub to: Option<PathBuf>,
+}
+
+impl ProjectConfig {
+    /// Name of the config file, looked up in the directory of the nix file.
+    pub const FILE_NAME: &'static str = ".lorri.json";
+
+    /// Path of the config file belonging to `nix_file`.
+    pub fn path(nix_file: &NixFile) -> PathBuf {
+        nix_file.as_absolute_path().with_file_name(Self::FILE_NAME)
+    }
+
+    /// Read the config for `nix_file`.
+    ///
+    /// A missing file is the empty config. An unreadable or invalid file
+    /// is logged and ignored, so that a typo does not stop the project from building.
+    pub fn load(nix_file: &NixFile, logger: &slog::Logger) -> ProjectConfig {
+        let path = Self::path(nix_file);
+        let contents = match std::fs::read(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return ProjectConfig::default(),
+            Err(e) => {
+                warn!(logger, "could not read project config, ignoring"; "path" => path.to_str(), "error" => %e);
+                return ProjectConfig::default();
+            }
+        };
+        match serde_json::from_slice(&contents) {
+            Ok(config) => config,
+            Err(e) => {
+                warn!(logger, "invalid project config, ignoring"; "path" => path.to_str(), "error" => %e);
+                ProjectConfig::default()
+            }
+        }
+    }
+
+    /// Apply the overrides of this project to the daemon-wide watch settings.
+    pub fn watch_config(&self, defaults: WatchConfig) -> WatchConfig {
+        WatchConfig {
+            debounce: self
+                .debounce_ms
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.debounce),
+            quiet_period: self
+                .quiet_period_ms
+                .map(Duration::from_millis)
+                .or(defaults.quiet_period),
+            backend: self.watcher.unwrap_or(defaults.backend),
+        }
+    }
+
+    /// The path reduction rules for `nix_file`: the rules of this project,
+    /// followed by the builtin rules for evaluations with `nix_path`.
+    pub fn path_reducers(&self, nix_file: &NixFile, nix_path: Option<&str>) -> PathReducers {
+        let project_dir = nix_file
+            .as_absolute_path()
+            .parent()
+            .unwrap_or_else(|| Path::new("/"));
+        let mut reducers = PathReducers::builtin(nix_path);
+        for rule in self.reduce.iter().rev() {
+            reducers.register_first(UserRule::new(
+                project_dir.join(&rule.under),
+                rule.to.as_ref().map(|to| project_dir.join(to)),
+            ));
+        }
+        reducers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ProjectConfig;
+    use crate::watch::{WatchBackend, WatchConfig, WatchPathBuf};
+    use crate::{AbsPathBuf, NixFile};
+    use std::time::Duration;
+
+    #[test]
+    fn overrides_only_given_fields() {
+        let defaults = WatchConfig {
+            debounce: Duration::from_millis(200),
+            quiet_period: Some(Duration::from_millis(1000)),
+            backend: WatchBackend::Auto,
+        };
+        let config: ProjectConfig =
+            serde_json::from_str(r#"{ "debounce_ms": 50, "watcher": "poll" }"#).unwrap();
+        assert_eq!(
+            config.watch_config(defaults),
+            WatchConfig {
+                debounce: Duration::from_millis(50),
+                quiet_period: Some(Duration::from_millis(1000)),
+                backend: WatchBackend::Poll,
+            }
+        );
+        assert_eq!(ProjectConfig::default().watch_config(defaults), defaults);
+    }
+
+    #[test]
+    fn load_ignores_missing_and_invalid_files() {
+        let logger = crate::logging::test_logger("project_config");
+        let dir = tempfile::tempdir().unwrap();
+        let nix_file = NixFile::from(AbsPathBuf::new(dir.path().join("shell.nix")).unwrap());
+
+        assert_eq!(
+            ProjectConfig::load(&nix_file, &logger),
+            ProjectConfig::default()
+        );
+
+        std::fs::write(ProjectConfig::path(&nix_file), r#"{ "debounce": 50 }"#).unwrap();
+        assert_eq!(
+            ProjectConfig::load(&nix_file, &logger),
+            ProjectConfig::default()
+        );
+
+        std::fs::write(
+            ProjectConfig::path(&nix_file),
+            r#"{ "quiet_period_ms": 3000 }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            ProjectConfig::load(&nix_file, &logger),
+            ProjectConfig {
+                quiet_period_ms: Some(3000),
+                ..ProjectConfig::default()
+            }
+        );
+    }
+
+    #[test]
+    fn reduce_rules_are_relative_to_the_project() {
+        let nix_file = NixFile::from(AbsPathBuf::new("/project/shell.nix".into()).unwrap());
+        let config: ProjectConfig = serde_json::from_str(
+            r#"{ "reduce": [
+                { "under": "vendor", "to": "vendor/lockfile" },
+                { "under": "generated", "to": null }
+            ] }"#,
+        )
+        .unwrap();
+        let reducers = config.path_reducers(&nix_file, None);
+        assert_eq!(&reducers.names()[..3], &["user", "user", "channel"]);
+
+        let paths = reducers.reduce_paths(&[
+            WatchPathBuf::Normal("/project/shell.nix".into()),
+            WatchPathBuf::Normal("/project/vendor/foo/default.nix".into()),
+            WatchPathBuf::Normal("/project/generated/bar.nix".into()),
+        ]);
+        assert_eq!(
+            paths,
+            vec![
+                WatchPathBuf::Normal("/project/shell.nix".into()),
+                WatchPathBuf::Normal("/project/vendor/lockfile".into()),
+            ]
+            .into_iter()
+            .collect()
+        );
+    }
+}
@@ -0,0 +1,325 @@
+//! Pinned sources of a project, as managed by [niv](https://github.com/nmattia/niv)
+//! (`nix/sources.json`) or [npins](https://github.com/andir/npins) (`npins/sources.json`).
+//!
+//! The pinned sources are fetched into the nix store, so lorri never
+//! watches them; instead we watch the pin files and report which pin changed.
+
+use crate::NixFile;
+use slog::warn;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// The tool that manages a pin file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PinTool {
+    /// `nix/sources.json`
+    Niv,
+    /// `npins/sources.json`
+    Npins,
+}
+
+impl PinTool {
+    /// Name of the tool, as in its command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            PinTool::Niv => "niv",
+            PinTool::Npins => "npins",
+        }
+    }
+
+    /// Location of the pin file, relative to the project directory.
+    pub fn sources_json(self) -> &'static Path {
+        match self {
+            PinTool::Niv => Path::new("nix/sources.json"),
+            PinTool::Npins => Path::new("npins/sources.json"),
+        }
+    }
+
+    /// All tools, with the location of their pin file in the project of `nix_file`.
+    pub fn all(nix_file: &NixFile) -> impl Iterator<Item = (PinTool, PathBuf)> {
+        let project_dir = nix_file
+            .as_absolute_path()
+            .parent()
+            .unwrap_or_else(|| Path::new("/"))
+            .to_owned();
+        [PinTool::Niv, PinTool::Npins]
+            .into_iter()
+            .map(move |tool| (tool, project_dir.join(tool.sources_json())))
+    }
+
+    /// Parse the contents of a pin file of this tool.
+    ///
+    /// Unknown fields are ignored, so that new versions of the tools
+    /// don’t break lorri.
+    pub fn parse(self, contents: &[u8]) -> Result<Vec<Pin>, serde_json::Error> {
+        let json: serde_json::Value = serde_json::from_slice(contents)?;
+        let sources = match self {
+            // { "<name>": { "rev": …, "url": …, … }, … }
+            PinTool::Niv => json.as_object(),
+            // { "pins": { "<name>": { "revision": …, "url": …, … }, … }, "version": … }
+            PinTool::Npins => json.get("pins").and_then(|pins| pins.as_object()),
+        };
+        let field = |source: &serde_json::Value, name: &str| {
+            source
+                .get(name)
+                .and_then(|value| value.as_str())
+                .map(String::from)
+        };
+        let mut pins = sources
+            .into_iter()
+            .flatten()
+            .map(|(name, source)| Pin {
+                name: name.clone(),
+                revision: match self {
+                    PinTool::Niv => field(source, "rev"),
+                    PinTool::Npins => {
+                        field(source, "revision").or_else(|| field(source, "version"))
+                    }
+                },
+                url: field(source, "url"),
+            })
+            .collect::<Vec<_>>();
+        pins.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(pins)
+    }
+}
+
+impl fmt::Display for PinTool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A single pinned source.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pin {
+    /// Name of the source, e.g. `nixpkgs`.
+    pub name: String,
+    /// The pinned revision (a git commit, or a release version).
+    pub revision: Option<String>,
+    /// The URL the source is fetched from.
+    pub url: Option<String>,
+}
+
+/// A pin file and the sources pinned in it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PinFile {
+    /// The tool managing the file.
+    pub tool: PinTool,
+    /// Absolute path of the file.
+    pub path: PathBuf,
+    /// The pinned sources, sorted by name.
+    pub pins: Vec<Pin>,
+}
+
+/// A pin that differs between two versions of a pin file.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangedPin {
+    /// The tool managing the pin.
+    pub tool: PinTool,
+    /// Name of the source.
+    pub name: String,
+    /// Revision before the change, `None` if the pin was added.
+    pub old_revision: Option<String>,
+    /// Revision after the change, `None` if the pin was removed.
+    pub new_revision: Option<String>,
+}
+
+impl fmt::Display for ChangedPin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rev = |rev: &Option<String>| rev.clone().unwrap_or_else(|| "-".to_string());
+        write!(
+            f,
+            "{} ({}): {} -> {}",
+            self.name,
+            self.tool,
+            rev(&self.old_revision),
+            rev(&self.new_revision)
+        )
+    }
+}
+
+/// All pin files of a project.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Pins {
+    /// The pin files that exist, niv before npins.
+    pub files: Vec<PinFile>,
+}
+
+/// Whether `path` is (a symlink to) a pin file of the project of `nix_file`,
+/// whether that file exists or not.
+pub fn is_pin_file(nix_file: &NixFile, path: &Path) -> bool {
+    let canonical = path.canonicalize().ok();
+    PinTool::all(nix_file).any(|(_, pin_file)| {
+        pin_file == path || (canonical.is_some() && pin_file.canonicalize().ok() == canonical)
+    })
+}
+
+impl Pins {
+    /// Read the pin files next to `nix_file`.
+    ///
+    /// Missing files are skipped. Unreadable or invalid files are logged and skipped.
+    pub fn load(nix_file: &NixFile, logger: &slog::Logger) -> Pins {
+        let files = PinTool::all(nix_file)
+            .filter_map(|(tool, path)| {
+                let contents = match std::fs::read(&path) {
+                    Ok(contents) => contents,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+                    Err(e) => {
+                        warn!(logger, "could not read pin file, ignoring"; "path" => path.to_str(), "error" => %e);
+                        return None;
+                    }
+                };
+                match tool.parse(&contents) {
+                    Ok(pins) => Some(PinFile { tool, path, pins }),
+                    Err(e) => {
+                        warn!(logger, "invalid pin file, ignoring"; "path" => path.to_str(), "error" => %e);
+                        None
+                    }
+                }
+            })
+            .collect();
+        Pins { files }
+    }
+
+    /// Paths of all pin files.
+    pub fn paths(&self) -> impl Iterator<Item = &Path> {
+        self.files.iter().map(|file| file.path.as_path())
+    }
+
+    /// The pins that are different in `self` compared to `old`.
+    pub fn changed_since(&self, old: &Pins) -> Vec<ChangedPin> {
+        let mut changed = vec![];
+        for tool in [PinTool::Niv, PinTool::Npins] {
+            let pins = |pins: &Pins| -> Vec<Pin> {
+                pins.files
+                    .iter()
+                    .filter(|file| file.tool == tool)
+                    .flat_map(|file| file.pins.clone())
+                    .collect()
+            };
+            let (old_pins, new_pins) = (pins(old), pins(self));
+            let find = |pins: &[Pin], name: &str| pins.iter().find(|p| p.name == name).cloned();
+            let mut names = old_pins
+                .iter()
+                .chain(new_pins.iter())
+                .map(|pin| pin.name.clone())
+                .collect::<Vec<_>>();
+            names.sort();
+            names.dedup();
+            for name in names {
+                let (old_pin, new_pin) = (find(&old_pins, &name), find(&new_pins, &name));
+                if old_pin != new_pin {
+                    changed.push(ChangedPin {
+                        tool,
+                        name,
+                        old_revision: old_pin.and_then(|p| p.revision),
+                        new_revision: new_pin.and_then(|p| p.revision),
+                    });
+                }
+            }
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_pin_file, ChangedPin, Pin, PinTool, Pins};
+    use crate::{AbsPathBuf, NixFile};
+
+    const NIV: &str = r#"{
+        "nixpkgs": {
+            "branch": "nixos-unstable",
+            "owner": "NixOS",
+            "repo": "nixpkgs",
+            "rev": "abc",
+            "type": "tarball",
+            "url": "https://github.com/NixOS/nixpkgs/archive/abc.tar.gz"
+        },
+        "niv": { "rev": "123", "type": "tarball" }
+    }"#;
+
+    const NPINS: &str = r#"{
+        "pins": {
+            "nixpkgs": {
+                "type": "Git",
+                "repository": { "type": "GitHub", "owner": "NixOS", "repo": "nixpkgs" },
+                "branch": "nixos-unstable",
+                "revision": "def",
+                "url": "https://github.com/NixOS/nixpkgs/archive/def.tar.gz",
+                "hash": "0000"
+            },
+            "tool": { "type": "GitRelease", "version": "v1.2" }
+        },
+        "version": 3
+    }"#;
+
+    #[test]
+    fn parse_niv_and_npins() {
+        assert_eq!(
+            PinTool::Niv.parse(NIV.as_bytes()).unwrap(),
+            vec![
+                Pin {
+                    name: "niv".into(),
+                    revision: Some("123".into()),
+                    url: None
+                },
+                Pin {
+                    name: "nixpkgs".into(),
+                    revision: Some("abc".into()),
+                    url: Some("https://github.com/NixOS/nixpkgs/archive/abc.tar.gz".into())
+                },
+            ]
+        );
+        assert_eq!(
+            PinTool::Npins.parse(NPINS.as_bytes()).unwrap(),
+            vec![
+                Pin {
+                    name: "nixpkgs".into(),
+                    revision: Some("def".into()),
+                    url: Some("https://github.com/NixOS/nixpkgs/archive/def.tar.gz".into())
+                },
+                Pin {
+                    name: "tool".into(),
+                    revision: Some("v1.2".into()),
+                    url: None
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn load_and_diff_pins() {
+        let logger = crate::logging::test_logger("pins");
+        let dir = tempfile::tempdir().unwrap();
+        let nix_file = NixFile::from(AbsPathBuf::new(dir.path().join("shell.nix")).unwrap());
+        assert_eq!(Pins::load(&nix_file, &logger), Pins::default());
+
+        std::fs::create_dir(dir.path().join("nix")).unwrap();
+        let sources_json = dir.path().join("nix/sources.json");
+        std::fs::write(&sources_json, NIV).unwrap();
+        let old = Pins::load(&nix_file, &logger);
+        assert!(is_pin_file(&nix_file, &sources_json));
+        assert!(is_pin_file(
+            &nix_file,
+            &dir.path().join("npins/sources.json")
+        ));
+        assert!(!is_pin_file(&nix_file, &dir.path().join("shell.nix")));
+
+        std::fs::write(&sources_json, NIV.replace("\"abc\"", "\"abd\"")).unwrap();
+        let new = Pins::load(&nix_file, &logger);
+        assert_eq!(
+            new.changed_since(&old),
+            vec![ChangedPin {
+                tool: PinTool::Niv,
+                name: "nixpkgs".into(),
+                old_revision: Some("abc".into()),
+                new_revision: Some("abd".into()),
+            }]
+        );
+        assert_eq!(new.changed_since(&new), vec![]);
+        assert_eq!(Pins::default().changed_since(&old).len(), 2);
+    }
+}
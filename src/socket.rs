@@ -1,4 +1,6 @@
 //! Modules to set up communication between client and server over a unix socket.
+pub mod activation;
 pub mod communicate;
 pub mod path;
 pub mod read_writer;
+pub mod wire;
@@ -0,0 +1,178 @@
+//! Socket activation, i.e. listening on a socket that the service manager
+//! (systemd, see `contrib/lorri.socket`) bound for us.
+//!
+//! This implements the `sd_listen_fds(3)` protocol, so we don’t have to link libsystemd:
+//! the passed sockets start at file descriptor 3, `LISTEN_FDS` is their number and
+//! `LISTEN_PID` the pid of the process they are meant for.
+
+use crate::ops::error::{ExitAs, ExitErrorType};
+use nix::sys::socket::{getsockname, getsockopt, sockopt, SockAddr, SockType};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// The first file descriptor passed by the service manager.
+pub const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// The socket passed by the service manager cannot be used.
+#[derive(Error, Debug)]
+pub enum ActivationError {
+    /// `LISTEN_FDS` or `LISTEN_PID` are not numbers
+    #[error("invalid socket activation variable {name}={value}")]
+    InvalidVariable {
+        /// name of the environment variable
+        name: &'static str,
+        /// its value
+        value: String,
+    },
+    /// lorri only listens on one socket
+    #[error("expected exactly one socket from the service manager, but got {0}")]
+    TooManySockets(usize),
+    /// The file descriptor is something else than a listening unix stream socket
+    #[error("the file descriptor {0} passed by the service manager is not a listening unix stream socket")]
+    NotAUnixListener(RawFd),
+    /// The socket is not bound to the path the clients connect to
+    #[error("the service manager listens on {actual}, but lorri clients connect to {expected}; please fix the `ListenStream=` of the socket unit")]
+    WrongPath {
+        /// the socket path of lorri
+        expected: PathBuf,
+        /// the path the passed socket is bound to
+        actual: String,
+    },
+    /// Inspecting the file descriptor failed
+    #[error("could not inspect the socket passed by the service manager")]
+    Unix(#[source] nix::Error),
+}
+
+impl ExitAs for ActivationError {
+    fn exit_as(&self) -> ExitErrorType {
+        match self {
+            ActivationError::Unix(_) => ExitErrorType::Temporary,
+            _ => ExitErrorType::UserError,
+        }
+    }
+}
+
+/// Take the listening socket the service manager passed to this process, if any.
+///
+/// The socket must be bound to `socket_path`.
+/// The activation variables are removed from the environment, so that they
+/// are not inherited by our child processes and the socket is only taken once.
+pub fn take_listener(socket_path: &Path) -> Result<Option<UnixListener>, ActivationError> {
+    let var = |name| std::env::var(name).ok();
+    let count = listen_fds(
+        var("LISTEN_PID").as_deref(),
+        var("LISTEN_FDS").as_deref(),
+        std::process::id(),
+    );
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(name);
+    }
+    match count? {
+        0 => Ok(None),
+        1 => listener_from_fd(SD_LISTEN_FDS_START, socket_path).map(Some),
+        n => Err(ActivationError::TooManySockets(n)),
+    }
+}
+
+/// The number of sockets passed to the process with id `pid`, given the values of
+/// `LISTEN_PID` and `LISTEN_FDS`.
+/// Sockets meant for another process (e.g. our parent) don’t count.
+fn listen_fds(
+    listen_pid: Option<&str>,
+    listen_fds: Option<&str>,
+    pid: u32,
+) -> Result<usize, ActivationError> {
+    fn parse<T: std::str::FromStr>(name: &'static str, value: &str) -> Result<T, ActivationError> {
+        value.parse().map_err(|_| ActivationError::InvalidVariable {
+            name,
+            value: value.to_string(),
+        })
+    }
+    match (listen_pid, listen_fds) {
+        (Some(listen_pid), Some(listen_fds)) => {
+            if parse::<u32>("LISTEN_PID", listen_pid)? == pid {
+                parse("LISTEN_FDS", listen_fds)
+            } else {
+                Ok(0)
+            }
+        }
+        _ => Ok(0),
+    }
+}
+
+/// Take ownership of `fd`, after checking that it is a listening unix stream socket
+/// bound to `socket_path`.
+fn listener_from_fd(fd: RawFd, socket_path: &Path) -> Result<UnixListener, ActivationError> {
+    let is_stream =
+        getsockopt(fd, sockopt::SockType).map_err(ActivationError::Unix)? == SockType::Stream;
+    let is_listening = getsockopt(fd, sockopt::AcceptConn).map_err(ActivationError::Unix)?;
+    let path = match getsockname(fd).map_err(ActivationError::Unix)? {
+        SockAddr::Unix(addr) => addr.path().map(Path::to_owned),
+        _ => None,
+    };
+    match path {
+        Some(path) if is_stream && is_listening => {
+            if path != socket_path {
+                return Err(ActivationError::WrongPath {
+                    expected: socket_path.to_owned(),
+                    actual: path.display().to_string(),
+                });
+            }
+        }
+        _ => return Err(ActivationError::NotAUnixListener(fd)),
+    }
+    // don’t leak the socket into nix processes
+    nix::fcntl::fcntl(
+        fd,
+        nix::fcntl::FcntlArg::F_SETFD(nix::fcntl::FdFlag::FD_CLOEXEC),
+    )
+    .map_err(ActivationError::Unix)?;
+    // Safe, because the service manager passed the file descriptor to us,
+    // and we removed the variables that would make anybody else take it.
+    Ok(unsafe { UnixListener::from_raw_fd(fd) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{listen_fds, listener_from_fd, ActivationError};
+    use std::os::unix::io::{AsRawFd, IntoRawFd};
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    #[test]
+    fn only_sockets_for_this_process_count() {
+        assert_eq!(listen_fds(None, None, 42).unwrap(), 0);
+        assert_eq!(listen_fds(Some("42"), Some("1"), 42).unwrap(), 1);
+        // meant for another process, e.g. the shell that started us
+        assert_eq!(listen_fds(Some("41"), Some("1"), 42).unwrap(), 0);
+        assert!(matches!(
+            listen_fds(Some("42"), Some("one"), 42),
+            Err(ActivationError::InvalidVariable {
+                name: "LISTEN_FDS",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn inherited_listener_is_checked() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let socket_path = tempdir.path().join("daemon.socket");
+        let fd = UnixListener::bind(&socket_path).unwrap().into_raw_fd();
+
+        assert!(matches!(
+            listener_from_fd(fd, &tempdir.path().join("other.socket")),
+            Err(ActivationError::WrongPath { .. })
+        ));
+        let listener = listener_from_fd(fd, &socket_path).unwrap();
+        let _client = UnixStream::connect(&socket_path).unwrap();
+        listener.accept().unwrap();
+
+        let (stream, _other) = UnixStream::pair().unwrap();
+        assert!(matches!(
+            listener_from_fd(stream.as_raw_fd(), &socket_path),
+            Err(ActivationError::NotAUnixListener(_))
+        ));
+    }
+}
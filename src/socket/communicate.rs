@@ -8,20 +8,108 @@
 //!
 //! `client` implements a set of clients specialized to the communications
 //! we support.
+//!
+//! Every connection starts with a handshake: the client sends `HANDSHAKE_MAGIC`
+//! and a `HandshakeRequest`, the daemon answers with `ConnectionAccepted`
+//! if both speak the same `PROTOCOL_VERSION`. The handshake itself must never change.
+//!
+//! Instead of bincode, clients can speak JSON lines (see `read_writer::Encoding`);
+//! the schema is documented in `contrib/socket-protocol.md`. JSON clients receive the
+//! daemon’s messages as their `wire` types, so that internal types can change freely.
 
 use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use thiserror::Error;
 
 use crate::build_loop;
+use crate::nix::env::ClientEnv;
 use crate::ops::error::{ExitAs, ExitErrorType};
-use crate::socket::path::{BindError, BindLock, SocketPath};
-use crate::socket::read_writer::{ReadWriteError, ReadWriter, Timeout};
+use crate::socket::path::{BindError, BindLock, DaemonAddress, SocketPath};
+use crate::socket::read_writer::{
+    Encoding, ReadError, ReadWriteError, ReadWriter, Timeout, WriteError,
+};
+use crate::watch::WatchStatus;
 use crate::NixFile;
 
 /// We declare 1s as the time readers should wait
 /// for the other side to send something.
 pub const DEFAULT_READ_TIMEOUT: Timeout = Timeout::from_millis(1000);
 
+/// Version of the socket protocol, increase it with every incompatible change
+/// to the messages. Compatible additions are announced as capabilities instead.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The first message of a client, before the `HandshakeRequest`.
+///
+/// Clients of older lorri versions sent a bare `CommunicationType` instead,
+/// which is encoded as a small number. In turn, older daemons reject the magic
+/// as an invalid `CommunicationType` and close the connection.
+pub const HANDSHAKE_MAGIC: u32 = 0x6c6f_7272;
+
+/// Optional features of a client or daemon.
+/// Peers ignore capabilities they don’t know.
+pub mod capability {
+    /// `Ping`s carry the client’s environment (see `crate::nix::env`).
+    pub const PING_ENV: &str = "ping-env";
+    /// The daemon streams `Crashed` events when a build loop dies.
+    pub const CRASH_EVENTS: &str = "crash-events";
+    /// The daemon understands `CommunicationType::AcknowledgedPing`.
+    pub const PING_ACK: &str = "ping-ack";
+    /// The daemon supports `FilteredStreamEvents`.
+    pub const STREAM_FILTERS: &str = "stream-filters";
+    /// `FilteredStreamEvents` are answered with `StampedEvent`s instead of bare events.
+    /// The daemon only sends them if the client has the capability as well.
+    pub const STAMPED_EVENTS: &str = "stamped-events";
+}
+
+/// The capabilities of this lorri version.
+pub const CAPABILITIES: &[&str] = &[
+    capability::PING_ENV,
+    capability::CRASH_EVENTS,
+    capability::PING_ACK,
+    capability::STREAM_FILTERS,
+    capability::STAMPED_EVENTS,
+];
+
+/// Which lorri is at the other end of the socket.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+    /// The `PROTOCOL_VERSION` it speaks.
+    pub protocol_version: u32,
+    /// Its lorri version, for error messages.
+    #[serde(default)]
+    pub lorri_version: String,
+    /// The `capability`s it supports.
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+}
+
+impl Version {
+    /// The version of this lorri.
+    pub fn current() -> Version {
+        Version {
+            protocol_version: PROTOCOL_VERSION,
+            lorri_version: env!("CARGO_PKG_VERSION").to_string(),
+            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+
+    /// Whether the other side supports `capability`.
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+}
+
+/// Sent by the client after `HANDSHAKE_MAGIC`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HandshakeRequest {
+    /// The version of the client.
+    pub client: Version,
+    /// What the client wants to do on this connection.
+    pub communication_type: CommunicationType,
+}
+
 /// Binds a client request type to a server response.
 ///
 /// For example, the handler for `Ping` has the response type `NoMessage`,
@@ -42,6 +130,10 @@ pub enum CommunicationType {
     Ping,
     /// Stream events that happen in the daemon to the client, as they happen.
     StreamEvents,
+    /// Like `Ping`, but the daemon acknowledges the ping and can report the build result
+    AcknowledgedPing,
+    /// Like `StreamEvents`, but only the events that match a filter.
+    FilteredStreamEvents,
 }
 
 /// No message can be sent through this socket end (empty type).
@@ -53,13 +145,44 @@ pub enum NoMessage {}
 pub struct DaemonInfo {}
 
 impl Handler for DaemonInfo {
-    type Resp = ();
+    type Resp = DaemonStatus;
 
     fn communication_type() -> CommunicationType {
         CommunicationType::DaemonInfo
     }
 }
 
+/// Reply to `DaemonInfo`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DaemonStatus {
+    /// Every project the daemon is watching.
+    pub projects: Vec<ProjectStatus>,
+}
+
+impl DaemonStatus {
+    /// Total number of paths watched, over all projects.
+    pub fn watched_paths(&self) -> usize {
+        self.projects.iter().map(|p| p.watch.watched).sum()
+    }
+
+    /// Status of the project for `nix_file`, if the daemon knows about it.
+    pub fn project(&self, nix_file: &NixFile) -> Option<&ProjectStatus> {
+        self.projects.iter().find(|p| &p.nix_file == nix_file)
+    }
+}
+
+/// What the daemon knows about a single project.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProjectStatus {
+    /// The project’s nix file.
+    pub nix_file: NixFile,
+    /// State of the file watcher of this project.
+    pub watch: WatchStatus,
+    /// If the build loop of this project crashed (and is being restarted), why.
+    /// Cleared by the next successful build.
+    pub crashed: Option<String>,
+}
+
 /// Message sent by the client to ask the server to start
 /// watching `nix_file`. See `CommunicationType::Ping`.
 #[derive(Serialize, Deserialize, Debug)]
@@ -68,10 +191,14 @@ pub struct Ping {
     pub nix_file: NixFile,
     /// When/whether to start the build.
     pub rebuild: Rebuild,
+    /// The allowlisted environment of the client, so the daemon
+    /// builds the project like the client’s shell would.
+    pub env: Option<ClientEnv>,
 }
 
 /// In which cases a ping will trigger a rebuild
 #[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
 pub enum Rebuild {
     /// Only if the nix_file is not yet watched
     OnlyIfNotYetWatching,
@@ -87,14 +214,177 @@ impl Handler for Ping {
     }
 }
 
+/// Message sent by the client to ping the daemon like `Ping`,
+/// and get to know what the daemon made of it.
+/// See `CommunicationType::AcknowledgedPing`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AcknowledgedPing {
+    /// The ping.
+    pub ping: Ping,
+    /// Whether the daemon should report the result of the first build
+    /// that starts after the ping (or of the last build, if none is triggered).
+    pub wait: bool,
+}
+
+/// What the daemon did with a ping.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PingAck {
+    /// The project was new, the daemon started watching and building it.
+    Accepted,
+    /// The project is watched already, and the ping did not trigger a rebuild.
+    AlreadyWatching,
+    /// The project is watched already, and it will be rebuilt.
+    RebuildScheduled,
+    /// The daemon serves multiple users, and the client’s user can’t read the nix file.
+    /// The ping was ignored.
+    PermissionDenied,
+}
+
+/// Reply to `AcknowledgedPing`: first a `PingAck`, then
+/// (if the client asked to wait) a `Finished`; or an `Error` instead of either.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum PingResponse {
+    /// The ping was received.
+    Ack(PingAck),
+    /// The build finished (`Completed` or `Failure`), or its build loop `Crashed`.
+    Finished(build_loop::Event),
+    /// The daemon could not handle the ping, and closes the connection.
+    Error(String),
+}
+
+impl Handler for AcknowledgedPing {
+    type Resp = PingResponse;
+
+    fn communication_type() -> CommunicationType {
+        CommunicationType::AcknowledgedPing
+    }
+}
+
 /// Stream events to the client, as they happen.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct StreamEvents {}
 
-// #[derive(Serialize, Deserialize, Debug)]
-// pub struct Event {
-//     pub event: Event,
-// }
+/// Stream the events that match `filter` to the client, as they happen.
+/// See `CommunicationType::FilteredStreamEvents`.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct FilteredStreamEvents {
+    /// Only stream the events that match.
+    #[serde(default)]
+    pub filter: EventFilter,
+}
+
+/// The type of a `build_loop::Event`, to filter on.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventType {
+    /// `Event::Started`
+    Started,
+    /// `Event::Completed`
+    Completed,
+    /// `Event::Failure`
+    Failure,
+    /// `Event::Retrying`
+    Retrying,
+    /// `Event::Crashed`
+    Crashed,
+}
+
+impl EventType {
+    /// The type of `event`, `None` for `SectionEnd`.
+    pub fn of(event: &build_loop::Event) -> Option<EventType> {
+        use build_loop::Event;
+        match event {
+            Event::SectionEnd => None,
+            Event::Started { .. } => Some(EventType::Started),
+            Event::Completed { .. } => Some(EventType::Completed),
+            Event::Failure { .. } => Some(EventType::Failure),
+            Event::Retrying { .. } => Some(EventType::Retrying),
+            Event::Crashed { .. } => Some(EventType::Crashed),
+        }
+    }
+}
+
+impl std::str::FromStr for EventType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "started" => Ok(EventType::Started),
+            "completed" => Ok(EventType::Completed),
+            "failure" => Ok(EventType::Failure),
+            "retrying" => Ok(EventType::Retrying),
+            "crashed" => Ok(EventType::Crashed),
+            _ => Err(format!(
+                "{} not in started,completed,failure,retrying,crashed",
+                s
+            )),
+        }
+    }
+}
+
+/// Which events a `FilteredStreamEvents` client wants to see.
+/// Every condition that is set must match; the default matches every event.
+/// `SectionEnd` always matches.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct EventFilter {
+    /// Only events of these projects (all if empty).
+    #[serde(default)]
+    pub nix_files: Vec<NixFile>,
+    /// Only events of projects whose nix file is in this directory, or below.
+    #[serde(default)]
+    pub under: Option<PathBuf>,
+    /// Only events of these types (all if empty).
+    #[serde(default)]
+    pub types: Vec<EventType>,
+}
+
+impl EventFilter {
+    /// Whether `event` passes the filter.
+    pub fn matches(&self, event: &build_loop::Event) -> bool {
+        let (nix_file, event_type) = match (event.nix_file(), EventType::of(event)) {
+            (Some(nix_file), Some(event_type)) => (nix_file, event_type),
+            _ => return true,
+        };
+        (self.nix_files.is_empty() || self.nix_files.contains(nix_file))
+            && self
+                .under
+                .iter()
+                .all(|dir| nix_file.as_absolute_path().starts_with(dir))
+            && (self.types.is_empty() || self.types.contains(&event_type))
+    }
+}
+
+/// A `build_loop::Event`, stamped with the time the daemon recorded it.
+/// `FilteredStreamEvents` clients receive these if both sides have `capability::STAMPED_EVENTS`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StampedEvent {
+    /// When the daemon recorded the event, in milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    /// The user the project is built for, if the daemon serves multiple users.
+    #[serde(default)]
+    pub uid: Option<u32>,
+    /// The event.
+    pub event: build_loop::Event,
+}
+
+impl StampedEvent {
+    /// Stamp `event` of the project of `uid` with the current time.
+    pub fn now(event: build_loop::Event, uid: Option<u32>) -> StampedEvent {
+        StampedEvent {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis() as u64),
+            uid,
+            event,
+        }
+    }
+
+    /// The time the daemon recorded the event.
+    pub fn time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.timestamp)
+    }
+}
 
 impl Handler for StreamEvents {
     type Resp = build_loop::Event;
@@ -104,18 +394,34 @@ impl Handler for StreamEvents {
     }
 }
 
+impl Handler for FilteredStreamEvents {
+    type Resp = StampedEvent;
+
+    fn communication_type() -> CommunicationType {
+        CommunicationType::FilteredStreamEvents
+    }
+}
+
 /// `Listener` and possible errors.
 pub mod listener {
     use super::*;
+    use crossbeam_channel as chan;
+    use std::io::Read;
     use std::os::unix::net::UnixListener;
 
-    /// If a connection on the socket is attempted and the first
-    /// message is of a `ConnectionType`, the `Listener` returns
-    /// this message as an ack.
-    /// In all other cases the `Listener` returns no answer (the
-    /// bad client should time out after some time).
+    /// If a connection on the socket is attempted and the client
+    /// sends a `HandshakeRequest`, the `Listener` answers with
+    /// this message if it speaks the client’s protocol version.
+    /// In all other cases the `Listener` closes the connection.
     #[derive(Debug, Serialize, Deserialize)]
-    pub struct ConnectionAccepted();
+    pub struct ConnectionAccepted {
+        /// The version of the daemon.
+        pub daemon: Version,
+    }
+
+    /// The answer to a `HandshakeRequest`; `Err` holds the daemon’s version
+    /// if it does not speak the client’s protocol version.
+    pub type HandshakeResponse = Result<ConnectionAccepted, Version>;
 
     /// Server-side part of a socket transmission,
     /// listening for incoming messages.
@@ -140,6 +446,11 @@ pub mod listener {
     pub struct Connection {
         /// The kind of communication the client requested us to talk to it.
         pub communication_type: CommunicationType,
+        /// The version of the client.
+        pub client: Version,
+        /// The user id of the client process, as reported by the kernel.
+        /// `None` if the platform can’t tell.
+        pub peer_uid: Option<u32>,
         /// The handlers, being able to
         pub handlers: Handlers,
     }
@@ -151,12 +462,17 @@ pub mod listener {
         Accept(std::io::Error),
         /// The client’s message could not be decoded.
         Message(ReadWriteError),
+        /// The client is from a lorri version without the versioned handshake.
+        LegacyClient,
+        /// The client speaks another protocol version; it was told ours.
+        VersionMismatch(Version),
     }
 
     impl Listener {
-        /// Create a new `daemon` by binding to `socket_path`.
+        /// Create a new `daemon` by binding to `socket_path`,
+        /// or by listening on the socket systemd passed us for `socket_path`.
         pub fn new(socket_path: &SocketPath) -> Result<Listener, BindError> {
-            let (l, lock) = socket_path.bind()?;
+            let (l, lock) = socket_path.bind_or_inherit()?;
             Ok(Listener {
                 listener: l,
                 bind_lock: lock,
@@ -171,41 +487,156 @@ pub mod listener {
         pub fn accept(&self) -> Result<Connection, AcceptError> {
             // - socket accept
             let (unix_stream, _) = self.listener.accept().map_err(AcceptError::Accept)?;
-            // - read first message as a `CommunicationType`
-            let communication_type: CommunicationType =
-                ReadWriter::<CommunicationType, ConnectionAccepted>::new(&unix_stream)
-                    .react(self.accept_timeout, |_| ConnectionAccepted())
-                    .map_err(AcceptError::Message)?;
+            // - find out whether the client speaks bincode or JSON
+            let encoding = Encoding::detect(&unix_stream, self.accept_timeout)
+                .map_err(|e| AcceptError::Message(ReadWriteError::R(e)))?;
+            // - read the magic, older clients send their `CommunicationType` instead.
+            //   JSON clients have no use for it, they were never able to connect.
+            if encoding == Encoding::Bincode {
+                let magic: u32 = ReadWriter::<u32, NoMessage>::new(&unix_stream)
+                    .read(self.accept_timeout)
+                    .map_err(|e| AcceptError::Message(ReadWriteError::R(e)))?;
+                if magic != HANDSHAKE_MAGIC {
+                    return Err(AcceptError::LegacyClient);
+                }
+            }
+            // - read the `HandshakeRequest` and answer with our version
+            let HandshakeRequest {
+                client,
+                communication_type,
+            } = ReadWriter::<HandshakeRequest, HandshakeResponse>::with_encoding(
+                &unix_stream,
+                encoding,
+            )
+            .react(self.accept_timeout, |req| {
+                if req.client.protocol_version == PROTOCOL_VERSION {
+                    Ok(ConnectionAccepted {
+                        daemon: Version::current(),
+                    })
+                } else {
+                    Err(Version::current())
+                }
+            })
+            .map_err(AcceptError::Message)?;
+            if client.protocol_version != PROTOCOL_VERSION {
+                return Err(AcceptError::VersionMismatch(client));
+            }
             // spawn a thread with the accept handler
             Ok(Connection {
+                peer_uid: peer_uid(&unix_stream),
                 handlers: Handlers {
                     socket: unix_stream,
+                    encoding,
                 },
                 communication_type,
+                client,
             })
         }
     }
 
+    /// The user id of the process on the other side of `socket` (`SO_PEERCRED`).
+    #[cfg(target_os = "linux")]
+    pub fn peer_uid(socket: &UnixStream) -> Option<u32> {
+        use nix::sys::socket::{getsockopt, sockopt};
+        use std::os::unix::io::AsRawFd;
+        getsockopt(socket.as_raw_fd(), sockopt::PeerCredentials)
+            .ok()
+            .map(|cred| cred.uid())
+    }
+
+    /// The user id of the process on the other side of `socket`.
+    #[cfg(not(target_os = "linux"))]
+    pub fn peer_uid(_socket: &UnixStream) -> Option<u32> {
+        None
+    }
+
     /// A wrapper that is returned by accept and provides a `ReadWriter` for each of the `CommunicationType`s.
     pub struct Handlers {
         socket: UnixStream,
+        /// All messages on this connection are encoded like the handshake.
+        encoding: Encoding,
     }
 
     /// All handlers we have available to read messages and reply.
     impl Handlers {
         /// React to a DaemonInfo message
         pub fn daemon_info(&self) -> ReadWriter<DaemonInfo, <DaemonInfo as Handler>::Resp> {
-            ReadWriter::new(&self.socket)
+            ReadWriter::with_encoding(&self.socket, self.encoding)
         }
 
         /// React to a ping message
         pub fn ping(&self) -> ReadWriter<Ping, <Ping as Handler>::Resp> {
-            ReadWriter::new(&self.socket)
+            ReadWriter::with_encoding(&self.socket, self.encoding)
+        }
+
+        /// React to an acknowledged ping message
+        pub fn acknowledged_ping(
+            &self,
+        ) -> ReadWriter<'_, AcknowledgedPing, <AcknowledgedPing as Handler>::Resp> {
+            ReadWriter::with_encoding(&self.socket, self.encoding)
         }
 
         /// Stream events to the client as they happen
         pub fn stream_events(&self) -> ReadWriter<StreamEvents, <StreamEvents as Handler>::Resp> {
-            ReadWriter::new(&self.socket)
+            ReadWriter::with_encoding(&self.socket, self.encoding)
+        }
+
+        /// Stream the events that match a filter to the client as they happen
+        pub fn filtered_stream_events(
+            &self,
+        ) -> ReadWriter<'_, FilteredStreamEvents, <FilteredStreamEvents as Handler>::Resp> {
+            ReadWriter::with_encoding(&self.socket, self.encoding)
+        }
+
+        /// Stream the events that match a filter to a client without
+        /// `capability::STAMPED_EVENTS`, which expects bare events.
+        pub fn filtered_stream_events_unstamped(
+            &self,
+        ) -> ReadWriter<'_, FilteredStreamEvents, build_loop::Event> {
+            ReadWriter::with_encoding(&self.socket, self.encoding)
+        }
+
+        /// How messages on this connection are encoded.
+        pub fn encoding(&self) -> Encoding {
+            self.encoding
+        }
+
+        /// Notice when the client hangs up, after it sent its last message.
+        pub fn hang_up(&self) -> std::io::Result<HangUp> {
+            let socket = self.socket.try_clone()?;
+            let mut reader = self.socket.try_clone()?;
+            let (tx, rx) = chan::bounded::<()>(0);
+            std::thread::spawn(move || {
+                let mut buf = [0; 64];
+                // returns 0 once the client closed the connection, or `HangUp` was dropped
+                while let Ok(n) = reader.read(&mut buf) {
+                    if n == 0 {
+                        break;
+                    }
+                }
+                drop(tx);
+            });
+            Ok(HangUp { socket, rx })
+        }
+    }
+
+    /// Returned by `Handlers::hang_up`.
+    pub struct HangUp {
+        socket: UnixStream,
+        rx: chan::Receiver<()>,
+    }
+
+    impl HangUp {
+        /// Disconnects once the client hung up.
+        pub fn chan(&self) -> &chan::Receiver<()> {
+            &self.rx
+        }
+    }
+
+    impl Drop for HangUp {
+        fn drop(&mut self) {
+            // wake up the thread waiting for the client
+            let _ = self.socket.shutdown(std::net::Shutdown::Read);
         }
     }
 }
@@ -227,6 +658,8 @@ pub mod client {
         comm_type: CommunicationType,
         /// Connected socket.
         socket: Option<UnixStream>,
+        /// Version of the daemon, known after connecting.
+        daemon: Option<Version>,
         /// Timeout for reads/writes.
         timeout: Timeout,
         read_type: PhantomData<R>,
@@ -259,11 +692,52 @@ pub mod client {
     #[derive(Error, Debug)]
     pub enum InitError {
         /// `connect()` syscall failed.
-        #[error("Unable to connect to socket at {0}, is the daemon running?")]
-        SocketConnect(SocketPath, #[source] std::io::Error),
-        /// Handshake failed (write `ConnectionType`, read `ConnectionAccepted`).
+        #[error("Unable to connect to the daemon at {0}, is the daemon running?")]
+        SocketConnect(DaemonAddress, #[source] std::io::Error),
+        /// Handshake failed (write `HandshakeRequest`, read `ConnectionAccepted`).
         #[error("Server Handshake failed: {0}")]
         ServerHandshake(ReadWriteError),
+        /// The daemon speaks another protocol version.
+        #[error(
+            "The daemon is lorri {} (protocol version {}), but this is lorri {} (protocol version {}), please restart the daemon",
+            .0.lorri_version,
+            .0.protocol_version,
+            env!("CARGO_PKG_VERSION"),
+            PROTOCOL_VERSION
+        )]
+        VersionMismatch(Version),
+        /// The daemon closed the connection during the handshake,
+        /// which is what daemons without the versioned handshake do.
+        #[error("The daemon closed the connection, it is probably an older version of lorri, please restart it")]
+        OldDaemon(#[source] ReadWriteError),
+    }
+
+    impl InitError {
+        /// Whether the daemon is running a lorri version we can’t talk to.
+        pub fn is_version_mismatch(&self) -> bool {
+            matches!(
+                self,
+                InitError::VersionMismatch(_) | InitError::OldDaemon(_)
+            )
+        }
+    }
+
+    /// Whether `err` means that the daemon closed the connection.
+    fn is_connection_closed(err: &ReadWriteError) -> bool {
+        let bincode_err = match err {
+            ReadWriteError::R(ReadError::Deserialize(e)) => e,
+            ReadWriteError::W(WriteError::Serialize(e)) => e,
+            _ => return false,
+        };
+        match &**bincode_err {
+            bincode::ErrorKind::Io(io) => matches!(
+                io.kind(),
+                std::io::ErrorKind::UnexpectedEof
+                    | std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::BrokenPipe
+            ),
+            _ => false,
+        }
     }
 
     impl ExitAs for InitError {
@@ -272,6 +746,8 @@ pub mod client {
             match self {
                 SocketConnect(_, _) => ExitErrorType::Temporary,
                 ServerHandshake(_) => ExitErrorType::Temporary,
+                VersionMismatch(_) => ExitErrorType::UserError,
+                OldDaemon(_) => ExitErrorType::UserError,
             }
         }
     }
@@ -295,37 +771,66 @@ pub mod client {
             Client {
                 comm_type,
                 socket: None,
+                daemon: None,
                 timeout,
                 read_type: PhantomData,
                 write_type: PhantomData,
             }
         }
 
-        /// Connect to the `Listener` listening on `socket_path`.
+        /// Connect to the `Listener` at `address`.
         /// TODO: remove the split between new() and connect(), and then remove `Error::NotConnected`
-        pub fn connect(self, socket_path: &SocketPath) -> Result<Client<R, W>, InitError> {
+        pub fn connect(self, address: &DaemonAddress) -> Result<Client<R, W>, InitError> {
             // TODO: check if the file exists and is a socket
 
-            // - connect to `socket_path`
-            let socket = socket_path
+            // - connect to `address`
+            let socket = address
                 .connect()
-                .map_err(|e| InitError::SocketConnect(socket_path.clone(), e))?;
+                .map_err(|e| InitError::SocketConnect(address.clone(), e))?;
 
-            // - send initial message with the CommunicationType
+            let handshake_error = |err| {
+                if is_connection_closed(&err) {
+                    InitError::OldDaemon(err)
+                } else {
+                    InitError::ServerHandshake(err)
+                }
+            };
+            // - send the magic and our version with the CommunicationType
             // - wait for server to acknowledge connect
-            let _: listener::ConnectionAccepted = ReadWriter::new(&socket)
-                .communicate(self.timeout, &self.comm_type)
-                .map_err(InitError::ServerHandshake)?;
+            ReadWriter::<NoMessage, u32>::new(&socket)
+                .write(self.timeout, &HANDSHAKE_MAGIC)
+                .map_err(|e| handshake_error(ReadWriteError::W(e)))?;
+            let response: listener::HandshakeResponse = ReadWriter::new(&socket)
+                .communicate(
+                    self.timeout,
+                    &HandshakeRequest {
+                        client: Version::current(),
+                        communication_type: self.comm_type,
+                    },
+                )
+                .map_err(handshake_error)?;
+            let accepted = response.map_err(InitError::VersionMismatch)?;
 
             Ok(Client {
                 comm_type: self.comm_type,
                 socket: Some(socket),
+                daemon: Some(accepted.daemon),
                 timeout: self.timeout,
                 read_type: PhantomData,
                 write_type: PhantomData,
             })
         }
 
+        /// Change the timeout for subsequent reads/writes.
+        pub fn set_timeout(&mut self, timeout: Timeout) {
+            self.timeout = timeout;
+        }
+
+        /// The version of the connected daemon.
+        pub fn daemon_version(&self) -> Option<&Version> {
+            self.daemon.as_ref()
+        }
+
         /// Write a message to the connected `Listener`, then wait for the reply. The configured timeout counts for the whole roundtrip.
         pub fn comunicate(&self, mes: &W) -> Result<R, Error>
         where
@@ -360,4 +865,308 @@ pub mod client {
                 .map_err(|e| Error::Message(ReadWriteError::W(e)))
         }
     }
+
+    impl Client<StampedEvent, FilteredStreamEvents> {
+        /// Daemons without `capability::STAMPED_EVENTS` answer with bare events.
+        pub fn unstamped(self) -> Client<build_loop::Event, FilteredStreamEvents> {
+            Client {
+                comm_type: self.comm_type,
+                socket: self.socket,
+                daemon: self.daemon,
+                timeout: self.timeout,
+                read_type: PhantomData,
+                write_type: PhantomData,
+            }
+        }
+    }
+
+    /// A client to ping the daemon with, see `connect_ping`.
+    pub enum PingClient {
+        /// The daemon acknowledges pings (`capability::PING_ACK`).
+        Acknowledged(Client<PingResponse, AcknowledgedPing>),
+        /// The daemon predates `capability::PING_ACK` and only takes plain `Ping`s.
+        Plain(Client<NoMessage, Ping>),
+    }
+
+    impl PingClient {
+        /// Send `ping` without waiting for a build.
+        /// Returns the daemon’s answer, or `None` if it does not acknowledge pings.
+        pub fn ping(self, ping: Ping) -> Result<Option<PingResponse>, Error> {
+            match self {
+                PingClient::Acknowledged(client) => client
+                    .comunicate(&AcknowledgedPing { ping, wait: false })
+                    .map(Some),
+                PingClient::Plain(client) => client.write(&ping).map(|()| None),
+            }
+        }
+    }
+
+    /// Connect to the `Listener` at `address` for pinging it.
+    ///
+    /// The `CommunicationType` is part of the handshake, so a daemon that does not
+    /// know `AcknowledgedPing` hangs up before we learn its capabilities.
+    /// In that case we connect again with a plain `Ping`,
+    /// and use that connection if the daemon indeed lacks `capability::PING_ACK`.
+    pub fn connect_ping(
+        timeout: Timeout,
+        address: &DaemonAddress,
+    ) -> Result<PingClient, InitError> {
+        match new::<AcknowledgedPing>(timeout).connect(address) {
+            Ok(client) => Ok(PingClient::Acknowledged(client)),
+            Err(err @ InitError::OldDaemon(_)) => {
+                let client = new::<Ping>(timeout).connect(address)?;
+                match client.daemon_version() {
+                    Some(daemon) if !daemon.has_capability(capability::PING_ACK) => {
+                        Ok(PingClient::Plain(client))
+                    }
+                    _ => Err(err),
+                }
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::client::{self, InitError};
+    use super::listener::{AcceptError, Listener};
+    use super::*;
+    use crate::AbsPathBuf;
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixListener;
+
+    fn socket_path(dir: &tempfile::TempDir) -> SocketPath {
+        SocketPath::from(AbsPathBuf::new(dir.path().join("daemon.socket")).unwrap())
+    }
+
+    #[test]
+    fn handshake_returns_the_daemon_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = socket_path(&dir);
+        let listener = Listener::new(&path).unwrap();
+        let server = std::thread::spawn(move || {
+            let conn = listener.accept().ok().unwrap();
+            assert_eq!(conn.client, Version::current());
+            assert!(matches!(conn.communication_type, CommunicationType::Ping));
+            #[cfg(target_os = "linux")]
+            assert_eq!(conn.peer_uid, Some(nix::unistd::getuid().as_raw()));
+        });
+        let client = client::new::<Ping>(DEFAULT_READ_TIMEOUT)
+            .connect(&path.clone().into())
+            .unwrap();
+        assert_eq!(client.daemon_version(), Some(&Version::current()));
+        assert!(client
+            .daemon_version()
+            .unwrap()
+            .has_capability(capability::PING_ENV));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn legacy_client_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = socket_path(&dir);
+        let listener = Listener::new(&path).unwrap();
+        let server = std::thread::spawn(move || listener.accept().err());
+        // older clients only send their `CommunicationType`
+        let socket = path.connect().unwrap();
+        ReadWriter::<NoMessage, CommunicationType>::new(&socket)
+            .write(DEFAULT_READ_TIMEOUT, &CommunicationType::Ping)
+            .unwrap();
+        assert!(matches!(
+            server.join().unwrap(),
+            Some(AcceptError::LegacyClient)
+        ));
+    }
+
+    #[test]
+    fn old_daemon_is_detected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = socket_path(&dir);
+        let listener = UnixListener::bind(path.as_absolute_path()).unwrap();
+        // older daemons fail to decode the magic as `CommunicationType` and hang up
+        let server = std::thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let mut magic = [0; 4];
+            socket.read_exact(&mut magic).unwrap();
+            socket.flush().unwrap();
+        });
+        let err = client::new::<Ping>(DEFAULT_READ_TIMEOUT)
+            .connect(&path.clone().into())
+            .err()
+            .unwrap();
+        assert!(matches!(err, InitError::OldDaemon(_)), "{:?}", err);
+        assert!(err.is_version_mismatch());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn pings_fall_back_for_daemons_without_ping_ack() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = socket_path(&dir);
+        let listener = UnixListener::bind(path.as_absolute_path()).unwrap();
+        // a daemon that has the handshake, but does not know `AcknowledgedPing`
+        let server = std::thread::spawn(move || loop {
+            let (socket, _) = listener.accept().unwrap();
+            let magic: u32 = ReadWriter::<u32, NoMessage>::new(&socket)
+                .read(DEFAULT_READ_TIMEOUT)
+                .unwrap();
+            assert_eq!(magic, HANDSHAKE_MAGIC);
+            let request: HandshakeRequest = ReadWriter::<HandshakeRequest, NoMessage>::new(&socket)
+                .read(DEFAULT_READ_TIMEOUT)
+                .unwrap();
+            // older daemons can’t decode the request and hang up
+            if let CommunicationType::AcknowledgedPing = request.communication_type {
+                continue;
+            }
+            let mut daemon = Version::current();
+            daemon.capabilities = vec![capability::PING_ENV.to_string()];
+            let response: listener::HandshakeResponse = Ok(listener::ConnectionAccepted { daemon });
+            ReadWriter::<NoMessage, listener::HandshakeResponse>::new(&socket)
+                .write(DEFAULT_READ_TIMEOUT, &response)
+                .unwrap();
+            assert!(matches!(
+                request.communication_type,
+                CommunicationType::Ping
+            ));
+            return ReadWriter::<Ping, NoMessage>::new(&socket)
+                .read(DEFAULT_READ_TIMEOUT)
+                .unwrap();
+        });
+        let client = client::connect_ping(DEFAULT_READ_TIMEOUT, &path.clone().into())
+            .ok()
+            .unwrap();
+        assert!(matches!(client, client::PingClient::Plain(_)));
+        let nix_file = NixFile::from(AbsPathBuf::new(PathBuf::from("/a/shell.nix")).unwrap());
+        let ack = client
+            .ping(Ping {
+                nix_file: nix_file.clone(),
+                rebuild: Rebuild::Always,
+                env: None,
+            })
+            .unwrap();
+        assert!(ack.is_none());
+        assert_eq!(server.join().unwrap().nix_file, nix_file);
+    }
+
+    #[test]
+    fn stream_events_keep_their_bincode_encoding() {
+        // clients that predate `FilteredStreamEvents` send these bytes
+        assert_eq!(
+            bincode::serialize(&CommunicationType::StreamEvents).unwrap(),
+            bincode::serialize(&2u32).unwrap()
+        );
+        assert!(bincode::serialize(&StreamEvents {}).unwrap().is_empty());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = socket_path(&dir);
+        let listener = Listener::new(&path).unwrap();
+        let server = std::thread::spawn(move || {
+            let mut filters = vec![];
+            for _ in 0..2 {
+                let conn = listener.accept().ok().unwrap();
+                let section_end = StampedEvent {
+                    timestamp: 1234,
+                    uid: None,
+                    event: build_loop::Event::SectionEnd,
+                };
+                let filter = match conn.communication_type {
+                    CommunicationType::StreamEvents => {
+                        let mut rw = conn.handlers.stream_events();
+                        let StreamEvents {} = rw.read(DEFAULT_READ_TIMEOUT).unwrap();
+                        rw.write(DEFAULT_READ_TIMEOUT, &section_end.event).unwrap();
+                        EventFilter::default()
+                    }
+                    CommunicationType::FilteredStreamEvents => {
+                        let mut rw = conn.handlers.filtered_stream_events();
+                        let FilteredStreamEvents { filter } =
+                            rw.read(DEFAULT_READ_TIMEOUT).unwrap();
+                        rw.write(DEFAULT_READ_TIMEOUT, &section_end).unwrap();
+                        filter
+                    }
+                    other => panic!("unexpected communication type {:?}", other),
+                };
+                filters.push(filter);
+            }
+            filters
+        });
+
+        let client = client::new::<StreamEvents>(DEFAULT_READ_TIMEOUT)
+            .connect(&path.clone().into())
+            .unwrap();
+        client.write(&StreamEvents {}).unwrap();
+        assert!(matches!(client.read(), Ok(build_loop::Event::SectionEnd)));
+
+        let crashed = EventFilter {
+            types: vec![EventType::Crashed],
+            ..EventFilter::default()
+        };
+        let client = client::new::<FilteredStreamEvents>(DEFAULT_READ_TIMEOUT)
+            .connect(&path.clone().into())
+            .unwrap();
+        client
+            .write(&FilteredStreamEvents {
+                filter: crashed.clone(),
+            })
+            .unwrap();
+        assert!(matches!(
+            client.read(),
+            Ok(StampedEvent {
+                timestamp: 1234,
+                uid: None,
+                event: build_loop::Event::SectionEnd
+            })
+        ));
+        assert_eq!(
+            server.join().unwrap(),
+            vec![EventFilter::default(), crashed]
+        );
+    }
+
+    #[test]
+    fn event_filter_matches() {
+        use crate::build_loop::{Event, ReasonI};
+        let nix_file = |path: &str| NixFile::from(AbsPathBuf::new(PathBuf::from(path)).unwrap());
+        let started = |path| Event::Started {
+            nix_file: nix_file(path),
+            reason: ReasonI::PingReceived,
+        };
+        let crashed = |path| Event::Crashed {
+            nix_file: nix_file(path),
+            reason: "oops".into(),
+            restart_in_secs: 5,
+        };
+
+        let all = EventFilter::default();
+        assert!(all.matches(&started("/a/shell.nix")));
+        assert!(all.matches(&Event::SectionEnd));
+
+        let project = EventFilter {
+            nix_files: vec![nix_file("/a/shell.nix")],
+            ..EventFilter::default()
+        };
+        assert!(project.matches(&started("/a/shell.nix")));
+        assert!(!project.matches(&started("/b/shell.nix")));
+        assert!(project.matches(&Event::SectionEnd));
+
+        let under = EventFilter {
+            under: Some(PathBuf::from("/a")),
+            ..EventFilter::default()
+        };
+        assert!(under.matches(&started("/a/sub/shell.nix")));
+        assert!(!under.matches(&started("/ab/shell.nix")));
+
+        let types = EventFilter {
+            types: vec![EventType::Crashed],
+            ..EventFilter::default()
+        };
+        assert!(types.matches(&crashed("/a/shell.nix")));
+        assert!(!types.matches(&started("/a/shell.nix")));
+        assert_eq!("crashed".parse(), Ok(EventType::Crashed));
+
+        // all fields of the filter are optional
+        let empty: FilteredStreamEvents = serde_json::from_str("{}").unwrap();
+        assert_eq!(empty.filter, all);
+    }
 }
@@ -1,6 +1,7 @@
 //! `bind()`ing & `connect()`ing to sockets.
 
 use crate::ops::error::{ExitAs, ExitErrorType};
+use crate::socket::activation::{self, ActivationError};
 use crate::AbsPathBuf;
 use std::fmt;
 use std::os::unix::io::AsRawFd;
@@ -12,6 +13,21 @@ use thiserror::Error;
 #[derive(Clone, Debug)]
 pub struct SocketPath(AbsPathBuf);
 
+/// Where clients connect to the daemon: its socket, or a TCP port that the
+/// daemon forwards to its socket (`lorri daemon --listen-tcp`).
+#[derive(Clone, Debug)]
+pub enum DaemonAddress {
+    /// The daemon socket.
+    Unix(SocketPath),
+    /// A `host:port` to connect to over TCP.
+    Tcp {
+        /// The `host:port`.
+        addr: String,
+        /// Where the daemon wrote the token clients have to send (see `tcp::Token`).
+        token_file: AbsPathBuf,
+    },
+}
+
 /// Binding to the socket failed.
 #[derive(Error, Debug)]
 pub enum BindError {
@@ -24,6 +40,9 @@ pub enum BindError {
     /// nix library I/O error (like Io)
     #[error("Unix error binding to socket")]
     Unix(#[source] nix::Error),
+    /// The socket passed by the service manager cannot be used
+    #[error("Socket activation failed")]
+    Activation(#[from] ActivationError),
 }
 
 impl ExitAs for BindError {
@@ -34,6 +53,7 @@ impl ExitAs for BindError {
             OtherProcessListening(_) => UserError,
             Io(_) => Temporary,
             Unix(_) => Temporary,
+            Activation(err) => err.exit_as(),
         }
     }
 }
@@ -95,6 +115,15 @@ impl SocketPath {
         Ok((l, lock))
     }
 
+    /// Like `bind`, but use the socket passed by the service manager if there is one
+    /// (see `socket::activation`), instead of replacing it with a new one.
+    pub fn bind_or_inherit(&self) -> Result<(UnixListener, BindLock), BindError> {
+        match activation::take_listener(self.as_absolute_path())? {
+            Some(listener) => Ok((listener, self.lock()?)),
+            None => self.bind(),
+        }
+    }
+
     /// `connect(2)` to this socket path.
     pub fn connect(&self) -> std::io::Result<UnixStream> {
         UnixStream::connect(self.as_absolute_path())
@@ -130,6 +159,56 @@ impl fmt::Display for SocketPath {
     }
 }
 
+impl DaemonAddress {
+    /// Prefix of TCP addresses in `LORRI_DAEMON_SOCKET` and `--daemon-socket`.
+    pub const TCP_PREFIX: &'static str = "tcp://";
+
+    /// Parse a TCP address like `tcp://127.0.0.1:4242`, whose token is in `token_file`.
+    /// `None` if `s` does not start with `TCP_PREFIX`.
+    pub fn parse_tcp(s: &str, token_file: &AbsPathBuf) -> Option<Result<DaemonAddress, String>> {
+        let addr = s.strip_prefix(Self::TCP_PREFIX)?;
+        Some(match addr.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+                Ok(DaemonAddress::Tcp {
+                    addr: addr.to_string(),
+                    token_file: token_file.clone(),
+                })
+            }
+            _ => Err(format!(
+                "{} is not a TCP address like {}127.0.0.1:4242",
+                s,
+                Self::TCP_PREFIX
+            )),
+        })
+    }
+
+    /// Connect to the daemon.
+    pub fn connect(&self) -> std::io::Result<UnixStream> {
+        match self {
+            DaemonAddress::Unix(socket_path) => socket_path.connect(),
+            DaemonAddress::Tcp { addr, token_file } => {
+                let token = crate::daemon::tcp::Token::read(token_file)?;
+                crate::daemon::tcp::connect(addr, &token)
+            }
+        }
+    }
+}
+
+impl From<SocketPath> for DaemonAddress {
+    fn from(socket_path: SocketPath) -> DaemonAddress {
+        DaemonAddress::Unix(socket_path)
+    }
+}
+
+impl fmt::Display for DaemonAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DaemonAddress::Unix(socket_path) => write!(f, "{}", socket_path),
+            DaemonAddress::Tcp { addr, .. } => write!(f, "{}{}", Self::TCP_PREFIX, addr),
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -146,4 +225,34 @@ mod test {
             "second locking attempt should fail because we still hold the lock"
         );
     }
+
+    #[test]
+    fn parse_tcp_addresses() {
+        let token_file = AbsPathBuf::new("/run/user/1000/lorri/daemon.token".into()).unwrap();
+        assert!(DaemonAddress::parse_tcp("/run/lorri/daemon.socket", &token_file).is_none());
+        assert!(matches!(
+            DaemonAddress::parse_tcp("tcp://127.0.0.1:4242", &token_file),
+            Some(Ok(DaemonAddress::Tcp { addr, .. })) if addr == "127.0.0.1:4242"
+        ));
+        assert!(matches!(
+            DaemonAddress::parse_tcp("tcp://[::1]:4242", &token_file),
+            Some(Ok(DaemonAddress::Tcp { addr, .. })) if addr == "[::1]:4242"
+        ));
+        assert!(matches!(
+            DaemonAddress::parse_tcp("tcp://localhost:4242", &token_file),
+            Some(Ok(_))
+        ));
+        assert!(matches!(
+            DaemonAddress::parse_tcp("tcp://127.0.0.1", &token_file),
+            Some(Err(_))
+        ));
+        assert!(matches!(
+            DaemonAddress::parse_tcp("tcp://:4242", &token_file),
+            Some(Err(_))
+        ));
+        assert!(matches!(
+            DaemonAddress::parse_tcp("tcp://127.0.0.1:99999", &token_file),
+            Some(Err(_))
+        ));
+    }
 }
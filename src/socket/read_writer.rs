@@ -1,5 +1,6 @@
 //! Talking to the `lorri` daemon / unix sockets.
 
+use crate::socket::wire::ToWire;
 use std::convert::TryFrom;
 use std::fmt;
 use std::io::Write;
@@ -14,10 +15,42 @@ use thiserror::Error;
 pub struct ReadWriter<'a, R, W> {
     // where R: serde::Deserialize {
     socket: &'a UnixStream,
+    encoding: Encoding,
     phantom_r: PhantomData<R>,
     phantom_w: PhantomData<W>,
 }
 
+/// How messages are encoded on the socket.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    /// `bincode`, as spoken by lorri itself.
+    Bincode,
+    /// One JSON value per line, for clients not written in Rust.
+    /// See `contrib/socket-protocol.md` for the schema.
+    Json,
+}
+
+impl Encoding {
+    /// Find out the encoding of the connection from the first byte
+    /// the client sends, without consuming it.
+    /// JSON clients start with an object, bincode clients never send a `{` first.
+    pub fn detect(socket: &UnixStream, timeout: Timeout) -> Result<Encoding, ReadError> {
+        let mut first = [0; 1];
+        let peeked = timeout::peek(socket, timeout, &mut first).map_err(|e| {
+            if e.kind() == std::io::ErrorKind::TimedOut {
+                ReadError::Timeout(timeout)
+            } else {
+                ReadError::Deserialize(Box::new(bincode::ErrorKind::Io(e)))
+            }
+        })?;
+        match &first[..peeked] {
+            b"{" => Ok(Encoding::Json),
+            // an empty peek means the client hung up, reading will tell
+            _ => Ok(Encoding::Bincode),
+        }
+    }
+}
+
 /// Milliseconds accepted by a `Timeout`.
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub struct Millis(u16);
@@ -67,6 +100,9 @@ pub enum ReadError {
     /// Deserializing `R` failed.
     #[error("Unable to deserialize message: {0}")]
     Deserialize(#[source] bincode::Error),
+    /// Deserializing `R` from JSON failed.
+    #[error("Unable to deserialize JSON message: {0}")]
+    DeserializeJson(#[source] serde_json::Error),
     /// No value available within given timeout.
     #[error("The read timed out ({0})")]
     Timeout(Timeout),
@@ -79,6 +115,9 @@ pub enum WriteError {
     /// Serializing `W` failed.
     #[error("Unable to serialize message: {0}")]
     Serialize(#[source] bincode::Error),
+    /// Serializing `W` to JSON failed.
+    #[error("Unable to serialize JSON message: {0}")]
+    SerializeJson(#[source] serde_json::Error),
     /// No value available within given timeout.
     #[error("The read timed out ({0})")]
     Timeout(Timeout),
@@ -149,8 +188,14 @@ impl<'a, R, W> ReadWriter<'a, R, W> {
     // TODO: &mut UnixStream
     /// Create from a unix socket.
     pub fn new(socket: &'a UnixStream) -> ReadWriter<'a, R, W> {
+        Self::with_encoding(socket, Encoding::Bincode)
+    }
+
+    /// Create from a unix socket, speaking `encoding`.
+    pub fn with_encoding(socket: &'a UnixStream, encoding: Encoding) -> ReadWriter<'a, R, W> {
         ReadWriter {
             socket,
+            encoding,
             phantom_r: PhantomData,
             phantom_w: PhantomData,
         }
@@ -211,6 +256,17 @@ impl<'a, R, W> ReadWriter<'a, R, W> {
     where
         R: serde::de::DeserializeOwned,
     {
+        if self.encoding == Encoding::Json {
+            let line = timeout::read_line(self.socket, timeout).map_err(|e| {
+                if e.kind() == std::io::ErrorKind::TimedOut {
+                    ReadError::Timeout(timeout)
+                } else {
+                    ReadError::DeserializeJson(serde_json::Error::io(e))
+                }
+            })?;
+            return serde_json::from_slice(&line).map_err(ReadError::DeserializeJson);
+        }
+
         let timeout_socket = timeout::TimeoutReadWriter::new(self.socket, timeout);
 
         // XXX: “If this returns an Error, `reader` may be in an invalid state”.
@@ -226,6 +282,47 @@ impl<'a, R, W> ReadWriter<'a, R, W> {
 
     /// Send a message to the other side.
     pub fn write(&mut self, timeout: Timeout, mes: &W) -> Result<(), WriteError>
+    where
+        W: serde::Serialize,
+    {
+        match self.encoding {
+            Encoding::Json => self.write_json(timeout, mes),
+            Encoding::Bincode => self.write_bincode(timeout, mes),
+        }
+    }
+
+    /// Send a message of the daemon to the other side.
+    /// JSON clients receive its `wire` type instead of lorri’s internal type.
+    pub fn write_wire(&mut self, timeout: Timeout, mes: &W) -> Result<(), WriteError>
+    where
+        W: serde::Serialize + ToWire,
+    {
+        match self.encoding {
+            Encoding::Json => self.write_json(timeout, &mes.to_wire()),
+            Encoding::Bincode => self.write_bincode(timeout, mes),
+        }
+    }
+
+    fn write_json<J>(&mut self, timeout: Timeout, mes: &J) -> Result<(), WriteError>
+    where
+        J: serde::Serialize,
+    {
+        let mut timeout_socket = timeout::TimeoutReadWriter::new(self.socket, timeout);
+        let mut line = serde_json::to_vec(mes).map_err(WriteError::SerializeJson)?;
+        line.push(b'\n');
+        timeout_socket
+            .write_all(&line)
+            .and_then(|()| timeout_socket.flush())
+            .map_err(|e| {
+                if e.kind() == std::io::ErrorKind::TimedOut {
+                    WriteError::Timeout(timeout)
+                } else {
+                    WriteError::SerializeJson(serde_json::Error::io(e))
+                }
+            })
+    }
+
+    fn write_bincode(&mut self, timeout: Timeout, mes: &W) -> Result<(), WriteError>
     where
         W: serde::Serialize,
     {
@@ -251,7 +348,9 @@ mod timeout {
 
     use self::nix::libc;
     use self::nix::poll;
+    use self::nix::sys::socket;
     use super::{Millis, Timeout};
+    use std::io::Read;
     use std::os::unix::io::AsRawFd;
     use std::os::unix::net::UnixStream;
 
@@ -278,6 +377,40 @@ mod timeout {
         Ok(())
     }
 
+    /// Look at the next bytes on `socket` without consuming them.
+    /// Returns 0 if the other side hung up.
+    pub fn peek(socket: &UnixStream, t: Timeout, buf: &mut [u8]) -> std::io::Result<usize> {
+        wait_until_ready(to_poll_2_timeout(t), socket, poll::PollFlags::POLLIN)?;
+        socket::recv(socket.as_raw_fd(), buf, socket::MsgFlags::MSG_PEEK).map_err(|e| {
+            e.as_errno()
+                .map_or_else(|| std::io::ErrorKind::Other.into(), std::io::Error::from)
+        })
+    }
+
+    /// Read up to and including the next newline, returning the line without it.
+    /// Never reads past the newline, so the socket can be passed on afterwards.
+    pub fn read_line(socket: &UnixStream, t: Timeout) -> std::io::Result<Vec<u8>> {
+        let mut line = Vec::new();
+        let mut buf = [0; 4096];
+        loop {
+            let peeked = peek(socket, t, &mut buf)?;
+            if peeked == 0 {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+            let (len, done) = match buf[..peeked].iter().position(|b| *b == b'\n') {
+                Some(newline) => (newline + 1, true),
+                None => (peeked, false),
+            };
+            let start = line.len();
+            line.resize(start + len, 0);
+            TimeoutReadWriter::new(socket, t).read_exact(&mut line[start..])?;
+            if done {
+                line.pop();
+                return Ok(line);
+            }
+        }
+    }
+
     pub struct TimeoutReadWriter<'a> {
         socket: &'a UnixStream,
         timeout: libc::c_int,
@@ -0,0 +1,436 @@
+//! The JSON representation of what the daemon sends.
+//!
+//! lorri’s internal types change from one version to the next, so JSON clients
+//! of the socket (see `contrib/socket-protocol.md`) and `lorri events --json
+//! --format-version 1` (see `contrib/stream-events.md`) get these types instead.
+//! Only ever change them in backwards-compatible ways: add fields and new `type`s.
+
+use crate::build_loop::{self, EventI, ReasonI};
+use crate::builder::{BuildError, LogLine};
+use crate::project::pins::ChangedPin;
+use crate::socket::communicate;
+use crate::watch;
+use crate::NixFile;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A message that JSON clients receive as its wire type.
+pub trait ToWire {
+    /// The JSON representation.
+    type Wire: serde::Serialize;
+    /// Convert to the JSON representation.
+    fn to_wire(&self) -> Self::Wire;
+}
+
+/// A build event.
+#[derive(Serialize)]
+pub struct Event {
+    /// Always 1, the version of `contrib/stream-events.md` this event follows.
+    pub format_version: u32,
+    /// When the daemon recorded the event, in milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    /// What happened.
+    #[serde(flatten)]
+    pub event: EventType,
+}
+
+/// What happened, with the `type` of the event as tag.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventType {
+    /// The end of the snapshot of the last event of every project.
+    SnapshotEnd,
+    /// A build started.
+    Started {
+        /// The project’s nix file.
+        nix_file: String,
+        /// Why the build started.
+        reason: Reason,
+    },
+    /// A build succeeded.
+    Completed {
+        /// The project’s nix file.
+        nix_file: String,
+        /// The GC roots of the build.
+        output_paths: OutputPaths,
+    },
+    /// A build failed.
+    Failure {
+        /// The project’s nix file.
+        nix_file: String,
+        /// Why the build failed.
+        error: Error,
+    },
+    /// A build failed with a transient error and will be retried.
+    Retrying {
+        /// The project’s nix file.
+        nix_file: String,
+        /// Number of the retry, starting at 1.
+        attempt: u32,
+        /// Why the build failed.
+        error: Error,
+    },
+    /// The build loop of the project stopped unexpectedly and will be restarted.
+    Crashed {
+        /// The project’s nix file.
+        nix_file: String,
+        /// Why the loop stopped.
+        message: String,
+        /// Seconds until the loop is restarted.
+        restart_in_secs: u64,
+    },
+}
+
+/// Why a build started, with its `type` as tag.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Reason {
+    /// The daemon started watching the project.
+    ProjectAdded {
+        /// The project’s nix file.
+        nix_file: String,
+    },
+    /// A client pinged the project.
+    PingReceived,
+    /// Watched files changed.
+    FilesChanged {
+        /// The changed files.
+        files: Vec<String>,
+    },
+    /// A niv or npins pin changed.
+    PinsChanged {
+        /// The changed files, including the pin files.
+        files: Vec<String>,
+        /// The pins whose revision changed.
+        pins: Vec<Pin>,
+    },
+}
+
+/// A changed niv or npins pin.
+#[derive(Serialize)]
+pub struct Pin {
+    /// `niv` or `npins`.
+    pub tool: &'static str,
+    /// Name of the pinned source.
+    pub name: String,
+    /// Revision before the change, `None` if the pin was added.
+    pub old_revision: Option<String>,
+    /// Revision after the change, `None` if the pin was removed.
+    pub new_revision: Option<String>,
+}
+
+/// The GC roots of a successful build.
+#[derive(Serialize)]
+pub struct OutputPaths {
+    /// GC root of the shell environment.
+    pub shell_gc_root: String,
+}
+
+/// A build error.
+#[derive(Serialize)]
+pub struct Error {
+    /// The human-readable error message, as lorri prints it.
+    pub message: String,
+    /// What kind of error it is.
+    #[serde(flatten)]
+    pub detail: ErrorDetail,
+}
+
+/// The kind of a build error, with its `type` as tag.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ErrorDetail {
+    /// An IO error.
+    Io,
+    /// nix could not be started.
+    Spawn {
+        /// The nix command.
+        command: String,
+    },
+    /// nix exited with an error.
+    Exit {
+        /// The nix command.
+        command: String,
+        /// Exit code of the command, `None` if it was killed by a signal.
+        exit_code: Option<i32>,
+        /// The error output of the command, one string per line.
+        logs: Vec<String>,
+    },
+    /// nix ran longer than its timeout and was killed.
+    Timeout {
+        /// The nix command.
+        command: String,
+        /// The timeout the command exceeded.
+        timeout_secs: u64,
+        /// The error output of the command, one string per line.
+        logs: Vec<String>,
+    },
+    /// The output of nix could not be understood.
+    Output,
+}
+
+fn nix_file_string(nix_file: NixFile) -> String {
+    nix_file.display().to_string()
+}
+
+/// Paths need not be UTF-8, but JSON strings must be.
+fn path_strings(paths: &[PathBuf]) -> Vec<String> {
+    paths
+        .iter()
+        .map(|path| path.to_string_lossy().into_owned())
+        .collect()
+}
+
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+impl Event {
+    /// The wire type of `event`, which the daemon recorded at `time`.
+    pub fn new(event: build_loop::Event, time: SystemTime) -> Event {
+        let event = match event {
+            EventI::SectionEnd => EventType::SnapshotEnd,
+            EventI::Started { nix_file, reason } => EventType::Started {
+                nix_file: nix_file_string(nix_file),
+                reason: match reason {
+                    ReasonI::ProjectAdded(nix_file) => Reason::ProjectAdded {
+                        nix_file: nix_file_string(nix_file),
+                    },
+                    ReasonI::PingReceived => Reason::PingReceived,
+                    ReasonI::FilesChanged(files) => Reason::FilesChanged {
+                        files: path_strings(&files),
+                    },
+                    ReasonI::PinsChanged { files, pins } => Reason::PinsChanged {
+                        files: path_strings(&files),
+                        pins: pins.into_iter().map(Pin::from).collect(),
+                    },
+                },
+            },
+            EventI::Completed {
+                nix_file,
+                rooted_output_paths,
+            } => EventType::Completed {
+                nix_file: nix_file_string(nix_file),
+                output_paths: OutputPaths {
+                    shell_gc_root: rooted_output_paths.shell_gc_root.display().to_string(),
+                },
+            },
+            EventI::Failure { nix_file, failure } => EventType::Failure {
+                nix_file: nix_file_string(nix_file),
+                error: Error::from(failure),
+            },
+            EventI::Retrying {
+                nix_file,
+                attempt,
+                failure,
+            } => EventType::Retrying {
+                nix_file: nix_file_string(nix_file),
+                attempt,
+                error: Error::from(failure),
+            },
+            EventI::Crashed {
+                nix_file,
+                reason,
+                restart_in_secs,
+            } => EventType::Crashed {
+                nix_file: nix_file_string(nix_file),
+                message: reason,
+                restart_in_secs,
+            },
+        };
+        Event {
+            format_version: 1,
+            timestamp: millis(time),
+            event,
+        }
+    }
+}
+
+impl From<ChangedPin> for Pin {
+    fn from(pin: ChangedPin) -> Pin {
+        Pin {
+            tool: pin.tool.name(),
+            name: pin.name,
+            old_revision: pin.old_revision,
+            new_revision: pin.new_revision,
+        }
+    }
+}
+
+impl From<BuildError> for Error {
+    fn from(error: BuildError) -> Error {
+        fn logs(logs: &[LogLine]) -> Vec<String> {
+            logs.iter()
+                .map(|l| String::from_utf8_lossy(l.0.as_bytes()).into_owned())
+                .collect()
+        }
+        let message = error.to_string();
+        let detail = match error {
+            BuildError::Io { .. } => ErrorDetail::Io,
+            BuildError::Spawn { cmd, .. } => ErrorDetail::Spawn { command: cmd },
+            BuildError::Exit {
+                cmd,
+                status,
+                logs: l,
+                ..
+            } => ErrorDetail::Exit {
+                command: cmd,
+                exit_code: status,
+                logs: logs(&l),
+            },
+            BuildError::Timeout {
+                cmd,
+                timeout,
+                logs: l,
+                ..
+            } => ErrorDetail::Timeout {
+                command: cmd,
+                timeout_secs: timeout.as_secs(),
+                logs: logs(&l),
+            },
+            BuildError::Output { .. } => ErrorDetail::Output,
+        };
+        Error { message, detail }
+    }
+}
+
+/// Events of older clients’ `StreamEvents` carry no timestamp,
+/// JSON clients get the time the daemon sent them.
+impl ToWire for build_loop::Event {
+    type Wire = Event;
+    fn to_wire(&self) -> Event {
+        Event::new(self.clone(), SystemTime::now())
+    }
+}
+
+/// An event of `FilteredStreamEvents`.
+#[derive(Serialize)]
+pub struct StampedEvent {
+    /// The user the project is built for, `None` unless the daemon serves multiple users.
+    pub uid: Option<u32>,
+    /// The event.
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+impl ToWire for communicate::StampedEvent {
+    type Wire = StampedEvent;
+    fn to_wire(&self) -> StampedEvent {
+        StampedEvent {
+            uid: self.uid,
+            event: Event::new(self.event.clone(), self.time()),
+        }
+    }
+}
+
+/// A reply to `AcknowledgedPing`, with its `type` as tag.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PingResponse {
+    /// The ping was received.
+    Ack {
+        /// What the daemon did with the ping.
+        ack: communicate::PingAck,
+    },
+    /// The build the client waits for finished.
+    Finished {
+        /// A `completed`, `failure` or `crashed` event.
+        event: Event,
+    },
+    /// The daemon could not handle the ping, and closes the connection.
+    Error {
+        /// What went wrong.
+        message: String,
+    },
+}
+
+impl ToWire for communicate::PingResponse {
+    type Wire = PingResponse;
+    fn to_wire(&self) -> PingResponse {
+        match self {
+            communicate::PingResponse::Ack(ack) => PingResponse::Ack { ack: *ack },
+            communicate::PingResponse::Finished(event) => PingResponse::Finished {
+                event: event.to_wire(),
+            },
+            communicate::PingResponse::Error(message) => PingResponse::Error {
+                message: message.clone(),
+            },
+        }
+    }
+}
+
+/// The reply to `DaemonInfo`.
+#[derive(Serialize)]
+pub struct DaemonStatus {
+    /// Every project the daemon is watching (for the client’s user).
+    pub projects: Vec<ProjectStatus>,
+}
+
+/// What the daemon knows about a single project.
+#[derive(Serialize)]
+pub struct ProjectStatus {
+    /// The project’s nix file.
+    pub nix_file: String,
+    /// State of the file watcher of the project.
+    pub watch: WatchStatus,
+    /// If the build loop of the project crashed (and is being restarted), why.
+    pub crashed: Option<String>,
+}
+
+/// State of the file watcher of a project.
+#[derive(Serialize)]
+pub struct WatchStatus {
+    /// Number of paths with an active watch.
+    pub watched: usize,
+    /// How many of the `watched` paths are polled, because the watch limit was hit.
+    pub polled: usize,
+    /// Set once the watch limit was hit.
+    pub limit_exceeded: Option<WatchLimitExceeded>,
+    /// Paths that could not be read, and are thus not watched.
+    pub unreadable: Vec<String>,
+}
+
+/// The kernel refused to add more inotify watches.
+#[derive(Serialize)]
+pub struct WatchLimitExceeded {
+    /// The limit of inotify watches per user, `None` if it could not be read.
+    pub limit: Option<u64>,
+    /// Number of watches of all projects when the limit was hit.
+    pub watched: usize,
+}
+
+impl ToWire for communicate::DaemonStatus {
+    type Wire = DaemonStatus;
+    fn to_wire(&self) -> DaemonStatus {
+        DaemonStatus {
+            projects: self
+                .projects
+                .iter()
+                .map(|project| ProjectStatus {
+                    nix_file: nix_file_string(project.nix_file.clone()),
+                    watch: WatchStatus::from(&project.watch),
+                    crashed: project.crashed.clone(),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<&watch::WatchStatus> for WatchStatus {
+    fn from(status: &watch::WatchStatus) -> WatchStatus {
+        WatchStatus {
+            watched: status.watched,
+            polled: status.polled,
+            limit_exceeded: status
+                .limit_exceeded
+                .as_ref()
+                .map(|limit| WatchLimitExceeded {
+                    limit: limit.limit,
+                    watched: limit.watched,
+                }),
+            unreadable: path_strings(&status.unreadable),
+        }
+    }
+}
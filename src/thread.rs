@@ -6,6 +6,10 @@
 //! join a thread once we know it has completed execution, meaning
 //! we don't block joining one thread while another thread has panicked
 //! already.
+//!
+//! Instead of joining all threads at once, a supervisor can `select` on
+//! `Pool::deaths` next to its own channels, so it keeps spawning new threads
+//! while it joins (and possibly restarts) the ones that died.
 
 use crossbeam_channel as chan;
 use slog::debug;
@@ -35,8 +39,18 @@ pub enum Cause<Err> {
     Paniced(Box<dyn Any + Send>),
 }
 
+/// The message of a panic payload, if it is a string (as it is for `panic!`).
+pub fn panic_message(panic: &(dyn Any + Send)) -> String {
+    match (panic.downcast_ref::<&str>(), panic.downcast_ref::<String>()) {
+        (Some(msg), _) => msg.to_string(),
+        (_, Some(msg)) => msg.clone(),
+        (None, None) => "<non-string panic payload>".to_string(),
+    }
+}
+
 /// A thread pool for joining many threads at once, panicking
 /// if any of the threads panicked.
+/// Can also be used to supervise threads, see `Pool::deaths`.
 pub struct Pool<Err> {
     threads: HashMap<ThreadId, Thread>,
     tx: chan::Sender<Dead<Err>>,
@@ -57,8 +71,8 @@ impl<Err> Pool<Err> {
     }
 
     /// Spawn a sub-thread which is joined at the same time as all the
-    /// remaining threads.
-    pub fn spawn<N, F>(&mut self, name: N, f: F) -> Result<(), std::io::Error>
+    /// remaining threads. Returns the id of the new thread.
+    pub fn spawn<N, F>(&mut self, name: N, f: F) -> Result<ThreadId, std::io::Error>
     where
         N: Into<String>,
         F: FnOnce() -> Result<(), Err>,
@@ -86,15 +100,49 @@ impl<Err> Pool<Err> {
             }
         })?;
 
+        let thread_id = handle.thread().id();
         self.threads.insert(
-            handle.thread().id(),
+            thread_id,
             Thread {
                 name,
                 join_handle: handle,
             },
         );
 
-        Ok(())
+        Ok(thread_id)
+    }
+
+    /// Receives a `Dead` for every thread of this pool once it finished.
+    ///
+    /// Every message must be passed to `join_dead`. Since the pool is only
+    /// borrowed while spawning, new threads can be spawned in between.
+    pub fn deaths(&self) -> chan::Receiver<Dead<Err>> {
+        self.rx.clone()
+    }
+
+    /// Join the thread that sent `death`, returning its name and its cause of death.
+    ///
+    /// This does not block, because the thread has finished already.
+    pub fn join_dead(&mut self, death: Dead<Err>) -> (String, Cause<Err>) {
+        let thread = self
+            .threads
+            .remove(&death.thread_id)
+            .expect("thread pool: Failed to find thread ID in handle table");
+
+        let name = thread.name;
+        thread
+            .join_handle
+            .join()
+            // If the thread panics without an unwindable panic,
+            // there’s nothing we can do here.
+            // Otherwise the stack is unrolled via Cause::Paniced
+            .unwrap_or_else(|_any| {
+                panic!(
+                    "thread pool: thread {} paniced and we were unable to unwind it",
+                    name
+                )
+            });
+        (name, death.cause)
     }
 
     /// Attempt to join all threads, and if any of them panicked,
@@ -110,26 +158,7 @@ impl<Err> Pool<Err> {
                 .recv()
                 .expect("thread pool: Failed to receive a ThreadResult, even though there are more threads.");
 
-            let thread = self
-                .threads
-                .remove(&death.thread_id)
-                .expect("thread pool: Failed to find thread ID in handle table");
-
-            let name = thread.name;
-            thread
-                .join_handle
-                .join()
-                // If the thread panics without an unwindable panic,
-                // there’s nothing we can do here.
-                // Otherwise the stack is unrolled via Cause::Paniced
-                .unwrap_or_else(|_any| {
-                    panic!(
-                        "thread pool: thread {} paniced and we were unable to unwind it",
-                        name
-                    )
-                });
-
-            match death.cause {
+            match self.join_dead(death).1 {
                 // The thread died successfully
                 Cause::Natural(Ok(())) => {}
                 // The thread didn’t panic, it returned an error, so we return early
@@ -139,3 +168,34 @@ impl<Err> Pool<Err> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{panic_message, Cause, Pool};
+
+    #[test]
+    fn spawn_while_joining() {
+        let mut pool: Pool<String> = Pool::new(crate::logging::test_logger("thread-pool"));
+        let deaths = pool.deaths();
+        let first = pool.spawn("first", || Err("oops".to_string())).unwrap();
+
+        let death = deaths.recv().unwrap();
+        assert_eq!(death.thread_id, first);
+        match pool.join_dead(death) {
+            (name, Cause::Natural(Err(err))) => {
+                assert_eq!((name.as_str(), err.as_str()), ("first", "oops"))
+            }
+            _ => panic!("first thread should have returned an error"),
+        }
+
+        // the pool can still be used after a thread died
+        let second = pool.spawn("second", || panic!("at the disco")).unwrap();
+        let death = deaths.recv().unwrap();
+        assert_eq!(death.thread_id, second);
+        match pool.join_dead(death) {
+            (_, Cause::Paniced(panic)) => assert_eq!(panic_message(&*panic), "at the disco"),
+            _ => panic!("second thread should have panicked"),
+        }
+        assert_eq!(pool.join_all_or_panic(), Ok(()));
+    }
+}
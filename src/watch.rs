@@ -4,13 +4,15 @@
 use chan::{select, Receiver, Sender};
 use crossbeam_channel as chan;
 use notify::event::ModifyKind;
-use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use notify::{EventKind, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher, WatcherKind};
 use notify_debouncer_full::{DebounceEventResult, DebouncedEvent, Debouncer, FileIdMap};
 use slog::{debug, info, warn};
-use std::collections::HashSet;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Mutex;
 use std::time::Duration;
+use thiserror::Error;
 
 use crate::run_async::{Async, StopSignal};
 
@@ -52,11 +54,160 @@ impl WatchPathBuf {
     }
 }
 
+/// How changes to watched files are detected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WatchBackend {
+    /// Use `Native` unless the project lives on a filesystem
+    /// that is known not to deliver native events (see `WatchConfig::resolve_backend`).
+    Auto,
+    /// The operating system’s notification mechanism (inotify, FSEvents, …).
+    Native,
+    /// Periodically scan all watched files (every `POLL_INTERVAL`).
+    /// Slower, but works on every filesystem.
+    Poll,
+}
+
+/// How often the polling backend rescans the watched files.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Tuning knobs for how filesystem events are grouped before they are reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchConfig {
+    /// Window in which the low-level events for a single file are debounced
+    /// into one event.
+    pub debounce: Duration,
+    /// If set, changes are only reported after no further events arrived
+    /// for this long. All paths changed in the meantime are reported together.
+    pub quiet_period: Option<Duration>,
+    /// How to detect changes.
+    pub backend: WatchBackend,
+}
+
+impl Default for WatchConfig {
+    fn default() -> Self {
+        WatchConfig {
+            debounce: Duration::from_millis(200),
+            quiet_period: None,
+            backend: WatchBackend::Auto,
+        }
+    }
+}
+
+impl WatchConfig {
+    /// Decide on a concrete backend if it is `Auto`.
+    ///
+    /// Network and FUSE filesystems (NFS, SMB, sshfs, 9p, …) accept inotify
+    /// watches, but never deliver events for changes made on another host
+    /// or by the FUSE daemon, so we poll if `path` lives on one of them.
+    pub fn resolve_backend(self, path: &Path) -> WatchConfig {
+        let backend = match self.backend {
+            WatchBackend::Auto if lacks_native_events(path) => WatchBackend::Poll,
+            WatchBackend::Auto => WatchBackend::Native,
+            backend => backend,
+        };
+        WatchConfig { backend, ..self }
+    }
+}
+
+/// Whether `path` is on a filesystem known not to support native change events.
+#[cfg(target_os = "linux")]
+fn lacks_native_events(path: &Path) -> bool {
+    // magic numbers from statfs(2)
+    const NFS: u32 = 0x6969;
+    const SMB: u32 = 0x517B;
+    const CIFS: u32 = 0xFF53_4D42;
+    const SMB2: u32 = 0xFE53_4D42;
+    const FUSE: u32 = 0x6573_5546;
+    const V9FS: u32 = 0x0102_1997;
+    match nix::sys::statfs::statfs(path) {
+        // the type of the magic is platform-dependent, and the constants fit into 32 bits
+        Ok(stat) => matches!(
+            stat.filesystem_type().0 as u32,
+            NFS | SMB | CIFS | SMB2 | FUSE | V9FS
+        ),
+        Err(_) => false,
+    }
+}
+
+/// Whether `path` is on a filesystem known not to support native change events.
+#[cfg(not(target_os = "linux"))]
+fn lacks_native_events(_path: &Path) -> bool {
+    false
+}
+
+/// Number of native watches registered by all `Watch`es of this process.
+static NATIVE_WATCHES: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of native watches (e.g. inotify watches) all projects of this process hold.
+pub fn native_watch_count() -> usize {
+    NATIVE_WATCHES.load(Ordering::Relaxed)
+}
+
+/// The kernel refused to add more inotify watches.
+///
+/// Watching continues in a degraded mode: files that could not be watched
+/// themselves are watched through their parent directory if that is watched,
+/// and polled otherwise.
+#[derive(Error, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[error(
+    "reached the inotify watch limit (fs.inotify.max_user_watches = {}) with {watched} paths watched by lorri; \
+     the paths that did not fit are polled, so changes might be picked up late. \
+     Raise the limit, e.g. with `sysctl fs.inotify.max_user_watches=524288`",
+    .limit.map_or_else(|| "unknown".to_string(), |l| l.to_string())
+)]
+pub struct WatchLimitExceeded {
+    /// The system-wide limit of inotify watches per user, if it could be read.
+    pub limit: Option<u64>,
+    /// Number of native watches of all projects when the limit was hit.
+    /// Other programs of the same user count against the limit as well.
+    pub watched: usize,
+}
+
+impl WatchLimitExceeded {
+    fn new() -> WatchLimitExceeded {
+        WatchLimitExceeded {
+            limit: std::fs::read_to_string("/proc/sys/fs/inotify/max_user_watches")
+                .ok()
+                .and_then(|l| l.trim().parse().ok()),
+            watched: native_watch_count(),
+        }
+    }
+}
+
+/// Whether `err` means the system does not allow any more watches.
+fn is_watch_limit(err: &notify::Error) -> bool {
+    match &err.kind {
+        notify::ErrorKind::MaxFilesWatch => true,
+        notify::ErrorKind::Io(err) => err.raw_os_error() == Some(nix::libc::ENOSPC),
+        _ => false,
+    }
+}
+
+/// Current state of a `Watch`, sent every time the watch list was extended.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WatchStatus {
+    /// Number of paths with an active watch.
+    pub watched: usize,
+    /// How many of the `watched` paths are polled, because the watch limit was hit.
+    pub polled: usize,
+    /// Set once the watch limit was hit.
+    pub limit_exceeded: Option<WatchLimitExceeded>,
+    /// Paths that could not be read, and are thus not watched.
+    pub unreadable: Vec<PathBuf>,
+}
+
 /// A dynamic list of paths to watch for changes, and
 /// react to changes when they occur.
 ///
 /// It runs a thread, which is stopped once this struct is dropped.
 pub struct Watch {
+    /// Thread that waits for events.
+    ///
+    /// Declared first so it is dropped (and sent its stop signal) before
+    /// the channels below disconnect and make the thread return on its own.
+    #[allow(dead_code)]
+    watch_thread: Async<()>,
     /// Receives watch events. When receiving events, run `Watch::process` on them
     pub watch_events_rx: chan::Receiver<Vec<PathBuf>>,
     /// Extend the watch list with an additional list of paths.
@@ -64,43 +215,73 @@ pub struct Watch {
     /// Note: Watch maintains a list of already watched paths, and
     /// will not add duplicates.
     pub add_to_watch_tx: chan::Sender<Vec<WatchPathBuf>>,
-    /// Thread that waits for events.
-    #[allow(dead_code)]
-    watch_thread: Async<()>,
+    /// Replace the watch list with a new list of paths.
+    ///
+    /// Paths that were added through `add_to_watch_tx` stay watched,
+    /// all others that are not in the new list are unwatched.
+    pub replace_watch_tx: chan::Sender<Vec<WatchPathBuf>>,
+    /// Receives the new `WatchStatus` after the watch list was changed.
+    pub watch_status_rx: chan::Receiver<WatchStatus>,
 }
 
 impl Watch {
     /// Instantiate a new Watch.
-    pub fn new(logger: &slog::Logger) -> Result<Watch, notify::Error> {
-        Self::new_impl(logger, None)
+    pub fn new(logger: &slog::Logger, config: WatchConfig) -> Result<Watch, notify::Error> {
+        Self::new_impl(logger, config, None)
     }
 
     fn new_impl(
         logger: &slog::Logger,
+        config: WatchConfig,
         drop_first_event_within: Option<Duration>,
     ) -> Result<Watch, notify::Error> {
         let (filtered_events_tx, filtered_events_rx) = chan::unbounded();
         let (user_requests_tx, user_requests_rx) = chan::unbounded();
-
-        let mut filter = Mutex::new(Filter::new(
-            user_requests_rx,
-            filtered_events_tx,
-            drop_first_event_within,
-            logger,
-        )?);
-        let watch_thread = Async::run_with_stop_signal(logger, move |stop_signal_rx| {
-            filter
-                .get_mut()
-                .expect("watcher mutex poisoned")
-                .loop_on_events(stop_signal_rx)
-        });
+        let (replace_requests_tx, replace_requests_rx) = chan::unbounded();
+        let (watch_status_tx, watch_status_rx) = chan::unbounded();
+
+        let watch_thread = match config.backend {
+            WatchBackend::Poll => Self::spawn_filter(Filter::<PollWatcher>::new(
+                user_requests_rx,
+                replace_requests_rx,
+                filtered_events_tx,
+                watch_status_tx,
+                config,
+                drop_first_event_within,
+                logger,
+            )?),
+            WatchBackend::Native | WatchBackend::Auto => {
+                Self::spawn_filter(Filter::<RecommendedWatcher>::new(
+                    user_requests_rx,
+                    replace_requests_rx,
+                    filtered_events_tx,
+                    watch_status_tx,
+                    config,
+                    drop_first_event_within,
+                    logger,
+                )?)
+            }
+        };
 
         Ok(Watch {
             watch_events_rx: filtered_events_rx,
             add_to_watch_tx: user_requests_tx,
+            replace_watch_tx: replace_requests_tx,
+            watch_status_rx,
             watch_thread,
         })
     }
+
+    fn spawn_filter<W: Watcher + Send + 'static>(filter: Filter<W>) -> Async<()> {
+        let logger = filter.logger.clone();
+        let mut filter = Mutex::new(filter);
+        Async::run_with_stop_signal(&logger, move |stop_signal_rx| {
+            filter
+                .get_mut()
+                .expect("watcher mutex poisoned")
+                .loop_on_events(stop_signal_rx)
+        })
+    }
 }
 
 /// A debug message string that can only be displayed via `Debug`.
@@ -113,47 +294,123 @@ struct FilteredOut<'a> {
     path: PathBuf,
 }
 
-struct Filter {
+/// Size and content hash of a watched regular file.
+///
+/// Used to recognize events for writes that did not actually change
+/// the file (`touch`, editors rewriting unchanged buffers, `git checkout`
+/// of an identical tree), which we don’t want to trigger a rebuild.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct FileFingerprint {
+    len: u64,
+    hash: md5::Digest,
+}
+
+impl FileFingerprint {
+    /// Fingerprint the file at `path`.
+    ///
+    /// Returns `None` if the path is not a readable regular file.
+    fn of(path: &Path) -> Option<FileFingerprint> {
+        let metadata = std::fs::metadata(path).ok()?;
+        if !metadata.is_file() {
+            return None;
+        }
+        let mut context = md5::Context::new();
+        std::io::copy(&mut std::fs::File::open(path).ok()?, &mut context).ok()?;
+        Some(FileFingerprint {
+            len: metadata.len(),
+            hash: context.compute(),
+        })
+    }
+}
+
+struct Filter<W: Watcher> {
     /// The low-level watcher
-    filesystem_watcher: Debouncer<RecommendedWatcher, FileIdMap>,
+    filesystem_watcher: Debouncer<W, FileIdMap>,
+    /// Polls the paths `filesystem_watcher` could not watch because of the watch limit.
+    /// Only started once the limit is hit.
+    fallback_watcher: Option<Debouncer<PollWatcher, FileIdMap>>,
+    /// Sends into `filesystem_events_rx`, kept for starting `fallback_watcher`
+    filesystem_events_tx: Sender<DebounceEventResult>,
     /// Unfiltered events from `notify` library
     filesystem_events_rx: Receiver<DebounceEventResult>,
     /// User requests to add more paths to our watcher
     user_requests_rx: Receiver<Vec<WatchPathBuf>>,
+    /// User requests to replace the paths of our watcher
+    replace_requests_rx: Receiver<Vec<WatchPathBuf>>,
     /// Channel we send filtered messages to
     filtered_events_tx: Sender<Vec<PathBuf>>,
+    /// Channel we send our status to after extending the watch list
+    watch_status_tx: Sender<WatchStatus>,
+    /// Paths added with `extend`, which are never unwatched
+    permanent: Vec<WatchPathBuf>,
     /// Set of currently watched paths
     current_watched: HashSet<PathBuf>,
+    /// Every path we registered with the low-level watcher,
+    /// including parent directories
+    registered: HashSet<PathBuf>,
+    /// Every path we registered with `fallback_watcher`
+    polled: HashSet<PathBuf>,
+    /// Set once the low-level watcher refused to add more watches
+    limit_exceeded: Option<WatchLimitExceeded>,
+    /// Paths we skipped because they could not be read
+    unreadable: BTreeSet<PathBuf>,
+    /// Last known fingerprint of every watched regular file
+    fingerprints: HashMap<PathBuf, FileFingerprint>,
+    /// Window in which events for a single file are debounced
+    debounce: Duration,
+    /// Wait for this long without events before reporting changes
+    quiet_period: Option<Duration>,
     // Whether to drop the first event if it arrives faster than the given duration (hack for macos tests)
     drop_first_event_within: Option<Duration>,
     logger: slog::Logger,
 }
 
-impl Filter {
+impl<W: Watcher> Filter<W> {
     fn new(
         user_requests_rx: Receiver<Vec<WatchPathBuf>>,
+        replace_requests_rx: Receiver<Vec<WatchPathBuf>>,
         filtered_events_tx: Sender<Vec<PathBuf>>,
+        watch_status_tx: Sender<WatchStatus>,
+        config: WatchConfig,
         drop_first_event_within: Option<Duration>,
         logger: &slog::Logger,
     ) -> notify::Result<Self> {
         let (filesystem_events_tx, filesystem_events_rx) = chan::unbounded();
 
         Ok(Filter {
-            filesystem_watcher: notify_debouncer_full::new_debouncer(
-                Duration::from_millis(200),
+            filesystem_watcher: notify_debouncer_full::new_debouncer_opt(
+                config.debounce,
                 None,
-                filesystem_events_tx,
+                filesystem_events_tx.clone(),
+                FileIdMap::new(),
+                notify::Config::default().with_poll_interval(POLL_INTERVAL),
             )?,
+            fallback_watcher: None,
+            filesystem_events_tx,
             filesystem_events_rx,
             user_requests_rx,
+            replace_requests_rx,
             filtered_events_tx,
+            watch_status_tx,
+            permanent: vec![],
             current_watched: HashSet::new(),
+            registered: HashSet::new(),
+            polled: HashSet::new(),
+            limit_exceeded: None,
+            unreadable: BTreeSet::new(),
+            fingerprints: HashMap::new(),
+            debounce: config.debounce,
+            quiet_period: config.quiet_period,
             logger: logger.clone(),
             drop_first_event_within,
         })
     }
 
     fn loop_on_events(&mut self, stop_signal_rx: chan::Receiver<StopSignal>) {
+        // Paths changed during the current quiet period, in order of first change
+        let mut pending_paths: Vec<PathBuf> = vec![];
+        // Fires once the quiet period passed without further events
+        let mut quiet_period_rx = chan::never();
         loop {
             let mut drop_event = false;
             let drop_first_event_within_rx = if let Some(dur) = self.drop_first_event_within {
@@ -178,6 +435,13 @@ impl Filter {
                     debug!(self.logger, "No event arrived within the initial drop timeout."; "duration" => ?self.drop_first_event_within);
                 }
 
+                // the quiet period is over, report everything that changed
+                recv(quiet_period_rx) -> _ => {
+                    quiet_period_rx = chan::never();
+                    debug!(self.logger, "quiet period over"; "paths" => ?pending_paths);
+                    self.send_filtered(std::mem::take(&mut pending_paths));
+                },
+
                 // Handle file events
                 recv(self.filesystem_events_rx) -> msg => match msg {
                     Ok( DebounceEventResult::Ok(event)) => {
@@ -187,8 +451,17 @@ impl Filter {
                         }
                         let paths = self.process_watch_events(event);
                         if !paths.is_empty() {
-                            if let Err(e) = self.filtered_events_tx.send(paths) {
-                                warn!(self.logger, "filtered_events_tx send error"; "error" => ?e)
+                            match self.quiet_period {
+                                None => self.send_filtered(paths),
+                                Some(quiet_period) => {
+                                    for path in paths {
+                                        if !pending_paths.contains(&path) {
+                                            pending_paths.push(path)
+                                        }
+                                    }
+                                    // every new change restarts the quiet period
+                                    quiet_period_rx = chan::after(quiet_period);
+                                }
                             }
                     }},
                     Ok(DebounceEventResult::Err(errs)) => {
@@ -208,20 +481,54 @@ impl Filter {
                         if let Err(e) = self.extend(paths) {
                                 warn!(self.logger, "error extending watch paths:"; "error" => ?e, "paths" => path_log)
                         }
+                        self.send_status();
                     },
                     Err(chan::RecvError) => {
                         debug!(self.logger, "watch extension channel was disconnected");
                         return
                     }
+                },
+
+                // Replace the files to watch
+                recv(self.replace_requests_rx) -> msg => match msg {
+                    Ok(paths) => {
+                        let path_log = format!("{:?}", paths);
+                        if let Err(e) = self.replace(paths) {
+                                warn!(self.logger, "error replacing watch paths:"; "error" => ?e, "paths" => path_log)
+                        }
+                        self.send_status();
+                    },
+                    Err(chan::RecvError) => {
+                        debug!(self.logger, "watch replace channel was disconnected");
+                        return
+                    }
                 }
             }
         }
     }
 
+    fn send_status(&self) {
+        let status = WatchStatus {
+            watched: self.registered.len() + self.polled.len(),
+            polled: self.polled.len(),
+            limit_exceeded: self.limit_exceeded.clone(),
+            unreadable: self.unreadable.iter().cloned().collect(),
+        };
+        if let Err(e) = self.watch_status_tx.send(status) {
+            debug!(self.logger, "watch_status_tx send error"; "error" => ?e)
+        }
+    }
+
+    fn send_filtered(&self, paths: Vec<PathBuf>) {
+        if let Err(e) = self.filtered_events_tx.send(paths) {
+            warn!(self.logger, "filtered_events_tx send error"; "error" => ?e)
+        }
+    }
+
     /// Process `notify::Event`s coming in via `Watch::rx`.
     ///
     /// Returns a list of „interesting“ paths, if any.
-    fn process_watch_events(&self, events: Vec<DebouncedEvent>) -> Vec<PathBuf> {
+    fn process_watch_events(&mut self, events: Vec<DebouncedEvent>) -> Vec<PathBuf> {
         let mut interesting_paths = vec![];
         for event in &events {
             {
@@ -255,6 +562,10 @@ impl Filter {
                 }
 
                 if self.path_match(path) {
+                    if self.content_unchanged(path) {
+                        debug!(self.logger, "suppressed event, file content did not change"; "path" => path.to_str(), "event" => ?kind);
+                        continue;
+                    }
                     interesting_paths.push((path, event))
                 }
             }
@@ -271,10 +582,77 @@ impl Filter {
     }
 
     /// Extend the watch list with an additional list of paths.
+    /// These stay watched for the lifetime of the `Watch`, see `replace`.
     ///
     /// Note: Watch maintains a list of already watched paths, and
     /// will not add duplicates.
     pub fn extend(&mut self, paths: Vec<WatchPathBuf>) -> Result<(), notify::Error> {
+        self.permanent.extend(paths.iter().cloned());
+        let paths = self.resolve(paths);
+        self.watch_all(paths)
+    }
+
+    /// Replace the watch list with a new list of paths.
+    ///
+    /// Paths that are neither in the new list nor were added by `extend`
+    /// are not watched anymore.
+    pub fn replace(&mut self, paths: Vec<WatchPathBuf>) -> Result<(), notify::Error> {
+        self.unreadable.clear();
+        let mut requested = self.permanent.clone();
+        requested.extend(paths);
+        let paths = self.resolve(requested);
+
+        // We also keep the parents of wanted paths registered;
+        // they are needed for noticing e.g. editors replacing files.
+        let wanted: HashSet<&Path> = paths.iter().map(|p| p.as_path()).collect();
+        let wanted_registered: HashSet<&Path> = paths
+            .iter()
+            .flat_map(|p| std::iter::once(p.as_path()).chain(p.parent()))
+            .collect();
+
+        self.current_watched
+            .retain(|p| wanted.contains(p.as_path()));
+        self.fingerprints
+            .retain(|p, _| wanted.contains(p.as_path()));
+        let unwanted: Vec<PathBuf> = self
+            .registered
+            .iter()
+            .filter(|p| !wanted_registered.contains(p.as_path()))
+            .cloned()
+            .collect();
+        for p in unwanted {
+            debug!(self.logger, "unwatching path"; "path" => p.to_str());
+            self.registered.remove(&p);
+            if Self::counts_native_watches() {
+                NATIVE_WATCHES.fetch_sub(1, Ordering::Relaxed);
+            }
+            // fails if the path was removed in the meantime, in which case there is nothing to unwatch
+            if let Err(e) = self.filesystem_watcher.watcher().unwatch(&p) {
+                debug!(self.logger, "could not unwatch path"; "path" => p.to_str(), "error" => %e);
+            }
+        }
+        let unwanted_polled: Vec<PathBuf> = self
+            .polled
+            .iter()
+            .filter(|p| !wanted.contains(p.as_path()))
+            .cloned()
+            .collect();
+        for p in unwanted_polled {
+            debug!(self.logger, "no longer polling path"; "path" => p.to_str());
+            self.polled.remove(&p);
+            if let Some(poller) = &mut self.fallback_watcher {
+                if let Err(e) = poller.watcher().unwatch(&p) {
+                    debug!(self.logger, "could not unwatch path"; "path" => p.to_str(), "error" => %e);
+                }
+            }
+        }
+
+        self.watch_all(paths)
+    }
+
+    /// Turn watch requests into the list of canonical paths that need a watch.
+    fn resolve(&mut self, paths: Vec<WatchPathBuf>) -> Vec<PathBuf> {
+        let mut res = vec![];
         for path in paths {
             // NOTE: notify.watch supports recursively watching directories itself, but we
             // 1) want to canonicalize each path we watch
@@ -282,12 +660,24 @@ impl Filter {
             // Plus, notify.watch will itself just walk the directories and watch things one-by-one
             // (at least for the `inotify` backend), so all is good on the performance front.
             let recursive_paths = match path {
-                WatchPathBuf::Recursive(path) => walk_path_topo(path)?,
+                WatchPathBuf::Recursive(path) => {
+                    let (paths, unreadable) = walk_path_topo(path);
+                    for (path, err) in unreadable {
+                        self.skip_unreadable(path, &err);
+                    }
+                    paths
+                }
                 WatchPathBuf::Normal(path) => vec![path],
             };
 
             for p_raw in recursive_paths {
-                let p = p_raw.canonicalize()?;
+                let p = match p_raw.canonicalize() {
+                    Ok(p) => p,
+                    Err(err) => {
+                        self.skip_unreadable(p_raw, &err);
+                        continue;
+                    }
+                };
                 if p.starts_with(Path::new("/nix/store")) {
                     debug!(
                         self.logger,
@@ -296,31 +686,151 @@ impl Filter {
                         "starts with /nix/store"
                     )
                 } else {
-                    let this = &mut *self;
-                    if !this.current_watched.contains(&p) {
-                        debug!(this.logger, "watching path"; "path" => p.to_str());
-
-                        this.filesystem_watcher
-                            .watcher()
-                            .watch(&p, RecursiveMode::NonRecursive)?;
-                        this.current_watched.insert(p.clone());
-                    }
+                    res.push(p)
+                }
+            }
+        }
+        res
+    }
 
-                    if let Some(parent) = p.parent() {
-                        if !this.current_watched.contains(parent) {
-                            debug!(this.logger, "watching parent path"; "parent_path" => parent.to_str());
+    /// Watch all of the given canonical paths, skipping the ones already watched.
+    fn watch_all(&mut self, paths: Vec<PathBuf>) -> Result<(), notify::Error> {
+        for p in paths {
+            if !self.current_watched.contains(&p) {
+                debug!(self.logger, "watching path"; "path" => p.to_str());
 
-                            this.filesystem_watcher
-                                .watcher()
-                                .watch(parent, RecursiveMode::NonRecursive)?;
+                // fingerprint before watching, so that a write racing
+                // with us is never mistaken for the original content
+                if let Some(fingerprint) = FileFingerprint::of(&p) {
+                    self.fingerprints.insert(p.clone(), fingerprint);
+                }
+                let registered = match self.register(&p) {
+                    Err(notify::Error {
+                        kind: notify::ErrorKind::Io(err),
+                        ..
+                    }) => {
+                        self.fingerprints.remove(&p);
+                        self.skip_unreadable(p, &err);
+                        continue;
+                    }
+                    res => res?,
+                };
+                if registered {
+                    self.current_watched.insert(p.clone());
+                } else {
+                    // Degraded mode: a watch on the parent directory
+                    // also reports changes to the files in it.
+                    self.fingerprints.remove(&p);
+                    match p.parent() {
+                        Some(parent) if self.register(parent)? => {
+                            warn!(self.logger, "watch limit reached, watching parent directory instead"; "path" => p.to_str(), "parent_path" => parent.to_str());
+                            self.current_watched.insert(parent.to_owned());
+                        }
+                        _ => {
+                            warn!(self.logger, "watch limit reached, polling path"; "path" => p.to_str());
+                            self.poll(&p)?;
+                            self.current_watched.insert(p);
                         }
                     }
+                    continue;
+                }
+            }
+
+            if let Some(parent) = p.parent() {
+                if !self.registered.contains(parent) {
+                    debug!(self.logger, "watching parent path"; "parent_path" => parent.to_str());
+                    self.register(parent)?;
                 }
             }
         }
         Ok(())
     }
 
+    /// Remember that `path` could not be read, so it is reported in the `WatchStatus`.
+    fn skip_unreadable(&mut self, path: PathBuf, err: &std::io::Error) {
+        warn!(self.logger, "skipping unreadable path"; "path" => path.to_str(), "error" => %err);
+        self.unreadable.insert(path);
+    }
+
+    /// Whether the watches of `W` count against the system’s watch limit.
+    fn counts_native_watches() -> bool {
+        W::kind() != WatcherKind::PollWatcher
+    }
+
+    /// Register `path` with the low-level watcher, unless it already is.
+    ///
+    /// Returns `false` if the watch limit of the system was reached.
+    fn register(&mut self, path: &Path) -> Result<bool, notify::Error> {
+        if self.registered.contains(path) {
+            return Ok(true);
+        }
+        match self
+            .filesystem_watcher
+            .watcher()
+            .watch(path, RecursiveMode::NonRecursive)
+        {
+            Ok(()) => {
+                self.registered.insert(path.to_owned());
+                if Self::counts_native_watches() {
+                    NATIVE_WATCHES.fetch_add(1, Ordering::Relaxed);
+                }
+                Ok(true)
+            }
+            Err(e) if is_watch_limit(&e) => {
+                if self.limit_exceeded.is_none() {
+                    let err = WatchLimitExceeded::new();
+                    warn!(self.logger, "{}", err);
+                    self.limit_exceeded = Some(err);
+                }
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Poll `path`, because it can’t be watched natively. Starts the poller if necessary.
+    fn poll(&mut self, path: &Path) -> Result<(), notify::Error> {
+        if self.polled.contains(path) {
+            return Ok(());
+        }
+        let poller = match self.fallback_watcher.take() {
+            Some(poller) => poller,
+            None => notify_debouncer_full::new_debouncer_opt(
+                self.debounce,
+                None,
+                self.filesystem_events_tx.clone(),
+                FileIdMap::new(),
+                notify::Config::default().with_poll_interval(POLL_INTERVAL),
+            )?,
+        };
+        self.fallback_watcher
+            .insert(poller)
+            .watcher()
+            .watch(path, RecursiveMode::NonRecursive)?;
+        self.polled.insert(path.to_owned());
+        Ok(())
+    }
+
+    /// Check whether a watched file still has the same content as
+    /// the last time we looked at it, and remember its new fingerprint.
+    ///
+    /// Only paths we watch directly are fingerprinted; for everything else
+    /// (directories, files we only see through their parent directory)
+    /// we can’t tell, so this returns `false`.
+    fn content_unchanged(&mut self, path: &Path) -> bool {
+        if !self.current_watched.contains(path) {
+            return false;
+        }
+        let new = FileFingerprint::of(path);
+        let old = match &new {
+            Some(fingerprint) => self
+                .fingerprints
+                .insert(path.to_owned(), fingerprint.clone()),
+            None => self.fingerprints.remove(path),
+        };
+        matches!((old, new), (Some(old), Some(new)) if old == new)
+    }
+
     /// Determine if the event path is covered by our list of watched
     /// paths.
     ///
@@ -360,20 +870,40 @@ impl Filter {
     }
 }
 
-/// Lists the dirs and files in a directory, as two vectors.
+impl<W: Watcher> Drop for Filter<W> {
+    fn drop(&mut self) {
+        // the watches are removed together with the low-level watcher
+        if Self::counts_native_watches() {
+            NATIVE_WATCHES.fetch_sub(self.registered.len(), Ordering::Relaxed);
+        }
+    }
+}
+
+/// Entries we could not read while listing a directory, with the reason.
+type Unreadable = Vec<(PathBuf, std::io::Error)>;
+
+/// Lists the dirs and files in a directory, as two vectors,
+/// plus the entries whose type could not be determined.
 /// Given path must be a readable directory.
-fn list_dir(dir: &Path) -> Result<(Vec<PathBuf>, Vec<PathBuf>), std::io::Error> {
+fn list_dir(dir: &Path) -> Result<(Vec<PathBuf>, Vec<PathBuf>, Unreadable), std::io::Error> {
     let mut dirs = vec![];
     let mut files = vec![];
+    let mut unreadable = vec![];
     for entry in std::fs::read_dir(dir)? {
-        let entry = entry?;
-        if entry.file_type()?.is_dir() {
-            dirs.push(entry.path())
-        } else {
-            files.push(entry.path())
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                unreadable.push((dir.to_owned(), err));
+                continue;
+            }
+        };
+        match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => dirs.push(entry.path()),
+            Ok(_) => files.push(entry.path()),
+            Err(err) => unreadable.push((entry.path(), err)),
         }
     }
-    Ok((dirs, files))
+    Ok((dirs, files, unreadable))
 }
 
 /// List all children of the given path.
@@ -381,18 +911,31 @@ fn list_dir(dir: &Path) -> Result<(Vec<PathBuf>, Vec<PathBuf>), std::io::Error>
 ///
 /// Returns the given path first, then a topologically sorted list of children, if any.
 ///
-/// All files have to be readable, or the function aborts.
-/// TODO: gracefully skip unreadable files.
-fn walk_path_topo(path: PathBuf) -> Result<Vec<PathBuf>, std::io::Error> {
+/// Directories that cannot be listed are still returned, but not recursed into.
+/// They are returned in the second vector, together with everything else
+/// that could not be read.
+fn walk_path_topo(path: PathBuf) -> (Vec<PathBuf>, Unreadable) {
     // push our own path first
     let mut res = vec![path.clone()];
+    let mut unreadable = vec![];
 
     // nothing to list
     if !path.is_dir() {
-        return Ok(res);
+        return (res, unreadable);
     }
 
-    let (dirs, mut files) = list_dir(&path)?;
+    let mut list_dir = |dir: &Path| match list_dir(dir) {
+        Ok((dirs, files, mut errs)) => {
+            unreadable.append(&mut errs);
+            (dirs, files)
+        }
+        Err(err) => {
+            unreadable.push((dir.to_owned(), err));
+            (vec![], vec![])
+        }
+    };
+
+    let (dirs, mut files) = list_dir(&path);
     // plain files
     res.append(&mut files);
 
@@ -405,21 +948,26 @@ fn walk_path_topo(path: PathBuf) -> Result<Vec<PathBuf>, std::io::Error> {
             None => break,
             Some(dir) => {
                 res.push(dir.clone());
-                let (dirs, mut files) = list_dir(&dir)?;
+                let (dirs, mut files) = list_dir(&dir);
                 res.append(&mut files);
                 work.append(&mut std::collections::VecDeque::from(dirs));
             }
         }
     }
-    Ok(res)
+    (res, unreadable)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Watch, WatchPathBuf};
+    use super::{
+        Filter, Watch, WatchBackend, WatchConfig, WatchPathBuf, WatchStatus, POLL_INTERVAL,
+    };
+    use crossbeam_channel as chan;
+    use notify::{EventHandler, RecommendedWatcher, RecursiveMode, Watcher, WatcherKind};
     use slog::{debug, info};
+    use std::cell::Cell;
     use std::ffi::OsStr;
-    use std::path::PathBuf;
+    use std::path::{Path, PathBuf};
     use std::thread::sleep;
     use std::time::{self, Duration};
     use tempfile::{tempdir, TempDir};
@@ -591,9 +1139,9 @@ mod tests {
         // Note, this is racey in the kernel. Otherwise I'd assert
         // this is empty.
         (if cfg!(target_os = "macos") {
-            Watch::new_impl(logger, Some(WATCHER_TIMEOUT))
+            Watch::new_impl(logger, WatchConfig::default(), Some(WATCHER_TIMEOUT))
         } else {
-            Watch::new_impl(logger, None)
+            Watch::new_impl(logger, WatchConfig::default(), None)
         })
         .expect("failed creating watch")
     }
@@ -609,6 +1157,10 @@ mod tests {
                 .add_to_watch_tx
                 .send(vec![WatchPathBuf::Recursive(t.to_path_buf())])
                 .unwrap();
+            watcher
+                .watch_status_rx
+                .recv_timeout(WATCHER_TIMEOUT)
+                .expect("no watch status after extending the watch list");
 
             expect_bash(r#"echo 1 > "$1/baz""#, [t]);
             assert_file_changed_within(&watcher, "baz", WATCHER_TIMEOUT);
@@ -629,6 +1181,10 @@ mod tests {
                 .add_to_watch_tx
                 .send(vec![WatchPathBuf::Normal(t.to_path_buf())])
                 .unwrap();
+            watcher
+                .watch_status_rx
+                .recv_timeout(WATCHER_TIMEOUT)
+                .expect("no watch status after extending the watch list");
 
             expect_bash(r#"touch "$1/baz""#, [t]);
             assert_file_changed_within(&watcher, "baz", WATCHER_TIMEOUT);
@@ -649,6 +1205,10 @@ mod tests {
                 .add_to_watch_tx
                 .send(vec![WatchPathBuf::Recursive(t.join("foo"))])
                 .unwrap();
+            watcher
+                .watch_status_rx
+                .recv_timeout(WATCHER_TIMEOUT)
+                .expect("no watch status after extending the watch list");
 
             expect_bash(r#"echo 1 > "$1/foo""#, [t]);
             sleep(WATCHER_TIMEOUT);
@@ -672,6 +1232,10 @@ mod tests {
                 .add_to_watch_tx
                 .send(vec![WatchPathBuf::Recursive(t.join("foo"))])
                 .unwrap();
+            watcher
+                .watch_status_rx
+                .recv_timeout(WATCHER_TIMEOUT)
+                .expect("no watch status after extending the watch list");
 
             info!(&logger, "bar is not watched, expect error");
             expect_bash(r#"echo 1 > "$1/bar""#, [t]);
@@ -681,8 +1245,8 @@ mod tests {
             expect_bash(r#"mv "$1/bar" "$1/foo""#, [t]);
             assert_file_changed_within(&watcher, "foo", WATCHER_TIMEOUT);
 
-            info!(&logger, "Do it a second time");
-            expect_bash(r#"echo 1 > "$1/bar""#, [t]);
+            info!(&logger, "Do it a second time, with different content");
+            expect_bash(r#"echo 2 > "$1/bar""#, [t]);
             assert_none_within(&watcher, WATCHER_TIMEOUT, None, &logger);
 
             info!(&logger, "Rename bar to foo, expect a notification");
@@ -691,6 +1255,392 @@ mod tests {
         })
     }
 
+    #[test]
+    fn unchanged_content_is_suppressed() {
+        let logger = crate::logging::test_logger("unchanged_content_is_suppressed");
+        let watcher = mk_test_watch(&logger);
+
+        with_test_tempdir("unchanged_content_is_suppressed", |t| {
+            expect_bash(r#"mkdir -p "$1""#, [t]);
+            expect_bash(r#"echo 1 > "$1/foo""#, [t]);
+            watcher
+                .add_to_watch_tx
+                .send(vec![WatchPathBuf::Normal(t.join("foo"))])
+                .unwrap();
+            sleep(WATCHER_TIMEOUT);
+
+            info!(
+                &logger,
+                "touch and rewrite foo with the same content, expect nothing"
+            );
+            expect_bash(r#"touch "$1/foo""#, [t]);
+            expect_bash(r#"echo 1 > "$1/foo""#, [t]);
+            assert_none_within(&watcher, WATCHER_TIMEOUT, Some(&["/foo"]), &logger);
+
+            info!(&logger, "change the content of foo, expect a notification");
+            expect_bash(r#"echo 2 > "$1/foo""#, [t]);
+            assert_file_changed_within(&watcher, "foo", WATCHER_TIMEOUT);
+        })
+    }
+
+    #[test]
+    fn watch_status_counts_watches() {
+        let logger = crate::logging::test_logger("watch_status_counts_watches");
+        let watcher = mk_test_watch(&logger);
+
+        with_test_tempdir("watch_status_counts_watches", |t| {
+            expect_bash(r#"mkdir -p "$1""#, [t]);
+            expect_bash(r#"touch "$1/foo" "$1/bar""#, [t]);
+            watcher
+                .add_to_watch_tx
+                .send(vec![
+                    WatchPathBuf::Normal(t.join("foo")),
+                    WatchPathBuf::Normal(t.join("bar")),
+                ])
+                .unwrap();
+
+            let status = watcher
+                .watch_status_rx
+                .recv_timeout(WATCHER_TIMEOUT)
+                .expect("no watch status after extending the watch list");
+            assert_eq!(
+                status,
+                WatchStatus {
+                    // foo, bar and their parent directory
+                    watched: 3,
+                    polled: 0,
+                    limit_exceeded: None,
+                    unreadable: vec![],
+                }
+            );
+        })
+    }
+
+    #[test]
+    fn unreadable_paths_are_skipped() {
+        let logger = crate::logging::test_logger("unreadable_paths_are_skipped");
+        let watcher = mk_test_watch(&logger);
+
+        with_test_tempdir("unreadable_paths_are_skipped", |t| {
+            expect_bash(r#"mkdir -p "$1""#, [t]);
+            expect_bash(r#"touch "$1/foo""#, [t]);
+            expect_bash(r#"ln -s "$1/does-not-exist" "$1/dangling""#, [t]);
+            watcher
+                .add_to_watch_tx
+                .send(vec![WatchPathBuf::Recursive(t.to_path_buf())])
+                .unwrap();
+
+            let status = watcher
+                .watch_status_rx
+                .recv_timeout(WATCHER_TIMEOUT)
+                .expect("no watch status after extending the watch list");
+            assert_eq!(status.unreadable, vec![t.join("dangling")]);
+
+            expect_bash(r#"echo 1 > "$1/foo""#, [t]);
+            assert_file_changed_within(&watcher, "foo", WATCHER_TIMEOUT);
+        })
+    }
+
+    #[test]
+    fn permission_denied_paths_are_skipped() {
+        // root can read files regardless of their mode, so there is nothing to test
+        if nix::unistd::geteuid().is_root() {
+            eprintln!("skipping permission_denied_paths_are_skipped: running as root");
+            return;
+        }
+        let logger = crate::logging::test_logger("permission_denied_paths_are_skipped");
+        let watcher = mk_test_watch(&logger);
+
+        with_test_tempdir("permission_denied_paths_are_skipped", |t| {
+            expect_bash(r#"mkdir -p "$1/locked""#, [t]);
+            expect_bash(r#"touch "$1/foo" "$1/secret" "$1/locked/bar""#, [t]);
+            expect_bash(r#"chmod 000 "$1/secret" "$1/locked""#, [t]);
+            watcher
+                .add_to_watch_tx
+                .send(vec![
+                    WatchPathBuf::Normal(t.join("secret")),
+                    WatchPathBuf::Recursive(t.to_path_buf()),
+                ])
+                .unwrap();
+
+            let status = watcher
+                .watch_status_rx
+                .recv_timeout(WATCHER_TIMEOUT)
+                .expect("no watch status after extending the watch list");
+            assert_eq!(status.unreadable, vec![t.join("locked"), t.join("secret")]);
+
+            // the watch loop is still alive and watching the readable files
+            expect_bash(r#"echo 1 > "$1/foo""#, [t]);
+            assert_file_changed_within(&watcher, "foo", WATCHER_TIMEOUT);
+
+            // let the tempdir cleanup remove the locked directory
+            expect_bash(r#"chmod 755 "$1/locked""#, [t]);
+        })
+    }
+
+    #[test]
+    fn replace_unwatches_unreferenced_paths() {
+        let logger = crate::logging::test_logger("replace_unwatches_unreferenced_paths");
+        let watcher = mk_test_watch(&logger);
+        let wait_for_status = || {
+            watcher
+                .watch_status_rx
+                .recv_timeout(WATCHER_TIMEOUT)
+                .expect("no watch status after changing the watch list")
+        };
+
+        with_test_tempdir("replace_unwatches_unreferenced_paths", |t| {
+            expect_bash(r#"mkdir -p "$1/a" "$1/b" "$1/c""#, [t]);
+            expect_bash(r#"touch "$1/a/nix" "$1/b/foo" "$1/c/bar""#, [t]);
+            watcher
+                .add_to_watch_tx
+                .send(vec![WatchPathBuf::Normal(t.join("a/nix"))])
+                .unwrap();
+            wait_for_status();
+            watcher
+                .replace_watch_tx
+                .send(vec![
+                    WatchPathBuf::Normal(t.join("b/foo")),
+                    WatchPathBuf::Normal(t.join("c/bar")),
+                ])
+                .unwrap();
+            assert_eq!(wait_for_status().watched, 6);
+
+            info!(&logger, "bar is not referenced anymore");
+            watcher
+                .replace_watch_tx
+                .send(vec![WatchPathBuf::Normal(t.join("b/foo"))])
+                .unwrap();
+            assert_eq!(wait_for_status().watched, 4);
+
+            expect_bash(r#"echo 1 > "$1/c/bar""#, [t]);
+            assert_none_within(&watcher, WATCHER_TIMEOUT, None, &logger);
+
+            expect_bash(r#"echo 1 > "$1/b/foo""#, [t]);
+            assert_file_changed_within(&watcher, "foo", WATCHER_TIMEOUT);
+
+            info!(&logger, "paths added with add_to_watch_tx stay watched");
+            watcher.replace_watch_tx.send(vec![]).unwrap();
+            assert_eq!(wait_for_status().watched, 2);
+            expect_bash(r#"echo 1 > "$1/a/nix""#, [t]);
+            assert_file_changed_within(&watcher, "nix", WATCHER_TIMEOUT);
+        })
+    }
+
+    #[test]
+    fn polling_backend_sees_changes() {
+        let logger = crate::logging::test_logger("polling_backend_sees_changes");
+        let watcher = Watch::new(
+            &logger,
+            WatchConfig {
+                backend: WatchBackend::Poll,
+                ..WatchConfig::default()
+            },
+        )
+        .expect("failed creating watch");
+
+        with_test_tempdir("polling_backend_sees_changes", |t| {
+            expect_bash(r#"mkdir -p "$1""#, [t]);
+            expect_bash(r#"touch "$1/foo""#, [t]);
+            watcher
+                .add_to_watch_tx
+                .send(vec![WatchPathBuf::Normal(t.join("foo"))])
+                .unwrap();
+            sleep(WATCHER_TIMEOUT);
+
+            expect_bash(r#"echo 1 > "$1/foo""#, [t]);
+            assert_file_changed_within(&watcher, "foo", WATCHER_TIMEOUT + POLL_INTERVAL);
+        })
+    }
+
+    thread_local! {
+        /// Watches a `LimitedWatcher` created on this thread accepts.
+        static WATCH_BUDGET: Cell<usize> = const { Cell::new(0) };
+    }
+
+    /// A native watcher that runs into the watch limit (`ENOSPC`) after `WATCH_BUDGET` watches.
+    struct LimitedWatcher {
+        inner: RecommendedWatcher,
+        budget: usize,
+    }
+
+    impl Watcher for LimitedWatcher {
+        fn new<F: EventHandler>(event_handler: F, config: notify::Config) -> notify::Result<Self> {
+            Ok(LimitedWatcher {
+                inner: RecommendedWatcher::new(event_handler, config)?,
+                budget: WATCH_BUDGET.with(Cell::get),
+            })
+        }
+
+        fn watch(&mut self, path: &Path, recursive_mode: RecursiveMode) -> notify::Result<()> {
+            if self.budget == 0 {
+                return Err(notify::Error::io(std::io::Error::from_raw_os_error(
+                    nix::libc::ENOSPC,
+                )));
+            }
+            self.budget -= 1;
+            self.inner.watch(path, recursive_mode)
+        }
+
+        fn unwatch(&mut self, path: &Path) -> notify::Result<()> {
+            self.budget += 1;
+            self.inner.unwatch(path)
+        }
+
+        fn kind() -> WatcherKind {
+            WatcherKind::Inotify
+        }
+    }
+
+    #[test]
+    fn watch_limit_falls_back_to_parent_directory_and_polling() {
+        let logger =
+            crate::logging::test_logger("watch_limit_falls_back_to_parent_directory_and_polling");
+        let (_user_requests_tx, user_requests_rx) = chan::unbounded();
+        let (_replace_requests_tx, replace_requests_rx) = chan::unbounded();
+        let (filtered_events_tx, _filtered_events_rx) = chan::unbounded();
+        let (watch_status_tx, _watch_status_rx) = chan::unbounded();
+        WATCH_BUDGET.with(|budget| budget.set(2));
+        let mut filter = Filter::<LimitedWatcher>::new(
+            user_requests_rx,
+            replace_requests_rx,
+            filtered_events_tx,
+            watch_status_tx,
+            WatchConfig::default(),
+            None,
+            &logger,
+        )
+        .expect("failed creating watch filter");
+
+        with_test_tempdir("watch_limit_falls_back", |t| {
+            expect_bash(r#"mkdir -p "$1/sub""#, [t]);
+            expect_bash(r#"touch "$1/foo" "$1/bar" "$1/sub/baz""#, [t]);
+            let t = t.canonicalize().unwrap();
+
+            info!(&logger, "foo and its directory use up the budget");
+            filter
+                .extend(vec![WatchPathBuf::Normal(t.join("foo"))])
+                .unwrap();
+            assert_eq!(filter.limit_exceeded, None);
+
+            info!(&logger, "bar is watched through its directory");
+            filter
+                .extend(vec![WatchPathBuf::Normal(t.join("bar"))])
+                .unwrap();
+            let limit = filter
+                .limit_exceeded
+                .clone()
+                .expect("watch limit not detected");
+            assert!(
+                limit.watched >= 2,
+                "watches of all projects count: {:?}",
+                limit
+            );
+            assert!(filter.current_watched.contains(&t));
+            assert!(filter.polled.is_empty());
+
+            info!(
+                &logger,
+                "the directory of baz is not watched, so baz is polled"
+            );
+            filter
+                .extend(vec![WatchPathBuf::Normal(t.join("sub/baz"))])
+                .unwrap();
+            assert!(filter.polled.contains(&t.join("sub/baz")));
+            // give the poller time for its initial scan
+            sleep(WATCHER_TIMEOUT);
+
+            expect_bash(r#"echo 1 > "$1/sub/baz""#, [&t]);
+            let deadline = time::Instant::now() + WATCHER_TIMEOUT + POLL_INTERVAL * 5;
+            loop {
+                let events = match filter
+                    .filesystem_events_rx
+                    .recv_deadline(deadline)
+                    .expect("no event for the polled file")
+                {
+                    notify_debouncer_full::DebounceEventResult::Ok(events) => events,
+                    notify_debouncer_full::DebounceEventResult::Err(errs) => {
+                        panic!("watch errors: {:?}", errs)
+                    }
+                };
+                if filter
+                    .process_watch_events(events)
+                    .contains(&t.join("sub/baz"))
+                {
+                    break;
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn quiet_period_merges_changes() {
+        let logger = crate::logging::test_logger("quiet_period_merges_changes");
+        let watcher = Watch::new(
+            &logger,
+            WatchConfig {
+                quiet_period: Some(Duration::from_millis(1000)),
+                ..WatchConfig::default()
+            },
+        )
+        .expect("failed creating watch");
+
+        with_test_tempdir("quiet_period_merges_changes", |t| {
+            expect_bash(r#"mkdir -p "$1""#, [t]);
+            expect_bash(r#"touch "$1/foo" "$1/bar""#, [t]);
+            watcher
+                .add_to_watch_tx
+                .send(vec![
+                    WatchPathBuf::Normal(t.join("foo")),
+                    WatchPathBuf::Normal(t.join("bar")),
+                ])
+                .unwrap();
+            sleep(WATCHER_TIMEOUT);
+
+            info!(&logger, "change foo and bar with a pause in between");
+            expect_bash(r#"echo 1 > "$1/foo""#, [t]);
+            sleep(Duration::from_millis(500));
+            expect_bash(r#"echo 1 > "$1/bar""#, [t]);
+
+            let changed = watcher
+                .watch_events_rx
+                .recv_timeout(2 * WATCHER_TIMEOUT)
+                .expect("no change reported after the quiet period");
+            let names = changed
+                .iter()
+                .filter_map(|p| p.file_name())
+                .collect::<Vec<_>>();
+            assert!(
+                names.contains(&OsStr::new("foo")) && names.contains(&OsStr::new("bar")),
+                "both changes should be reported together, got {:?}",
+                changed
+            );
+        })
+    }
+
+    #[test]
+    fn file_fingerprint_tracks_content() {
+        with_test_tempdir("file_fingerprint_tracks_content", |t| {
+            std::fs::create_dir_all(t).unwrap();
+            let foo = t.join("foo");
+            std::fs::write(&foo, "content").unwrap();
+            let first = super::FileFingerprint::of(&foo).expect("foo is a regular file");
+
+            std::fs::write(&foo, "content").unwrap();
+            assert_eq!(Some(first.clone()), super::FileFingerprint::of(&foo));
+
+            std::fs::write(&foo, "other content").unwrap();
+            assert_ne!(Some(first), super::FileFingerprint::of(&foo));
+
+            assert_eq!(
+                None,
+                super::FileFingerprint::of(t),
+                "directories have no fingerprint"
+            );
+            assert_eq!(None, super::FileFingerprint::of(&t.join("bar")));
+        })
+    }
+
     #[test]
     fn walk_path_topo_filetree() {
         with_test_tempdir("walk_path_topo_filetree", |t| {
@@ -700,7 +1650,8 @@ mod tests {
                 std::fs::write(t.join(dir).join(file), []).unwrap();
             }
 
-            let res = super::walk_path_topo(t.to_owned()).unwrap();
+            let (res, unreadable) = super::walk_path_topo(t.to_owned());
+            assert!(unreadable.is_empty(), "{:?}", unreadable);
 
             // check that the list is topolocially sorted
             // by making sure *no* later path is a prefix of a previous path.
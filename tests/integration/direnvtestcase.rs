@@ -9,6 +9,7 @@ use lorri::nix::options::NixOptions;
 use lorri::ops;
 use lorri::project;
 use lorri::project::Project;
+use lorri::watch::WatchConfig;
 use lorri::AbsPathBuf;
 use lorri::NixFile;
 
@@ -40,7 +41,7 @@ impl DirenvTestCase {
         let shell_file = NixFile::from(AbsPathBuf::new(test_root.join("shell.nix")).unwrap());
 
         let cas = ContentAddressable::new(cachedir.join("cas").to_owned()).unwrap();
-        let project = Project::new(shell_file.clone(), &cachedir.join("gc_roots"), cas).unwrap();
+        let project = Project::new(shell_file.clone(), &cachedir.join("gc_roots"), cas);
 
         DirenvTestCase {
             projectdir,
@@ -52,9 +53,16 @@ impl DirenvTestCase {
 
     /// Execute the build loop one time
     pub fn evaluate(&mut self) -> Result<builder::OutputPath<project::RootPath>, BuildError> {
-        BuildLoop::new(&self.project, NixOptions::empty(), self.logger.clone())
-            .expect("could not set up build loop")
-            .once()
+        BuildLoop::new(
+            &self.project,
+            NixOptions::empty(),
+            WatchConfig::default(),
+            lorri::builder::Timeouts::default(),
+            lorri::build_loop::RetryPolicy::default(),
+            self.logger.clone(),
+        )
+        .expect("could not set up build loop")
+        .once()
     }
 
     /// Run `direnv allow` and then `direnv export json`, and return
@@ -62,7 +70,7 @@ impl DirenvTestCase {
     pub fn get_direnv_variables(&self) -> DirenvEnv {
         let envrc = File::create(self.projectdir.path().join(".envrc")).unwrap();
         let paths = lorri::ops::get_paths().unwrap();
-        ops::op_direnv(self.project.clone(), &paths, envrc, &self.logger).unwrap();
+        ops::op_direnv(self.project.clone(), &paths, None, envrc, &self.logger).unwrap();
 
         {
             let mut allow = self.direnv_cmd();
@@ -10,4 +10,5 @@ mod direnvtestcase;
 mod envrc;
 mod envrctestcase;
 mod gc;
+mod shared_gc_roots;
 mod trivial;
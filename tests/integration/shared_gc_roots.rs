@@ -0,0 +1,59 @@
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+fn cargo_bin(name: &str) -> std::path::PathBuf {
+    std::env::current_exe()
+        .ok()
+        .map(|mut path| {
+            path.pop();
+            if path.ends_with("deps") {
+                path.pop();
+            }
+            path.join(name)
+        })
+        .unwrap()
+}
+
+/// Run lorri like a user of a shared daemon, whose GC roots the daemon keeps.
+fn run_lorri(dir: &Path, gc_root_dir: &Path, args: &[&str]) -> String {
+    let out = Command::new(cargo_bin("lorri"))
+        .args(args)
+        .current_dir(dir.join("project"))
+        .env("HOME", dir.join("home"))
+        .env_remove("XDG_CACHE_HOME")
+        .env_remove("XDG_RUNTIME_DIR")
+        .env("LORRI_DAEMON_SOCKET", dir.join("run/daemon.socket"))
+        .env("LORRI_GC_ROOT_DIR", gc_root_dir)
+        .stdin(Stdio::null())
+        .output()
+        .expect("running lorri");
+    let mut res = String::from_utf8(out.stdout).expect("non utf8 output");
+    res.push_str(std::str::from_utf8(&out.stderr).expect("non utf8 stderr"));
+    res
+}
+
+#[test]
+fn clients_dont_create_the_gc_root_dir() {
+    let testdir = tempfile::tempdir().expect("tempdir failed");
+    let dir = testdir.path();
+    for d in ["project", "home", "gc_roots"] {
+        std::fs::create_dir(dir.join(d)).expect("mkdir");
+    }
+    std::fs::write(dir.join("project/shell.nix"), "{}").expect("writing shell.nix");
+    // the daemon’s GC root directory, which only the daemon may write to
+    let shared = dir.join("gc_roots");
+    std::fs::set_permissions(&shared, std::fs::Permissions::from_mode(0o555))
+        .expect("chmod gc_roots");
+    let gc_root_dir = shared.join("1000");
+
+    let out = run_lorri(dir, &gc_root_dir, &["info"]);
+    assert!(dbg!(&out).contains("Lorri Daemon Status"));
+    // the daemon is not running, but the client got that far
+    let out = run_lorri(dir, &gc_root_dir, &["internal", "ping", "shell.nix"]);
+    assert!(dbg!(&out).contains("is the daemon running?"));
+    assert!(
+        !gc_root_dir.exists(),
+        "a client created the GC root directory"
+    );
+}
@@ -77,7 +77,6 @@ fn project(name: &str, cache_dir: &AbsPathBuf) -> Project {
         &cache_dir.join("gc_roots"),
         ContentAddressable::new(cas_dir).unwrap(),
     )
-    .unwrap()
 }
 
 fn build(project: &Project, logger: &slog::Logger) -> PathBuf {
@@ -87,6 +86,8 @@ fn build(project: &Project, logger: &slog::Logger) -> PathBuf {
                 &project.nix_file,
                 &project.cas,
                 &NixOptions::empty(),
+                None,
+                lorri::builder::Timeouts::default(),
                 logger,
             )
             .unwrap()